@@ -0,0 +1,23 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use bouffalo_cli::elf_parser::ElfParser;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let parser = match ElfParser::parse(Cursor::new(data)) {
+        Ok(parser) => parser,
+        Err(_) => return,
+    };
+
+    let _ = parser.symbol_table();
+
+    for section in parser.section_headers() {
+        let _ = parser.read_section_data(section);
+    }
+
+    for segment in parser.program_headers() {
+        let _ = parser.read_segment_data(segment);
+    }
+});
@@ -1,7 +1,22 @@
 //! Bouffalo Lab firmware module
 
+pub mod boot2;
+mod boot_config;
 pub mod bootrom;
+mod clock;
+pub mod conf;
+pub mod diff;
+pub mod encryption;
+pub mod factory_params;
 mod firmware;
+pub mod flash_presets;
+mod header;
+pub mod image;
+pub mod memory_map;
+pub mod ota;
+pub mod partition;
+pub mod signature;
+pub mod size_report;
 
 #[allow(dead_code)]
 pub const EFLASH_LOADER_24M_BIN: &[u8] = include_bytes!("../blobs/eflash_loader_24m.bin");
@@ -17,4 +32,13 @@ pub const EFLASH_LOADER_NONE_BIN: &[u8] = include_bytes!("../blobs/eflash_loader
 #[allow(dead_code)]
 pub const EFLASH_LOADER_RC32M_BIN: &[u8] = include_bytes!("../blobs/eflash_loader_rc32m.bin");
 
-pub use firmware::{crc32, Firmware, FirmwareBuilder, Segment};
+pub use boot_config::{BootConfig, EncryptionType, SignatureType};
+pub use clock::{FlashClockType, ParseClockError, PllClock, XtalType};
+pub use encryption::AesKey;
+pub use firmware::{
+    crc32, BootHeaderError, BuilderError, ClockConfig, ClockConfigError, Cpu, Firmware,
+    FirmwareBuilder, FlashConfig, FlashConfigError, ParseError, Segment, BOOT_HEADER_LEN,
+};
+pub use flash_presets::FlashPreset;
+pub use header::{BootHeader, HeaderError, HeaderFormat};
+pub use signature::{ImageSignature, SignatureError};
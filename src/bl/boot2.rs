@@ -0,0 +1,349 @@
+//! The boot2 bootloader at the start of flash
+//!
+//! The BootROM boots boot2 from flash offset 0 like any other firmware image without segments,
+//! with its code at `BOOT2_IMAGE_START`. boot2 then picks the active partition table, looks up the
+//! `FW` entry and boots the firmware image at the active address of the entry, falling back to the
+//! other address if there's no valid image there.
+
+use std::fmt;
+use std::io::Cursor;
+
+use thiserror::Error;
+
+use super::partition::{PartitionEntry, PartitionError, PartitionTable, PARTITION_TABLE_OFFSETS};
+use super::{BootHeader, BuilderError, ClockConfig, Firmware, FlashConfig, ParseError};
+
+/// The flash offset of the boot2 code, after the boot header
+pub const BOOT2_IMAGE_START: u32 = 0x2000;
+
+/// The maximum length of boot2, which ends where the first partition table starts
+pub const BOOT2_MAX_LEN: u32 = PARTITION_TABLE_OFFSETS[0];
+
+/// The name of the partition entry that boot2 boots the firmware image of
+pub const FW_PARTITION: &str = "FW";
+
+/// The start of the message boot2 logs on startup, which is followed by the build date and time
+const BOOT2_MARKER: &[u8] = b"BLSP_Boot2_";
+
+#[derive(Error, Debug)]
+pub enum Boot2Error {
+    #[error("Failed to parse the boot header: {}", _0)]
+    ParseError(#[from] ParseError),
+    #[error("Failed to build the boot header: {}", _0)]
+    BuilderError(#[from] BuilderError),
+    #[error("The boot header says the image has segments, but boot2 has none")]
+    UnexpectedSegments,
+    #[error("The code at {:#x}..{:#x} is outside of the file", _0, _1)]
+    TruncatedImage(u32, u32),
+    #[error("The code doesn't look like boot2")]
+    NotBoot2,
+    #[error(
+        "boot2 ends at {:#x}, which overlaps the partition table at {:#x}",
+        _0,
+        BOOT2_MAX_LEN
+    )]
+    TooLarge(u32),
+    #[error("Partition table error: {}", _0)]
+    PartitionError(#[from] PartitionError),
+    #[error("The partition table has no {} entry", FW_PARTITION)]
+    MissingFirmwarePartition,
+    #[error(
+        "There's no firmware image at either address of the {} partition",
+        FW_PARTITION
+    )]
+    MissingFirmware,
+}
+
+/// The version of boot2, as far as it can be told from the strings it logs
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct Boot2Version {
+    /// The date and time boot2 was built, i.e. `Jun 29 2020 19:31:58`
+    pub build: Option<String>,
+    /// The version of the SDK boot2 was built with
+    pub sdk_version: Option<String>,
+    /// The version of the board support package boot2 was built with
+    pub bsp_version: Option<String>,
+}
+
+impl Boot2Version {
+    /// Reads the version from the strings in the boot2 `code`
+    fn from_code(code: &[u8]) -> Option<Boot2Version> {
+        let marker = find(code, BOOT2_MARKER)?;
+
+        // The build date and time are the string literals right in front of the first message
+        let build = string_before(code, marker).and_then(|(start, date)| {
+            let (_, time) = string_before(code, start)?;
+
+            if time.len() == 8 && time.matches(':').count() == 2 {
+                Some(format!("{} {}", date, time))
+            } else {
+                None
+            }
+        });
+
+        // The versions are the string literals in front of the messages that log them
+        let version_before = |message: &[u8]| {
+            find(code, message)
+                .and_then(|pos| string_before(code, pos))
+                .map(|(_, version)| version.to_string())
+        };
+
+        Some(Boot2Version {
+            build,
+            sdk_version: version_before(b"MCU SDK:%s").or_else(|| version_before(b"SDK:%s")),
+            bsp_version: version_before(b"BSP:%s"),
+        })
+    }
+}
+
+impl fmt::Display for Boot2Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.build.as_deref().unwrap_or("unknown build"))?;
+
+        if let Some(ref sdk) = self.sdk_version {
+            write!(f, ", SDK {}", sdk)?;
+        }
+
+        if let Some(ref bsp) = self.bsp_version {
+            write!(f, ", BSP {}", bsp)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A boot2 image, which is the boot header and the code that follows it at `image_start`
+#[derive(Debug, Clone)]
+pub struct Boot2Image {
+    /// The boot header
+    pub firmware: Firmware,
+    /// The boot2 code
+    pub code: Vec<u8>,
+    /// The version of boot2
+    pub version: Boot2Version,
+}
+
+impl Boot2Image {
+    /// Parses the boot2 image at the start of `flash`, which may be a whole-flash image
+    pub fn from_bytes(flash: &[u8]) -> Result<Boot2Image, Boot2Error> {
+        let firmware = Firmware::from_reader(Cursor::new(flash))?;
+
+        // boot2 runs from flash, so its code is stored as is after the boot header
+        if !firmware.boot_config().no_segment {
+            return Err(Boot2Error::UnexpectedSegments);
+        }
+
+        let start = firmware.image_start();
+        let end = start.saturating_add(firmware.image_segment_info());
+        let code = flash
+            .get(start as usize..end as usize)
+            .ok_or(Boot2Error::TruncatedImage(start, end))?;
+
+        let version = Boot2Version::from_code(code).ok_or(Boot2Error::NotBoot2)?;
+
+        Ok(Boot2Image {
+            firmware,
+            code: code.to_vec(),
+            version,
+        })
+    }
+
+    /// Creates a boot2 image of the raw boot2 `code`, like the prebuilt images of the SDK, with
+    /// the given flash and clock configuration
+    pub fn from_code(
+        code: Vec<u8>,
+        flash_config: FlashConfig,
+        clock_config: ClockConfig,
+    ) -> Result<Boot2Image, Boot2Error> {
+        let version = Boot2Version::from_code(&code).ok_or(Boot2Error::NotBoot2)?;
+
+        let firmware = Firmware::builder()
+            .flash_config(flash_config)
+            .clock_config(clock_config)
+            .no_segment(true)
+            .xip(true)
+            .ignore_hash(true)
+            .entry_point(0)
+            .image_start(BOOT2_IMAGE_START)
+            .image_len(code.len() as u32)
+            .build()?;
+
+        Ok(Boot2Image {
+            firmware,
+            code,
+            version,
+        })
+    }
+
+    /// Returns whether `flash` starts with a boot2 image
+    pub fn is_boot2(flash: &[u8]) -> bool {
+        Boot2Image::from_bytes(flash).is_ok()
+    }
+
+    /// Replaces the flash and clock configuration in the boot header and recomputes the checksums
+    pub fn set_config(&mut self, flash_config: FlashConfig, clock_config: ClockConfig) {
+        let mut header = BootHeader::from(&self.firmware);
+
+        header.flash_config = flash_config;
+        header.clock_config = clock_config;
+
+        self.firmware.set_boot_header(&header);
+    }
+
+    /// Returns the boot header, padded with erased flash up to the code, followed by the code
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Boot2Error> {
+        let start = self.firmware.image_start();
+        let end = start as usize + self.code.len();
+
+        if end > BOOT2_MAX_LEN as usize {
+            return Err(Boot2Error::TooLarge(end as u32));
+        }
+
+        let mut buf = Vec::with_capacity(end);
+        self.firmware.write_flash_image_to(&self.code, &mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+/// The firmware image that boot2 boots
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BootTarget {
+    /// The `FW` entry of the active partition table
+    pub entry: PartitionEntry,
+    /// The flash offset of the firmware image that boot2 boots
+    pub address: u32,
+    /// Whether boot2 falls back to the inactive address, since there's no image at the active one
+    pub fallback: bool,
+}
+
+/// Returns the firmware image that boot2 boots from the whole-flash image `flash`
+pub fn boot_target(flash: &[u8]) -> Result<BootTarget, Boot2Error> {
+    let table = PartitionTable::from_flash(flash)?;
+    let entry = table
+        .entries
+        .into_iter()
+        .find(|entry| entry.name == FW_PARTITION)
+        .ok_or(Boot2Error::MissingFirmwarePartition)?;
+
+    // Try the active address first and then the other one, like boot2 does
+    let active = (entry.active_index & 1) as usize;
+    let address = [entry.address[active], entry.address[active ^ 1]]
+        .iter()
+        .position(|&address| {
+            flash
+                .get(address as usize..)
+                .map(|data| Firmware::from_reader(Cursor::new(data)).is_ok())
+                .unwrap_or(false)
+        })
+        .ok_or(Boot2Error::MissingFirmware)?;
+
+    Ok(BootTarget {
+        address: entry.address[active ^ address],
+        fallback: address == 1,
+        entry,
+    })
+}
+
+/// Returns the offset of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Returns the offset and the contents of the NUL-terminated string that ends before `end`,
+/// skipping the padding in between
+fn string_before(code: &[u8], end: usize) -> Option<(usize, &str)> {
+    let end = code[..end].iter().rposition(|&b| b != 0)? + 1;
+    let start = code[..end]
+        .iter()
+        .rposition(|&b| b == 0)
+        .map_or(0, |pos| pos + 1);
+    let s = std::str::from_utf8(&code[start..end]).ok()?;
+
+    if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        Some((start, s))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bl::{flash_presets, XtalType};
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    #[test]
+    fn it_should_read_the_reference_boot2_version() {
+        let boot2 = Boot2Image::from_bytes(REFERENCE_FIRMWARE).unwrap();
+
+        assert_eq!(boot2.version.build.as_deref(), Some("Jun 29 2020 19:31:58"));
+        assert_eq!(
+            boot2.version.sdk_version.as_deref(),
+            Some("ae431638c7dec54c7fdde8a665412a5de93ff88a")
+        );
+        assert_eq!(
+            boot2.version.bsp_version.as_deref(),
+            Some("831f8a103fc3c546c428b8773cb89e6316165feb")
+        );
+        assert_eq!(boot2.code.len(), 0x96d0);
+
+        // The eflash loader is a firmware image, but not boot2
+        let eflash_loader = include_bytes!("../../test/eflash_loader_40m.bin");
+
+        assert!(!Boot2Image::is_boot2(eflash_loader));
+    }
+
+    #[test]
+    fn it_should_find_the_firmware_boot2_boots() {
+        let target = boot_target(REFERENCE_FIRMWARE).unwrap();
+
+        assert_eq!(target.entry.name, "FW");
+        assert_eq!(target.address, 0x10000);
+        assert!(!target.fallback);
+
+        // Erase the active image, which leaves nothing to fall back to
+        let mut flash = REFERENCE_FIRMWARE.to_vec();
+        flash[0x10000..0x10004].copy_from_slice(&[0xff; 4]);
+
+        assert!(matches!(
+            boot_target(&flash),
+            Err(Boot2Error::MissingFirmware)
+        ));
+    }
+
+    #[test]
+    fn it_should_install_boot2_with_the_board_config() {
+        let boot2 = Boot2Image::from_bytes(REFERENCE_FIRMWARE).unwrap();
+        let flash_config = flash_presets::find("GD25Q16").unwrap().flash_config();
+        let mut clock_config = *boot2.firmware.clock_config();
+        clock_config.xtal_type = XtalType::Xtal26M;
+
+        // Rebuild the header of the reference image and of its raw code
+        let mut patched = boot2.clone();
+        patched.set_config(flash_config, clock_config);
+
+        let raw = Boot2Image::from_code(boot2.code.clone(), flash_config, clock_config).unwrap();
+
+        for image in &[patched, raw] {
+            let buf = image.to_bytes().unwrap();
+            let parsed = Boot2Image::from_bytes(&buf).unwrap();
+
+            assert_eq!(buf.len(), 0x2000 + 0x96d0);
+            assert_eq!(parsed.firmware.flash_config(), &flash_config);
+            assert_eq!(parsed.firmware.clock_config().xtal_type, XtalType::Xtal26M);
+            assert_eq!(parsed.code, boot2.code);
+            assert_eq!(parsed.version, boot2.version);
+        }
+
+        // boot2 must not overwrite the partition table
+        let mut large = boot2;
+        large.code.resize(BOOT2_MAX_LEN as usize, 0);
+
+        assert!(matches!(large.to_bytes(), Err(Boot2Error::TooLarge(_))));
+    }
+}
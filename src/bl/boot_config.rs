@@ -0,0 +1,243 @@
+//! The boot configuration flags of the boot header
+//!
+//! The layout is taken from `boot_cfg` in `bl602_sflash.h` / `blsp_bootinfo.h` in the SDK:
+//!
+//! | Bits    | Field               |
+//! |---------|---------------------|
+//! | 1:0     | sign                |
+//! | 3:2     | encrypt_type        |
+//! | 5:4     | key_sel             |
+//! | 7:6     | reserved            |
+//! | 8       | no_segment          |
+//! | 9       | cache_enable        |
+//! | 10      | notload_in_bootrom  |
+//! | 11      | aes_region_lock     |
+//! | 15:12   | cache_way_disable   |
+//! | 16      | crc_ignore          |
+//! | 17      | hash_ignore         |
+//! | 18      | halt_boot2          |
+//! | 31:19   | reserved            |
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The type of encryption used for the image
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionType {
+    #[default]
+    None,
+    Aes128,
+    Aes256,
+    Aes192,
+}
+
+impl From<u8> for EncryptionType {
+    fn from(val: u8) -> EncryptionType {
+        match val & 0b11 {
+            0 => EncryptionType::None,
+            1 => EncryptionType::Aes128,
+            2 => EncryptionType::Aes256,
+            _ => EncryptionType::Aes192,
+        }
+    }
+}
+
+impl From<EncryptionType> for u8 {
+    fn from(val: EncryptionType) -> u8 {
+        match val {
+            EncryptionType::None => 0,
+            EncryptionType::Aes128 => 1,
+            EncryptionType::Aes256 => 2,
+            EncryptionType::Aes192 => 3,
+        }
+    }
+}
+
+impl fmt::Display for EncryptionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionType::None => write!(f, "none"),
+            EncryptionType::Aes128 => write!(f, "AES-128"),
+            EncryptionType::Aes256 => write!(f, "AES-256"),
+            EncryptionType::Aes192 => write!(f, "AES-192"),
+        }
+    }
+}
+
+/// The type of signature used for the image
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureType {
+    #[default]
+    None,
+    /// ECDSA with the secp256r1 curve
+    Ecc,
+    /// A signature type value that is reserved
+    Reserved(u8),
+}
+
+impl From<u8> for SignatureType {
+    fn from(val: u8) -> SignatureType {
+        match val & 0b11 {
+            0 => SignatureType::None,
+            1 => SignatureType::Ecc,
+            val => SignatureType::Reserved(val),
+        }
+    }
+}
+
+impl From<SignatureType> for u8 {
+    fn from(val: SignatureType) -> u8 {
+        match val {
+            SignatureType::None => 0,
+            SignatureType::Ecc => 1,
+            SignatureType::Reserved(val) => val & 0b11,
+        }
+    }
+}
+
+impl fmt::Display for SignatureType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureType::None => write!(f, "none"),
+            SignatureType::Ecc => write!(f, "ECDSA P-256"),
+            SignatureType::Reserved(val) => write!(f, "reserved ({})", val),
+        }
+    }
+}
+
+/// The boot configuration flags of the boot header
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+pub struct BootConfig {
+    /// The type of signature used for the image
+    pub signature: SignatureType,
+    /// The type of encryption used for the image
+    pub encryption: EncryptionType,
+    /// Selects which of the efuse keys is used for decryption
+    pub key_select: u8,
+    /// Indicates that there's no segment information after the boot header, and that the image
+    /// data follows directly
+    pub no_segment: bool,
+    /// Enables the flash cache, which is required to execute the image in place (XIP) from flash
+    pub cache_enable: bool,
+    /// Indicates that the image shouldn't be loaded by the BootROM
+    pub notload_in_bootrom: bool,
+    /// Locks the AES region after boot
+    pub aes_region_lock: bool,
+    /// A bitmask of the cache ways to disable
+    pub cache_way_disable: u8,
+    /// Determines whether the BootROM should ignore the crc checksum of the header
+    ///
+    /// Note that the CRC also needs to be 0xDEADBEEF
+    pub crc_ignore: bool,
+    /// Determines whether the BootROM should ignore the hash of the image
+    ///
+    /// Note that the hash also needs to be 0xDEADBEEF
+    pub hash_ignore: bool,
+    /// Indicates that boot2 should halt after booting, instead of jumping to the application
+    pub halt_boot2: bool,
+    /// Reserved bits 7:6
+    pub reserved_6_7: u8,
+    /// Reserved bits 31:19
+    pub reserved_19_31: u16,
+}
+
+impl From<u32> for BootConfig {
+    fn from(val: u32) -> BootConfig {
+        let bit = |n: u32| val & (1 << n) != 0;
+
+        BootConfig {
+            signature: SignatureType::from((val & 0b11) as u8),
+            encryption: EncryptionType::from(((val >> 2) & 0b11) as u8),
+            key_select: ((val >> 4) & 0b11) as u8,
+            reserved_6_7: ((val >> 6) & 0b11) as u8,
+            no_segment: bit(8),
+            cache_enable: bit(9),
+            notload_in_bootrom: bit(10),
+            aes_region_lock: bit(11),
+            cache_way_disable: ((val >> 12) & 0xf) as u8,
+            crc_ignore: bit(16),
+            hash_ignore: bit(17),
+            halt_boot2: bit(18),
+            reserved_19_31: ((val >> 19) & 0x1fff) as u16,
+        }
+    }
+}
+
+impl From<BootConfig> for u32 {
+    fn from(val: BootConfig) -> u32 {
+        let bit = |enabled: bool, n: u32| (enabled as u32) << n;
+
+        (u8::from(val.signature) as u32 & 0b11)
+            | (u8::from(val.encryption) as u32 & 0b11) << 2
+            | (val.key_select as u32 & 0b11) << 4
+            | (val.reserved_6_7 as u32 & 0b11) << 6
+            | bit(val.no_segment, 8)
+            | bit(val.cache_enable, 9)
+            | bit(val.notload_in_bootrom, 10)
+            | bit(val.aes_region_lock, 11)
+            | (val.cache_way_disable as u32 & 0xf) << 12
+            | bit(val.crc_ignore, 16)
+            | bit(val.hash_ignore, 17)
+            | bit(val.halt_boot2, 18)
+            | (val.reserved_19_31 as u32 & 0x1fff) << 19
+    }
+}
+
+impl fmt::Display for BootConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Signature: {}", self.signature)?;
+        writeln!(f, "Encryption: {}", self.encryption)?;
+        writeln!(f, "Key select: {}", self.key_select)?;
+        writeln!(f, "No segment: {}", self.no_segment)?;
+        writeln!(f, "Cache enable: {}", self.cache_enable)?;
+        writeln!(f, "Not loaded in BootROM: {}", self.notload_in_bootrom)?;
+        writeln!(f, "AES region lock: {}", self.aes_region_lock)?;
+        writeln!(f, "Cache way disable: {:#06b}", self.cache_way_disable)?;
+        writeln!(f, "Ignore CRC: {}", self.crc_ignore)?;
+        writeln!(f, "Ignore hash: {}", self.hash_ignore)?;
+        write!(f, "Halt boot2: {}", self.halt_boot2)?;
+
+        if self.reserved_6_7 != 0 || self.reserved_19_31 != 0 {
+            write!(
+                f,
+                "\nReserved: {:#04x} {:#06x}",
+                self.reserved_6_7, self.reserved_19_31
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_reference_boot_config() {
+        let config = BootConfig::from(0x33300);
+
+        assert_eq!(config.signature, SignatureType::None);
+        assert_eq!(config.encryption, EncryptionType::None);
+        assert!(config.no_segment);
+        assert!(config.cache_enable);
+        assert_eq!(config.cache_way_disable, 0b0011);
+        assert!(config.crc_ignore);
+        assert!(config.hash_ignore);
+        assert!(!config.halt_boot2);
+    }
+
+    #[test]
+    fn it_should_round_trip_every_bit() {
+        for n in 0..32 {
+            let val = 1u32 << n;
+
+            assert_eq!(u32::from(BootConfig::from(val)), val);
+        }
+
+        assert_eq!(u32::from(BootConfig::from(u32::MAX)), u32::MAX);
+    }
+}
@@ -3,6 +3,7 @@ use thiserror::Error;
 
 /// Indicates an error received from the BootROM
 #[repr(u16)]
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug, IntoPrimitive, FromPrimitive)]
 pub enum Error {
     #[error("BFLB_BOOTROM_SUCCESS")]
@@ -0,0 +1,312 @@
+//! The clock tree settings used in the clock configuration of the boot header
+//!
+//! The values are taken from `bl602_glb.h` in the SDK
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Implements `Serialize` and `Deserialize` using the `Display` and `FromStr` implementations, so
+/// the values are the same as on the command-line
+macro_rules! impl_serde_from_str {
+    ($ty:ty) => {
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+
+                s.parse().map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error(
+    "Invalid {} value: {:?} (expected one of: {}, or a raw value)",
+    kind,
+    value,
+    expected
+)]
+pub struct ParseClockError {
+    kind: &'static str,
+    value: String,
+    expected: &'static str,
+}
+
+/// Defines a clock setting with the given raw values, and an `Unknown` variant that keeps the
+/// values that aren't known, so images with them can still be inspected
+///
+/// `TryFrom<u8>` only accepts the known values, while `from_raw` accepts any value. Unknown values
+/// are displayed as their raw value, which `FromStr` accepts as well, so they survive an export
+/// and import of the boot header.
+macro_rules! clock_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident ($kind:expr, $expected:expr) {
+            $($variant:ident = $value:expr,)+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        pub enum $name {
+            $($variant,)+
+            /// A raw value that isn't known
+            Unknown(u8),
+        }
+
+        impl $name {
+            /// Returns the setting with the raw `value`, which is `Unknown` if it isn't known
+            pub fn from_raw(value: u8) -> $name {
+                match value {
+                    $($value => $name::$variant,)+
+                    _ => $name::Unknown(value),
+                }
+            }
+
+            /// Returns whether the setting is one of the known values
+            pub fn is_known(self) -> bool {
+                !matches!(self, $name::Unknown(_))
+            }
+
+            /// Parses a decimal or `0x`-prefixed hexadecimal raw value
+            fn parse_raw(s: &str) -> Result<$name, ParseClockError> {
+                let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => s.parse(),
+                };
+
+                value.map($name::from_raw).map_err(|_| $name::parse_error(s))
+            }
+
+            fn parse_error<S: ToString>(value: S) -> ParseClockError {
+                ParseClockError {
+                    kind: $kind,
+                    value: value.to_string(),
+                    expected: $expected,
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(setting: $name) -> u8 {
+                match setting {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = ParseClockError;
+
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match $name::from_raw(value) {
+                    $name::Unknown(_) => Err($name::parse_error(value)),
+                    setting => Ok(setting),
+                }
+            }
+        }
+    };
+}
+
+clock_enum! {
+    /// The type of the crystal that drives the PLL
+    ///
+    /// https://github.com/bouffalolab/bl_iot_sdk/blob/ee4a10b1a1e3609243bd5e7b3a45f02d768f6c14/components/bl602/bl602_std/bl602_std/StdDriver/Inc/bl602_glb.h#L286-L297
+    pub enum XtalType("crystal", "none, 24M, 32M, 38.4M, 40M, 26M, RC32M") {
+        None = 0,
+        Xtal24M = 1,
+        Xtal32M = 2,
+        Xtal38P4M = 3,
+        Xtal40M = 4,
+        Xtal26M = 5,
+        Rc32M = 6,
+    }
+}
+
+impl fmt::Display for XtalType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            XtalType::None => "none",
+            XtalType::Xtal24M => "24M",
+            XtalType::Xtal32M => "32M",
+            XtalType::Xtal38P4M => "38.4M",
+            XtalType::Xtal40M => "40M",
+            XtalType::Xtal26M => "26M",
+            XtalType::Rc32M => "RC32M",
+            XtalType::Unknown(value) => return write!(f, "{}", value),
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for XtalType {
+    type Err = ParseClockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "NONE" => Ok(XtalType::None),
+            "24M" => Ok(XtalType::Xtal24M),
+            "32M" => Ok(XtalType::Xtal32M),
+            "38.4M" | "38P4M" => Ok(XtalType::Xtal38P4M),
+            "40M" => Ok(XtalType::Xtal40M),
+            "26M" => Ok(XtalType::Xtal26M),
+            "RC32M" => Ok(XtalType::Rc32M),
+            _ => Self::parse_raw(s),
+        }
+    }
+}
+
+impl_serde_from_str!(XtalType);
+
+clock_enum! {
+    /// The clock source of the system clock
+    ///
+    /// https://github.com/bouffalolab/bl_iot_sdk/blob/ee4a10b1a1e3609243bd5e7b3a45f02d768f6c14/components/bl602/bl602_std/bl602_std/StdDriver/Inc/bl602_glb.h#L299-L312
+    pub enum PllClock("PLL clock", "RC32M, XTAL, 48M, 120M, 160M, 192M") {
+        Rc32M = 0,
+        Xtal = 1,
+        Pll48M = 2,
+        Pll120M = 3,
+        Pll160M = 4,
+        Pll192M = 5,
+    }
+}
+
+impl fmt::Display for PllClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PllClock::Rc32M => "RC32M",
+            PllClock::Xtal => "XTAL",
+            PllClock::Pll48M => "48M",
+            PllClock::Pll120M => "120M",
+            PllClock::Pll160M => "160M",
+            PllClock::Pll192M => "192M",
+            PllClock::Unknown(value) => return write!(f, "{}", value),
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for PllClock {
+    type Err = ParseClockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "RC32M" => Ok(PllClock::Rc32M),
+            "XTAL" => Ok(PllClock::Xtal),
+            "48M" => Ok(PllClock::Pll48M),
+            "120M" => Ok(PllClock::Pll120M),
+            "160M" => Ok(PllClock::Pll160M),
+            "192M" => Ok(PllClock::Pll192M),
+            _ => Self::parse_raw(s),
+        }
+    }
+}
+
+impl_serde_from_str!(PllClock);
+
+clock_enum! {
+    /// The clock source of the serial flash controller
+    ///
+    /// https://github.com/bouffalolab/bl_iot_sdk/blob/ee4a10b1a1e3609243bd5e7b3a45f02d768f6c14/components/bl602/bl602_std/bl602_std/StdDriver/Inc/bl602_glb.h#L101-L111
+    pub enum FlashClockType("flash clock", "120M, XTAL, 48M, 80M, BCLK, 96M") {
+        Pll120M = 0,
+        Xtal = 1,
+        Pll48M = 2,
+        Pll80M = 3,
+        Bclk = 4,
+        Pll96M = 5,
+    }
+}
+
+impl fmt::Display for FlashClockType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FlashClockType::Pll120M => "120M",
+            FlashClockType::Xtal => "XTAL",
+            FlashClockType::Pll48M => "48M",
+            FlashClockType::Pll80M => "80M",
+            FlashClockType::Bclk => "BCLK",
+            FlashClockType::Pll96M => "96M",
+            FlashClockType::Unknown(value) => return write!(f, "{}", value),
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for FlashClockType {
+    type Err = ParseClockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "120M" => Ok(FlashClockType::Pll120M),
+            "XTAL" => Ok(FlashClockType::Xtal),
+            "48M" => Ok(FlashClockType::Pll48M),
+            "80M" => Ok(FlashClockType::Pll80M),
+            "BCLK" => Ok(FlashClockType::Bclk),
+            "96M" => Ok(FlashClockType::Pll96M),
+            _ => Self::parse_raw(s),
+        }
+    }
+}
+
+impl_serde_from_str!(FlashClockType);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn it_should_reject_out_of_range_values() {
+        assert_eq!(XtalType::try_from(4).unwrap(), XtalType::Xtal40M);
+        assert!(XtalType::try_from(7).is_err());
+        assert!(PllClock::try_from(6).is_err());
+        assert!(FlashClockType::try_from(6).is_err());
+    }
+
+    #[test]
+    fn it_should_keep_unknown_raw_values() {
+        assert_eq!(XtalType::from_raw(4), XtalType::Xtal40M);
+        assert_eq!(XtalType::from_raw(9), XtalType::Unknown(9));
+        assert_eq!(u8::from(XtalType::Unknown(9)), 9);
+        assert!(!PllClock::from_raw(6).is_known());
+        assert_eq!(FlashClockType::Unknown(7).to_string(), "7");
+        assert_eq!(
+            "7".parse::<FlashClockType>().unwrap(),
+            FlashClockType::Unknown(7)
+        );
+        assert_eq!("0x09".parse::<XtalType>().unwrap(), XtalType::Unknown(9));
+        assert_eq!("4".parse::<XtalType>().unwrap(), XtalType::Xtal40M);
+        assert!("0x100".parse::<PllClock>().is_err());
+    }
+
+    #[test]
+    fn it_should_parse_display_output() {
+        for val in 0..=6 {
+            let xtal = XtalType::try_from(val).unwrap();
+
+            assert_eq!(xtal.to_string().parse::<XtalType>().unwrap(), xtal);
+        }
+
+        assert_eq!("160m".parse::<PllClock>().unwrap(), PllClock::Pll160M);
+        assert_eq!(
+            "bclk".parse::<FlashClockType>().unwrap(),
+            FlashClockType::Bclk
+        );
+        assert!("100M".parse::<PllClock>().is_err());
+    }
+}
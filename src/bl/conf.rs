@@ -0,0 +1,541 @@
+//! Reading and writing the INI-style configuration files of the vendor SDK
+//!
+//! The SDK describes flash chips in `flash_para/*.conf` (in a `[FLASH_CFG]` section) and the boot
+//! header defaults in `efuse_bootheader_cfg.conf` (in a `[BOOTHEADER_CFG]` section). The keys in
+//! those sections correspond to the fields of `FlashConfig`, `ClockConfig` and `BootConfig`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::{BootConfig, ClockConfig, FlashClockType, FlashConfig, PllClock, XtalType};
+
+/// The names of the sections that can contain a boot header or flash configuration, in order of
+/// preference
+const BOOT_HEADER_SECTIONS: &[&str] = &["BOOTHEADER_CFG", "BOOTHEADER_CPU0_CFG", "FLASH_CFG"];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfError {
+    #[error("Invalid line {}: {}", _0, _1)]
+    InvalidLine(usize, String),
+    #[error("Missing key {} in section [{}]", _1, _0)]
+    MissingKey(String, String),
+    #[error("Invalid value for {}: {}", _0, _1)]
+    InvalidValue(String, String),
+    #[error("No boot header or flash configuration section found")]
+    MissingSection,
+}
+
+/// A section of a configuration file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Section {
+    /// The name of the section, without the brackets
+    pub name: String,
+    /// The key-value pairs of the section, in the order they appear in the file
+    pub entries: Vec<(String, String)>,
+}
+
+impl Section {
+    /// Creates a new, empty section with the given `name`
+    pub fn new<S: Into<String>>(name: S) -> Section {
+        Section {
+            name: name.into(),
+            entries: vec![],
+        }
+    }
+
+    /// Returns the value of `key`, if present
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns whether the section contains `key`
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the value of `key` as an integer, which can be either decimal or hexadecimal with a
+    /// `0x` prefix
+    pub fn get_int(&self, key: &str) -> Result<u32, ConfError> {
+        let value = self
+            .get(key)
+            .ok_or_else(|| ConfError::MissingKey(self.name.clone(), key.to_string()))?;
+
+        parse_int(value).ok_or_else(|| ConfError::InvalidValue(key.to_string(), value.to_string()))
+    }
+
+    /// Sets `key` to `value`, replacing the existing value if the key is already present
+    pub fn set<V: ToString>(&mut self, key: &str, value: V) {
+        let value = value.to_string();
+
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+    }
+}
+
+/// A configuration file made up of sections
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conf {
+    pub sections: Vec<Section>,
+}
+
+impl Conf {
+    /// Returns the section with the given `name`
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Returns the section that contains the boot header or flash configuration
+    pub fn boot_header_section(&self) -> Result<&Section, ConfError> {
+        BOOT_HEADER_SECTIONS
+            .iter()
+            .find_map(|name| self.section(name))
+            .ok_or(ConfError::MissingSection)
+    }
+}
+
+impl FromStr for Conf {
+    type Err = ConfError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conf = Conf::default();
+
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+
+            // Skip empty lines and comments
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                conf.sections
+                    .push(Section::new(line[1..line.len() - 1].trim()));
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(ConfError::InvalidLine(n + 1, line.to_string())),
+            };
+
+            match conf.sections.last_mut() {
+                Some(section) => section.entries.push((key.to_string(), value.to_string())),
+                None => return Err(ConfError::InvalidLine(n + 1, line.to_string())),
+            }
+        }
+
+        Ok(conf)
+    }
+}
+
+impl fmt::Display for Conf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, section) in self.sections.iter().enumerate() {
+            if n > 0 {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "[{}]", section.name)?;
+
+            for (key, value) in &section.entries {
+                writeln!(f, "{} = {}", key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A mutable reference to a configuration field of a given width
+enum Field<'a> {
+    U8(&'a mut u8),
+    U16(&'a mut u16),
+}
+
+/// Returns the conf keys of the flash configuration along with the fields they correspond to
+fn flash_config_fields(c: &mut FlashConfig) -> Vec<(&'static str, Field<'_>)> {
+    let [reg_read_cmd0, reg_read_cmd1, _, _] = &mut c.read_reg_cmd_buffer;
+    let [reg_write_cmd0, reg_write_cmd1, _, _] = &mut c.write_reg_cmd_buffer;
+
+    vec![
+        ("io_mode", Field::U8(&mut c.io_mode)),
+        (
+            "cont_read_support",
+            Field::U8(&mut c.continuous_read_support),
+        ),
+        ("sfctrl_clk_delay", Field::U8(&mut c.clock_delay)),
+        ("sfctrl_clk_invert", Field::U8(&mut c.clock_invert)),
+        ("reset_en_cmd", Field::U8(&mut c.reset_enable_cmd)),
+        ("reset_cmd", Field::U8(&mut c.reset_cmd)),
+        (
+            "exit_contread_cmd",
+            Field::U8(&mut c.reset_continuous_read_cmd),
+        ),
+        (
+            "exit_contread_cmd_size",
+            Field::U8(&mut c.reset_continuous_read_cmd_size),
+        ),
+        ("jedecid_cmd", Field::U8(&mut c.jedec_id_cmd)),
+        (
+            "jedecid_cmd_dmy_clk",
+            Field::U8(&mut c.jedec_id_cmd_dummy_clock),
+        ),
+        ("qpi_jedecid_cmd", Field::U8(&mut c.qpi_jedec_id_cmd)),
+        (
+            "qpi_jedecid_dmy_clk",
+            Field::U8(&mut c.qpi_jedec_id_cmd_dummy_clock),
+        ),
+        ("sector_size", Field::U8(&mut c.sector_size)),
+        ("mfg_id", Field::U8(&mut c.manufacturer_id)),
+        ("page_size", Field::U16(&mut c.page_size)),
+        ("chip_erase_cmd", Field::U8(&mut c.chip_erase_cmd)),
+        ("sector_erase_cmd", Field::U8(&mut c.sector_erase_cmd)),
+        ("blk32k_erase_cmd", Field::U8(&mut c.block_erase_32k_cmd)),
+        ("blk64k_erase_cmd", Field::U8(&mut c.block_erase_64k_cmd)),
+        ("write_enable_cmd", Field::U8(&mut c.write_enable_cmd)),
+        ("page_prog_cmd", Field::U8(&mut c.page_program_cmd)),
+        ("qpage_prog_cmd", Field::U8(&mut c.qio_page_program_cmd)),
+        (
+            "qual_page_prog_addr_mode",
+            Field::U8(&mut c.qio_page_program_address_mode),
+        ),
+        ("fast_read_cmd", Field::U8(&mut c.fast_read_cmd)),
+        (
+            "fast_read_dmy_clk",
+            Field::U8(&mut c.fast_read_cmd_dummy_clock),
+        ),
+        ("qpi_fast_read_cmd", Field::U8(&mut c.qpi_fast_read_cmd)),
+        (
+            "qpi_fast_read_dmy_clk",
+            Field::U8(&mut c.qpi_fast_read_cmd_dummy_clock),
+        ),
+        (
+            "fast_read_do_cmd",
+            Field::U8(&mut c.fast_read_dual_output_cmd),
+        ),
+        (
+            "fast_read_do_dmy_clk",
+            Field::U8(&mut c.fast_read_dual_output_cmd_dummy_clock),
+        ),
+        ("fast_read_dio_cmd", Field::U8(&mut c.fast_read_dual_io_cmd)),
+        (
+            "fast_read_dio_dmy_clk",
+            Field::U8(&mut c.fast_read_dual_io_cmd_dummy_clock),
+        ),
+        (
+            "fast_read_qo_cmd",
+            Field::U8(&mut c.fast_read_quad_output_cmd),
+        ),
+        (
+            "fast_read_qo_dmy_clk",
+            Field::U8(&mut c.fast_read_quad_output_cmd_dummy_clock),
+        ),
+        ("fast_read_qio_cmd", Field::U8(&mut c.fast_read_quad_io_cmd)),
+        (
+            "fast_read_qio_dmy_clk",
+            Field::U8(&mut c.fast_read_quad_io_cmd_dummy_clock),
+        ),
+        (
+            "qpi_fast_read_qio_cmd",
+            Field::U8(&mut c.qpi_fast_read_quad_io_cmd),
+        ),
+        (
+            "qpi_fast_read_qio_dmy_clk",
+            Field::U8(&mut c.qpi_fast_read_quad_io_cmd_dummy_clock),
+        ),
+        ("qpi_page_prog_cmd", Field::U8(&mut c.qpi_program_cmd)),
+        (
+            "write_vreg_enable_cmd",
+            Field::U8(&mut c.volatile_register_write_enable_cmd),
+        ),
+        ("wel_reg_index", Field::U8(&mut c.write_enable_reg_index)),
+        ("qe_reg_index", Field::U8(&mut c.quad_mode_enable_reg_index)),
+        ("busy_reg_index", Field::U8(&mut c.busy_status_reg_index)),
+        ("wel_bit_pos", Field::U8(&mut c.write_enable_bit_pos)),
+        ("qe_bit_pos", Field::U8(&mut c.quad_enable_bit_pos)),
+        ("busy_bit_pos", Field::U8(&mut c.busy_status_bit_pos)),
+        (
+            "wel_reg_write_len",
+            Field::U8(&mut c.write_enable_reg_write_len),
+        ),
+        (
+            "wel_reg_read_len",
+            Field::U8(&mut c.write_enable_reg_read_len),
+        ),
+        (
+            "qe_reg_write_len",
+            Field::U8(&mut c.quad_enable_reg_write_len),
+        ),
+        (
+            "qe_reg_read_len",
+            Field::U8(&mut c.quad_enable_reg_read_len),
+        ),
+        (
+            "release_power_down",
+            Field::U8(&mut c.release_power_down_cmd),
+        ),
+        (
+            "busy_reg_read_len",
+            Field::U8(&mut c.busy_status_reg_read_len),
+        ),
+        ("reg_read_cmd0", Field::U8(reg_read_cmd0)),
+        ("reg_read_cmd1", Field::U8(reg_read_cmd1)),
+        ("reg_write_cmd0", Field::U8(reg_write_cmd0)),
+        ("reg_write_cmd1", Field::U8(reg_write_cmd1)),
+        ("enter_qpi_cmd", Field::U8(&mut c.enter_qpi_cmd)),
+        ("exit_qpi_cmd", Field::U8(&mut c.exit_qpi_cmd)),
+        ("cont_read_code", Field::U8(&mut c.continuous_read_mode_cfg)),
+        (
+            "cont_read_exit_code",
+            Field::U8(&mut c.continuous_read_mode_exit_cfg),
+        ),
+        ("burst_wrap_cmd", Field::U8(&mut c.enable_burst_wrap_cmd)),
+        (
+            "burst_wrap_dmy_clk",
+            Field::U8(&mut c.enable_burst_wrap_cmd_dummy_clock),
+        ),
+        (
+            "burst_wrap_data_mode",
+            Field::U8(&mut c.burst_wrap_data_mode),
+        ),
+        ("burst_wrap_code", Field::U8(&mut c.burst_wrap_data)),
+        (
+            "de_burst_wrap_cmd",
+            Field::U8(&mut c.disable_burst_wrap_cmd),
+        ),
+        (
+            "de_burst_wrap_cmd_dmy_clk",
+            Field::U8(&mut c.disable_burst_wrap_cmd_dummy_clock),
+        ),
+        (
+            "de_burst_wrap_code_mode",
+            Field::U8(&mut c.disable_burst_wrap_data_mode),
+        ),
+        (
+            "de_burst_wrap_code",
+            Field::U8(&mut c.disable_burst_wrap_data),
+        ),
+        ("sector_erase_time", Field::U16(&mut c.sector_erase_time_4k)),
+        (
+            "blk32k_erase_time",
+            Field::U16(&mut c.sector_erase_time_32k),
+        ),
+        (
+            "blk64k_erase_time",
+            Field::U16(&mut c.sector_erase_time_64k),
+        ),
+        ("page_prog_time", Field::U16(&mut c.page_program_time)),
+        ("chip_erase_time", Field::U16(&mut c.chip_erase_time)),
+        ("power_down_delay", Field::U8(&mut c.power_down_delay)),
+        ("qe_data", Field::U8(&mut c.quad_enable_data)),
+    ]
+}
+
+/// Reads the fields of `fields` from `section`
+fn read_fields(section: &Section, fields: Vec<(&'static str, Field<'_>)>) -> Result<(), ConfError> {
+    for (key, field) in fields {
+        let value = section.get_int(key)?;
+        let invalid = || ConfError::InvalidValue(key.to_string(), value.to_string());
+
+        match field {
+            Field::U8(field) => *field = u8::try_from(value).map_err(|_| invalid())?,
+            Field::U16(field) => *field = u16::try_from(value).map_err(|_| invalid())?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the fields of `fields` to `section`
+fn write_fields(section: &mut Section, fields: Vec<(&'static str, Field<'_>)>) {
+    for (key, field) in fields {
+        match field {
+            Field::U8(val) => section.set(key, format!("{:#04x}", val)),
+            Field::U16(val) => section.set(key, val),
+        }
+    }
+}
+
+/// Builds a `FlashConfig` from the keys in `section`
+pub fn read_flash_config(section: &Section) -> Result<FlashConfig, ConfError> {
+    let mut config = FlashConfig::default();
+
+    read_fields(section, flash_config_fields(&mut config))?;
+    config.update_crc32();
+
+    Ok(config)
+}
+
+/// Writes the keys of the given flash `config` to `section`
+pub fn write_flash_config(config: &FlashConfig, section: &mut Section) {
+    let mut config = *config;
+
+    write_fields(section, flash_config_fields(&mut config));
+}
+
+/// Builds a `ClockConfig` from the keys in `section`
+pub fn read_clock_config(section: &Section) -> Result<ClockConfig, ConfError> {
+    let value = |key: &str| -> Result<u8, ConfError> {
+        let value = section.get_int(key)?;
+
+        u8::try_from(value).map_err(|_| ConfError::InvalidValue(key.to_string(), value.to_string()))
+    };
+
+    // Unknown clock values are kept like in the boot header, and rejected when building an image
+    let mut config = ClockConfig::default();
+
+    config.xtal_type = XtalType::from_raw(value("xtal_type")?);
+    config.pll_clock = PllClock::from_raw(value("pll_clk")?);
+    config.hclk_divider = value("hclk_div")?;
+    config.bclk_divider = value("bclk_div")?;
+    config.flash_clock_type = FlashClockType::from_raw(value("flash_clk_type")?);
+    config.flash_clock_divider = value("flash_clk_div")?;
+    config.update_crc32();
+
+    Ok(config)
+}
+
+/// Writes the keys of the given clock `config` to `section`
+pub fn write_clock_config(config: &ClockConfig, section: &mut Section) {
+    section.set("xtal_type", u8::from(config.xtal_type));
+    section.set("pll_clk", u8::from(config.pll_clock));
+    section.set("hclk_div", config.hclk_divider);
+    section.set("bclk_div", config.bclk_divider);
+    section.set("flash_clk_type", u8::from(config.flash_clock_type));
+    section.set("flash_clk_div", config.flash_clock_divider);
+}
+
+/// Builds a `BootConfig` from the keys in `section`
+pub fn read_boot_config(section: &Section) -> Result<BootConfig, ConfError> {
+    let value = |key: &str| section.get_int(key);
+    let flag = |key: &str| value(key).map(|v| v != 0);
+
+    Ok(BootConfig {
+        signature: (value("sign")? as u8).into(),
+        encryption: (value("encrypt_type")? as u8).into(),
+        key_select: value("key_sel")? as u8 & 0b11,
+        no_segment: flag("no_segment")?,
+        cache_enable: flag("cache_enable")?,
+        notload_in_bootrom: flag("notload_in_bootrom")?,
+        aes_region_lock: flag("aes_region_lock")?,
+        cache_way_disable: value("cache_way_disable")? as u8 & 0xf,
+        crc_ignore: flag("crc_ignore")?,
+        hash_ignore: flag("hash_ignore")?,
+        halt_boot2: flag("hal_boot_after")?,
+        ..BootConfig::default()
+    })
+}
+
+/// Writes the keys of the given boot `config` to `section`
+pub fn write_boot_config(config: &BootConfig, section: &mut Section) {
+    section.set("sign", u8::from(config.signature));
+    section.set("encrypt_type", u8::from(config.encryption));
+    section.set("key_sel", config.key_select);
+    section.set("no_segment", config.no_segment as u8);
+    section.set("cache_enable", config.cache_enable as u8);
+    section.set("notload_in_bootrom", config.notload_in_bootrom as u8);
+    section.set("aes_region_lock", config.aes_region_lock as u8);
+    section.set(
+        "cache_way_disable",
+        format!("{:#04x}", config.cache_way_disable),
+    );
+    section.set("crc_ignore", config.crc_ignore as u8);
+    section.set("hash_ignore", config.hash_ignore as u8);
+    section.set("hal_boot_after", config.halt_boot2 as u8);
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal integer
+fn parse_int(value: &str) -> Option<u32> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bl::Firmware;
+    use std::io::Cursor;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    const REFERENCE_CONF: &str = include_str!("../../test/efuse_bootheader_cfg.conf");
+
+    #[test]
+    fn it_should_read_reference_boot_header_conf() {
+        let firmware = Firmware::from_reader(Cursor::new(REFERENCE_FIRMWARE)).unwrap();
+        let conf: Conf = REFERENCE_CONF.parse().unwrap();
+        let section = conf.boot_header_section().unwrap();
+
+        assert_eq!(
+            &read_flash_config(section).unwrap(),
+            firmware.flash_config()
+        );
+        assert_eq!(
+            &read_clock_config(section).unwrap(),
+            firmware.clock_config()
+        );
+        assert_eq!(&read_boot_config(section).unwrap(), firmware.boot_config());
+    }
+
+    #[test]
+    fn it_should_round_trip_conf() {
+        let firmware = Firmware::from_reader(Cursor::new(REFERENCE_FIRMWARE)).unwrap();
+        let mut section = Section::new("FLASH_CFG");
+
+        write_flash_config(firmware.flash_config(), &mut section);
+        write_clock_config(firmware.clock_config(), &mut section);
+        write_boot_config(firmware.boot_config(), &mut section);
+
+        let conf = Conf {
+            sections: vec![section],
+        };
+        let parsed: Conf = conf.to_string().parse().unwrap();
+
+        assert_eq!(parsed, conf);
+        assert_eq!(
+            &read_flash_config(parsed.boot_header_section().unwrap()).unwrap(),
+            firmware.flash_config()
+        );
+    }
+
+    #[test]
+    fn it_should_keep_unknown_clock_values() {
+        let mut clock_config = *Firmware::from_reader(Cursor::new(REFERENCE_FIRMWARE))
+            .unwrap()
+            .clock_config();
+        clock_config.xtal_type = XtalType::Unknown(9);
+        clock_config.update_crc32();
+
+        let mut section = Section::new("FLASH_CFG");
+        write_clock_config(&clock_config, &mut section);
+
+        assert_eq!(read_clock_config(&section).unwrap(), clock_config);
+    }
+
+    #[test]
+    fn it_should_report_missing_keys() {
+        let conf: Conf = "[FLASH_CFG]\nio_mode = 4\n".parse().unwrap();
+
+        assert_eq!(
+            read_flash_config(conf.boot_header_section().unwrap()),
+            Err(ConfError::MissingKey(
+                "FLASH_CFG".to_string(),
+                "cont_read_support".to_string()
+            ))
+        );
+    }
+}
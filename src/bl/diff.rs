@@ -0,0 +1,376 @@
+//! Comparing two firmware images or whole-flash dumps
+//!
+//! The boot headers are compared field by field, the segments by their address and the hash of
+//! their content, and whole-flash dumps partition by partition.
+
+use std::fmt;
+use std::io::Cursor;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::partition::{PartitionError, PartitionTable, PARTITION_TABLE_OFFSETS};
+use super::{BootHeader, Firmware, ParseError};
+
+#[derive(Error, Debug)]
+pub enum DiffError {
+    #[error("Failed to parse firmware image {}: {}", _0, _1)]
+    ParseError(String, ParseError),
+    #[error("Partition table error: {}", _0)]
+    PartitionError(#[from] PartitionError),
+    #[error("Can't compare a whole-flash dump with a single firmware image")]
+    MixedInputs,
+}
+
+/// A difference between two images
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Difference {
+    /// The path of the field that differs, i.e. `flash_config.io_mode`
+    pub path: String,
+    /// The value in the first image, or `None` if it's missing
+    pub left: Option<String>,
+    /// The value in the second image, or `None` if it's missing
+    pub right: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let left = self.left.as_deref().unwrap_or("<missing>");
+        let right = self.right.as_deref().unwrap_or("<missing>");
+
+        write!(f, "{}: {} -> {}", self.path, left, right)
+    }
+}
+
+/// Compares two firmware images or two whole-flash dumps and returns the differences
+pub fn diff_images(left: &[u8], right: &[u8]) -> Result<Vec<Difference>, DiffError> {
+    let mut diffs = vec![];
+
+    match (
+        PartitionTable::is_flash_dump(left),
+        PartitionTable::is_flash_dump(right),
+    ) {
+        (true, true) => diff_flash_dumps(left, right, &mut diffs)?,
+        (false, false) => diff_firmware("", left, right, &mut diffs)?,
+        _ => return Err(DiffError::MixedInputs),
+    }
+
+    Ok(diffs)
+}
+
+/// Compares two whole-flash dumps partition by partition
+fn diff_flash_dumps(
+    left: &[u8],
+    right: &[u8],
+    diffs: &mut Vec<Difference>,
+) -> Result<(), DiffError> {
+    // Compare the boot2 image in front of the partition tables
+    let boot2_len = PARTITION_TABLE_OFFSETS[0] as usize;
+    diff_firmware("boot2", &left[..boot2_len], &right[..boot2_len], diffs)?;
+
+    let left_table = PartitionTable::from_flash(left)?;
+    let right_table = PartitionTable::from_flash(right)?;
+
+    for entry in &left_table.entries {
+        let other = right_table.entries.iter().find(|e| e.name == entry.name);
+        let path = format!("partition.{}", entry.name);
+
+        let other = match other {
+            Some(other) => other,
+            None => {
+                diffs.push(Difference {
+                    path,
+                    left: Some(format!("{:#010x}", entry.active_address())),
+                    right: None,
+                });
+                continue;
+            }
+        };
+
+        push_if_different(
+            diffs,
+            format!("{}.address", path),
+            format!("{:#010x}", entry.active_address()),
+            format!("{:#010x}", other.active_address()),
+        );
+        push_if_different(
+            diffs,
+            format!("{}.max_len", path),
+            entry.active_max_len(),
+            other.active_max_len(),
+        );
+
+        let left_data = region(left, entry.active_address(), entry.active_max_len());
+        let right_data = region(right, other.active_address(), other.active_max_len());
+
+        // Compare the partitions as firmware images if they both contain one
+        if left_data.starts_with(b"BFNP") && right_data.starts_with(b"BFNP") {
+            diff_firmware(&path, left_data, right_data, diffs)?;
+        } else {
+            push_if_different(
+                diffs,
+                format!("{}.sha256", path),
+                sha256_hex(left_data),
+                sha256_hex(right_data),
+            );
+        }
+    }
+
+    for entry in &right_table.entries {
+        if !left_table.entries.iter().any(|e| e.name == entry.name) {
+            diffs.push(Difference {
+                path: format!("partition.{}", entry.name),
+                left: None,
+                right: Some(format!("{:#010x}", entry.active_address())),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares the boot headers, the segments and the image data of two firmware images
+fn diff_firmware(
+    prefix: &str,
+    left: &[u8],
+    right: &[u8],
+    diffs: &mut Vec<Difference>,
+) -> Result<(), DiffError> {
+    let name = |side: &str| {
+        if prefix.is_empty() {
+            side.to_string()
+        } else {
+            format!("{} of the {} image", prefix, side)
+        }
+    };
+
+    let left_fw = Firmware::from_reader(Cursor::new(left))
+        .map_err(|err| DiffError::ParseError(name("first"), err))?;
+    let right_fw = Firmware::from_reader(Cursor::new(right))
+        .map_err(|err| DiffError::ParseError(name("second"), err))?;
+
+    let path = |field: &str| {
+        if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        }
+    };
+
+    // Serializing the boot header can't fail
+    let left_value = serde_json::to_value(BootHeader::from(&left_fw)).unwrap();
+    let right_value = serde_json::to_value(BootHeader::from(&right_fw)).unwrap();
+
+    diff_values(prefix, &left_value, &right_value, diffs);
+
+    push_if_different(
+        diffs,
+        path("crc32"),
+        format!("{:#010x}", left_fw.crc32()),
+        format!("{:#010x}", right_fw.crc32()),
+    );
+
+    // Compare the segments by their destination address
+    for segment in &left_fw.segments {
+        let seg_path = path(&format!("segment.{:#010x}", segment.dest_addr.0));
+        let other = right_fw
+            .segments
+            .iter()
+            .find(|s| s.dest_addr == segment.dest_addr);
+
+        let left = describe_data(&segment.data);
+        let right = other.map(|s| describe_data(&s.data));
+
+        if right.as_ref() != Some(&left) {
+            diffs.push(Difference {
+                path: seg_path,
+                left: Some(left),
+                right,
+            });
+        }
+    }
+
+    for segment in &right_fw.segments {
+        if !left_fw
+            .segments
+            .iter()
+            .any(|s| s.dest_addr == segment.dest_addr)
+        {
+            diffs.push(Difference {
+                path: path(&format!("segment.{:#010x}", segment.dest_addr.0)),
+                left: None,
+                right: Some(describe_data(&segment.data)),
+            });
+        }
+    }
+
+    // Compare the segments of encrypted images as they are if they couldn't be decrypted
+    if left_fw.encrypted_segments().is_some() || right_fw.encrypted_segments().is_some() {
+        push_if_different(
+            diffs,
+            path("segments.sha256"),
+            left_fw
+                .encrypted_segments()
+                .map(sha256_hex)
+                .unwrap_or_default(),
+            right_fw
+                .encrypted_segments()
+                .map(sha256_hex)
+                .unwrap_or_default(),
+        );
+    }
+
+    // Compare the data following the boot header for images without segments
+    if left_fw.boot_config().no_segment && right_fw.boot_config().no_segment {
+        push_if_different(
+            diffs,
+            path("data.sha256"),
+            sha256_hex(&left[left_fw.header_len().min(left.len())..]),
+            sha256_hex(&right[right_fw.header_len().min(right.len())..]),
+        );
+    }
+
+    Ok(())
+}
+
+/// Recursively compares two JSON values and records the leaves that differ
+fn diff_values(path: &str, left: &Value, right: &Value, diffs: &mut Vec<Difference>) {
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+
+    match (left, right) {
+        (Value::Object(left), Value::Object(right)) => {
+            for (key, value) in left {
+                match right.get(key) {
+                    Some(other) => diff_values(&join(key), value, other, diffs),
+                    None => diffs.push(Difference {
+                        path: join(key),
+                        left: Some(value_to_string(value)),
+                        right: None,
+                    }),
+                }
+            }
+
+            for (key, value) in right {
+                if !left.contains_key(key) {
+                    diffs.push(Difference {
+                        path: join(key),
+                        left: None,
+                        right: Some(value_to_string(value)),
+                    });
+                }
+            }
+        }
+        (left, right) => push_if_different(
+            diffs,
+            path.to_string(),
+            value_to_string(left),
+            value_to_string(right),
+        ),
+    }
+}
+
+fn push_if_different<T: fmt::Display + PartialEq>(
+    diffs: &mut Vec<Difference>,
+    path: String,
+    left: T,
+    right: T,
+) {
+    if left != right {
+        diffs.push(Difference {
+            path,
+            left: Some(left.to_string()),
+            right: Some(right.to_string()),
+        });
+    }
+}
+
+/// Returns the JSON value as a string, without quotes if it's a string
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Returns the region of `buf` at `offset` with the given `len`, clamped to the end of `buf`
+fn region(buf: &[u8], offset: u32, len: u32) -> &[u8] {
+    let start = (offset as usize).min(buf.len());
+    let end = start.saturating_add(len as usize).min(buf.len());
+
+    &buf[start..end]
+}
+
+fn describe_data(data: &[u8]) -> String {
+    format!("{} bytes, sha256 {}", data.len(), sha256_hex(data))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    const EFLASH_FIRMWARE: &[u8] = include_bytes!("../../test/eflash_loader_40m.bin");
+
+    #[test]
+    fn it_should_find_no_differences_in_identical_images() {
+        assert!(diff_images(EFLASH_FIRMWARE, EFLASH_FIRMWARE)
+            .unwrap()
+            .is_empty());
+        assert!(diff_images(REFERENCE_FIRMWARE, REFERENCE_FIRMWARE)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_should_compare_headers_and_segments() {
+        let mut other = EFLASH_FIRMWARE.to_vec();
+
+        // Change the io_mode of the flash config and the last byte of the segment
+        other[0xc] = 0x11;
+        *other.last_mut().unwrap() ^= 0xff;
+
+        let diffs = diff_images(EFLASH_FIRMWARE, &other).unwrap();
+        let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+
+        assert_eq!(paths, ["flash_config.io_mode", "segment.0x22010000"]);
+        assert_eq!(diffs[0].left.as_deref(), Some("20"));
+        assert_eq!(diffs[0].right.as_deref(), Some("17"));
+    }
+
+    #[test]
+    fn it_should_compare_flash_dumps_by_partition() {
+        let mut other = REFERENCE_FIRMWARE.to_vec();
+
+        // Change a byte in the mfg partition
+        other[0x160000] ^= 0xff;
+
+        let diffs = diff_images(REFERENCE_FIRMWARE, &other).unwrap();
+
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "partition.mfg.sha256");
+    }
+
+    #[test]
+    fn it_should_refuse_mixed_inputs() {
+        assert!(matches!(
+            diff_images(REFERENCE_FIRMWARE, EFLASH_FIRMWARE),
+            Err(DiffError::MixedInputs)
+        ));
+    }
+}
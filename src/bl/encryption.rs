@@ -0,0 +1,228 @@
+//! AES-CTR encryption of firmware images
+//!
+//! Encrypted images have a 16 byte AES IV followed by its CRC32 checksum right after the boot
+//! header. Everything after the IV block is encrypted with AES-CTR, using the IV as the initial
+//! counter block.
+
+use std::fmt;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::{Aes128, Aes192, Aes256};
+use thiserror::Error;
+
+use super::{crc32, EncryptionType};
+
+/// The length of the AES IV
+pub const AES_IV_LEN: usize = 16;
+
+/// The length of the AES IV block, which is the IV followed by its CRC32 checksum
+pub const AES_IV_BLOCK_LEN: usize = AES_IV_LEN + 4;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type Aes192Ctr = ctr::Ctr128BE<Aes192>;
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Invalid AES key length: {} bytes (expected 16, 24 or 32)", _0)]
+    InvalidKeyLength(usize),
+    #[error("Invalid AES IV length: {} bytes (expected 16)", _0)]
+    InvalidIvLength(usize),
+    #[error("Invalid hexadecimal string: {}", _0)]
+    InvalidHex(String),
+    #[error("Invalid AES IV checksum (expected {:#010x}, got {:#010x})", _0, _1)]
+    InvalidIvChecksum(u32, u32),
+    #[error("The image is encrypted with {}, but the key is for {}", _0, _1)]
+    KeyMismatch(EncryptionType, EncryptionType),
+    #[error("The image is encrypted, but no AES key was given")]
+    MissingKey,
+    #[error("The image is encrypted, but no AES IV was given")]
+    MissingIv,
+    #[error(
+        "The last 4 bytes of the AES IV must be zero for images that execute in place, since the flash controller counts the flash offset there"
+    )]
+    XipIvCounter,
+}
+
+/// An AES-128, AES-192 or AES-256 key
+#[derive(Clone, Eq, PartialEq)]
+pub struct AesKey {
+    bytes: Vec<u8>,
+}
+
+impl AesKey {
+    /// Creates a key from its raw `bytes`, which determines the key size
+    pub fn from_bytes(bytes: &[u8]) -> Result<AesKey, EncryptionError> {
+        match bytes.len() {
+            16 | 24 | 32 => Ok(AesKey {
+                bytes: bytes.to_vec(),
+            }),
+            len => Err(EncryptionError::InvalidKeyLength(len)),
+        }
+    }
+
+    /// Creates a key from the contents of a key file, which is either the key as a hexadecimal
+    /// string, like in the vendor SDK configuration files, or the raw key
+    pub fn from_file_contents(contents: &[u8]) -> Result<AesKey, EncryptionError> {
+        let s = String::from_utf8_lossy(contents);
+
+        // A raw key may happen to be valid hexadecimal, but a hexadecimal key is far more likely
+        match parse_hex(s.trim()) {
+            Ok(bytes) => AesKey::from_bytes(&bytes),
+            Err(_) => AesKey::from_bytes(contents),
+        }
+    }
+
+    /// Returns the type of encryption this key is used for
+    pub fn encryption_type(&self) -> EncryptionType {
+        match self.bytes.len() {
+            16 => EncryptionType::Aes128,
+            24 => EncryptionType::Aes192,
+            _ => EncryptionType::Aes256,
+        }
+    }
+
+    /// Returns the raw key
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+// Don't leak the key in debug output
+impl fmt::Debug for AesKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AesKey({})", self.encryption_type())
+    }
+}
+
+/// An AES-CTR keystream, which encrypts and decrypts alike
+pub enum AesCtr {
+    Aes128(Box<Aes128Ctr>),
+    Aes192(Box<Aes192Ctr>),
+    Aes256(Box<Aes256Ctr>),
+}
+
+impl AesCtr {
+    /// Creates a keystream with the given `key` and `iv` as the initial counter block
+    pub fn new(key: &AesKey, iv: &[u8; AES_IV_LEN]) -> AesCtr {
+        let key = key.as_bytes();
+
+        // The key length has been validated by `AesKey`
+        match key.len() {
+            16 => AesCtr::Aes128(Box::new(Aes128Ctr::new(key.into(), iv.into()))),
+            24 => AesCtr::Aes192(Box::new(Aes192Ctr::new(key.into(), iv.into()))),
+            _ => AesCtr::Aes256(Box::new(Aes256Ctr::new(key.into(), iv.into()))),
+        }
+    }
+
+    /// Encrypts or decrypts `buf` in place, continuing where the last call left off
+    pub fn apply_keystream(&mut self, buf: &mut [u8]) {
+        match self {
+            AesCtr::Aes128(cipher) => cipher.apply_keystream(buf),
+            AesCtr::Aes192(cipher) => cipher.apply_keystream(buf),
+            AesCtr::Aes256(cipher) => cipher.apply_keystream(buf),
+        }
+    }
+}
+
+/// Returns the AES IV block with the IV followed by its CRC32 checksum
+pub fn iv_block(iv: &[u8; AES_IV_LEN]) -> [u8; AES_IV_BLOCK_LEN] {
+    let mut block = [0u8; AES_IV_BLOCK_LEN];
+
+    block[..AES_IV_LEN].copy_from_slice(iv);
+    block[AES_IV_LEN..].copy_from_slice(&crc32(iv).to_le_bytes());
+    block
+}
+
+/// Parses an AES IV block, verifying its checksum
+pub fn parse_iv_block(block: &[u8; AES_IV_BLOCK_LEN]) -> Result<[u8; AES_IV_LEN], EncryptionError> {
+    let mut iv = [0u8; AES_IV_LEN];
+    let mut crc = [0u8; 4];
+
+    iv.copy_from_slice(&block[..AES_IV_LEN]);
+    crc.copy_from_slice(&block[AES_IV_LEN..]);
+
+    let expected = crc32(&iv);
+    let actual = u32::from_le_bytes(crc);
+
+    if expected != actual {
+        return Err(EncryptionError::InvalidIvChecksum(expected, actual));
+    }
+
+    Ok(iv)
+}
+
+/// Parses an AES IV from a hexadecimal string
+pub fn parse_iv(s: &str) -> Result<[u8; AES_IV_LEN], EncryptionError> {
+    let bytes = parse_hex(s)?;
+    let mut iv = [0u8; AES_IV_LEN];
+
+    if bytes.len() != AES_IV_LEN {
+        return Err(EncryptionError::InvalidIvLength(bytes.len()));
+    }
+
+    iv.copy_from_slice(&bytes);
+
+    Ok(iv)
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, EncryptionError> {
+    let s = s.trim_start_matches("0x");
+
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(EncryptionError::InvalidHex(s.to_string()));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|n| {
+            u8::from_str_radix(&s[n..n + 2], 16)
+                .map_err(|_| EncryptionError::InvalidHex(s.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_match_nist_ctr_test_vectors() {
+        // F.5.1, F.5.3 and F.5.5 CTR-AES*.Encrypt of NIST SP 800-38A
+        let keys = [
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        ];
+        let ciphertexts = [
+            "874d6191b620e3261bef6864990db6ce",
+            "1abc932417521ca24f2b0459fe7e6e0b",
+            "601ec313775789a5b7a7f504bbf3d228",
+        ];
+        let iv = parse_iv("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap();
+
+        for (key, ciphertext) in keys.iter().zip(ciphertexts.iter()) {
+            let key = AesKey::from_file_contents(key.as_bytes()).unwrap();
+            let mut buf = parse_hex("6bc1bee22e409f96e93d7e117393172a").unwrap();
+
+            AesCtr::new(&key, &iv).apply_keystream(&mut buf);
+
+            assert_eq!(buf, parse_hex(ciphertext).unwrap());
+        }
+    }
+
+    #[test]
+    fn it_should_verify_the_iv_block() {
+        let iv = parse_iv("000102030405060708090a0b0c0d0e0f").unwrap();
+        let mut block = iv_block(&iv);
+
+        assert_eq!(parse_iv_block(&block).unwrap(), iv);
+
+        block[0] ^= 0xff;
+
+        assert!(matches!(
+            parse_iv_block(&block),
+            Err(EncryptionError::InvalidIvChecksum(_, _))
+        ));
+    }
+}
@@ -0,0 +1,240 @@
+//! The factory parameters of a board
+//!
+//! The firmware reads the RF calibration, crystal capacitance, power tables and MAC addresses
+//! from the device tree blob at the start of the `factory` partition, which is built from the
+//! vendor `bl_factory_params_*.dts` files.
+
+use thiserror::Error;
+
+use super::partition::{PartitionError, PartitionTable};
+use crate::device_tree::{cells_value, DeviceTree, DeviceTreeError};
+
+/// The name of the partition with the device tree
+pub const FACTORY_PARTITION: &str = "factory";
+
+/// The crystal capacitance codes and settings
+pub const XTAL_PATH: &str = "/wifi/brd_rf/xtal";
+
+/// The country code, which is the international calling code, i.e. 86 for China
+pub const COUNTRY_CODE_PATH: &str = "/wifi/region/country_code";
+
+/// The Wi-Fi power tables, in dBm per rate
+pub const POWER_TABLE_PATHS: [&str; 3] = [
+    "/wifi/brd_rf/pwr_table_11b",
+    "/wifi/brd_rf/pwr_table_11g",
+    "/wifi/brd_rf/pwr_table_11n",
+];
+
+/// The highest crystal capacitance code
+pub const MAX_XTAL_CAPCODE: u32 = 63;
+
+/// The size of the flash sectors that are erased before writing
+const SECTOR_LEN: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum FactoryParamsError {
+    #[error("Partition table error: {}", _0)]
+    PartitionError(#[from] PartitionError),
+    #[error("The partition table has no {} entry", FACTORY_PARTITION)]
+    MissingPartition,
+    #[error("Device tree error: {}", _0)]
+    DeviceTreeError(#[from] DeviceTreeError),
+    #[error(
+        "The crystal capacitance code {} is out of range (0-{})",
+        _0,
+        MAX_XTAL_CAPCODE
+    )]
+    InvalidCapcode(u32),
+    #[error("The property {} is missing or has an unexpected length", _0)]
+    InvalidProperty(&'static str),
+    #[error(
+        "The device tree is {} bytes, but the factory partition only fits {}",
+        _0,
+        _1
+    )]
+    TooLarge(usize, u32),
+}
+
+/// Returns the flash offset and maximum length of the factory partition
+pub fn factory_partition(table: &PartitionTable) -> Result<(u32, u32), FactoryParamsError> {
+    table
+        .entries
+        .iter()
+        .find(|entry| entry.name == FACTORY_PARTITION)
+        .map(|entry| (entry.active_address(), entry.active_max_len()))
+        .ok_or(FactoryParamsError::MissingPartition)
+}
+
+/// Returns an error if the device tree `blob` doesn't fit in a partition of `max_len` bytes
+pub fn check_len(blob: &[u8], max_len: u32) -> Result<(), FactoryParamsError> {
+    if blob.len() > max_len as usize {
+        return Err(FactoryParamsError::TooLarge(blob.len(), max_len));
+    }
+
+    Ok(())
+}
+
+/// Reads the device tree in the factory partition of the whole-flash image `flash`
+pub fn read_device_tree(flash: &[u8]) -> Result<DeviceTree, FactoryParamsError> {
+    let (offset, _) = factory_partition(&PartitionTable::from_flash(flash)?)?;
+    let data = flash.get(offset as usize..).unwrap_or_default();
+
+    Ok(DeviceTree::from_bytes(data)?)
+}
+
+/// Replaces the device tree in the factory partition of the whole-flash image `flash`, erasing the
+/// sectors it's written to like the flash is
+pub fn write_device_tree(flash: &mut [u8], tree: &DeviceTree) -> Result<(), FactoryParamsError> {
+    let (offset, max_len) = factory_partition(&PartitionTable::from_flash(flash)?)?;
+    let blob = tree.to_bytes();
+
+    check_len(&blob, max_len)?;
+
+    let start = offset as usize;
+    let erased_len = blob.len().div_ceil(SECTOR_LEN) * SECTOR_LEN;
+    let end = (start + erased_len).min(flash.len());
+
+    if start + blob.len() > flash.len() {
+        return Err(FactoryParamsError::TooLarge(
+            blob.len(),
+            flash.len().saturating_sub(start) as u32,
+        ));
+    }
+
+    flash[start..end].iter_mut().for_each(|b| *b = 0xff);
+    flash[start..start + blob.len()].copy_from_slice(&blob);
+
+    Ok(())
+}
+
+/// Changes to the factory parameters
+#[derive(Debug, Clone, Default)]
+pub struct FactoryPatch {
+    /// The crystal capacitance code to use for both the input and the output
+    pub xtal_capcode: Option<u32>,
+    /// The power to set every entry of the Wi-Fi power tables to, in dBm
+    pub tx_power: Option<u32>,
+    /// The country code, which determines the allowed channels
+    pub country_code: Option<u32>,
+    /// Any other properties to set, by path
+    pub properties: Vec<(String, Vec<u8>)>,
+}
+
+impl FactoryPatch {
+    /// Returns whether the patch doesn't change anything
+    pub fn is_empty(&self) -> bool {
+        self.xtal_capcode.is_none()
+            && self.tx_power.is_none()
+            && self.country_code.is_none()
+            && self.properties.is_empty()
+    }
+
+    /// Applies the changes to `tree` and returns the paths of the properties that were set
+    pub fn apply(&self, tree: &mut DeviceTree) -> Result<Vec<String>, FactoryParamsError> {
+        let mut paths = vec![];
+
+        if let Some(capcode) = self.xtal_capcode {
+            if capcode > MAX_XTAL_CAPCODE {
+                return Err(FactoryParamsError::InvalidCapcode(capcode));
+            }
+
+            // The first two cells are the capacitance codes of the crystal input and output
+            let mut cells = cells_of(tree, XTAL_PATH).filter(|cells| cells.len() >= 2);
+            let cells = cells
+                .as_mut()
+                .ok_or(FactoryParamsError::InvalidProperty(XTAL_PATH))?;
+
+            cells[0] = capcode;
+            cells[1] = capcode;
+
+            tree.set_property(XTAL_PATH, cells_value(cells))?;
+            paths.push(XTAL_PATH.to_string());
+        }
+
+        if let Some(power) = self.tx_power {
+            for &path in &POWER_TABLE_PATHS {
+                let len = cells_of(tree, path)
+                    .ok_or(FactoryParamsError::InvalidProperty(path))?
+                    .len();
+
+                tree.set_property(path, cells_value(&vec![power; len]))?;
+                paths.push(path.to_string());
+            }
+        }
+
+        if let Some(country_code) = self.country_code {
+            tree.set_property(COUNTRY_CODE_PATH, cells_value(&[country_code]))?;
+            paths.push(COUNTRY_CODE_PATH.to_string());
+        }
+
+        for (path, value) in &self.properties {
+            tree.set_property(path, value.clone())?;
+            paths.push(path.clone());
+        }
+
+        Ok(paths)
+    }
+}
+
+fn cells_of(tree: &DeviceTree, path: &str) -> Option<Vec<u32>> {
+    tree.property(path).and_then(|property| property.cells())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    #[test]
+    fn it_should_patch_the_reference_factory_params() {
+        let mut flash = REFERENCE_FIRMWARE.to_vec();
+        let mut tree = read_device_tree(&flash).unwrap();
+        let patch = FactoryPatch {
+            xtal_capcode: Some(40),
+            tx_power: Some(16),
+            country_code: Some(1),
+            properties: vec![("/wifi/sta/ssid".to_string(), b"board\0".to_vec())],
+        };
+
+        let paths = patch.apply(&mut tree).unwrap();
+
+        assert_eq!(paths.len(), 6);
+
+        write_device_tree(&mut flash, &tree).unwrap();
+
+        let tree = read_device_tree(&flash).unwrap();
+        let cells = |path| tree.property(path).unwrap().cells().unwrap();
+
+        assert_eq!(cells(XTAL_PATH), [40, 40, 0, 60, 60]);
+        assert_eq!(cells(POWER_TABLE_PATHS[0]), [16; 4]);
+        assert_eq!(cells(POWER_TABLE_PATHS[2]), [16; 8]);
+        assert_eq!(cells(COUNTRY_CODE_PATH), [1]);
+        assert_eq!(
+            tree.property("/wifi/sta/ssid").unwrap().strings(),
+            Some(vec!["board"])
+        );
+
+        // Nothing but the factory partition changed
+        assert_eq!(flash[..0x1f8000], REFERENCE_FIRMWARE[..0x1f8000]);
+    }
+
+    #[test]
+    fn it_should_reject_invalid_patches() {
+        let mut tree = read_device_tree(REFERENCE_FIRMWARE).unwrap();
+        let patch = FactoryPatch {
+            xtal_capcode: Some(64),
+            ..FactoryPatch::default()
+        };
+
+        assert!(matches!(
+            patch.apply(&mut tree),
+            Err(FactoryParamsError::InvalidCapcode(64))
+        ));
+        assert!(matches!(
+            check_len(&tree.to_bytes(), 0x1000),
+            Err(FactoryParamsError::TooLarge(_, 0x1000))
+        ));
+    }
+}
@@ -0,0 +1,301 @@
+//! Flash configuration presets for common serial flash chips
+//!
+//! The presets are keyed by the JEDEC ID that is returned by the flash chip, which is made up of
+//! the manufacturer ID, the memory type and the capacity, i.e. `0xef4015` for a Winbond W25Q16.
+//!
+//! Chips from the same family share their command set and status register layout, so each family
+//! is described once.
+
+use std::fmt;
+
+use super::FlashConfig;
+
+/// A flash chip family with a shared command set and status register layout
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlashFamily {
+    /// Quad enable is bit 1 of status register 2, which is written with its own command (0x31)
+    Sr2Write31,
+    /// Quad enable is bit 1 of status register 2, which can only be written together with status
+    /// register 1 (0x01 with 2 bytes)
+    Sr2Write01,
+    /// Quad enable is bit 6 of status register 1 (Macronix)
+    Sr1Bit6,
+}
+
+/// A flash chip preset
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FlashPreset {
+    /// The JEDEC ID as `manufacturer << 16 | memory type << 8 | capacity`
+    pub jedec_id: u32,
+    /// The name of the chip
+    pub name: &'static str,
+    /// The family that determines the command set and status register layout
+    pub family: FlashFamily,
+}
+
+impl FlashPreset {
+    /// Returns the manufacturer ID
+    pub fn manufacturer_id(&self) -> u8 {
+        (self.jedec_id >> 16) as u8
+    }
+
+    /// Returns the manufacturer name
+    pub fn manufacturer(&self) -> &'static str {
+        manufacturer_name(self.manufacturer_id())
+    }
+
+    /// Returns the size of the chip in bytes
+    pub fn size(&self) -> u32 {
+        1 << (self.jedec_id & 0xff)
+    }
+
+    /// Returns the flash configuration for this chip
+    pub fn flash_config(&self) -> FlashConfig {
+        let base = FlashConfig {
+            manufacturer_id: self.manufacturer_id(),
+            ..winbond_base()
+        };
+
+        let mut config = match self.family {
+            FlashFamily::Sr2Write31 => base,
+            FlashFamily::Sr2Write01 => FlashConfig {
+                quad_enable_reg_write_len: 2,
+                write_reg_cmd_buffer: [0x01, 0x01, 0x00, 0x00],
+                ..base
+            },
+            FlashFamily::Sr1Bit6 => FlashConfig {
+                quad_mode_enable_reg_index: 0,
+                quad_enable_bit_pos: 6,
+                read_reg_cmd_buffer: [0x05, 0x15, 0x00, 0x00],
+                write_reg_cmd_buffer: [0x01, 0x01, 0x00, 0x00],
+                continuous_read_mode_cfg: 0xa5,
+                continuous_read_mode_exit_cfg: 0xf0,
+                enable_burst_wrap_cmd: 0xc0,
+                enable_burst_wrap_cmd_dummy_clock: 0,
+                burst_wrap_data_mode: 0,
+                burst_wrap_data: 0x02,
+                disable_burst_wrap_cmd: 0xc0,
+                disable_burst_wrap_cmd_dummy_clock: 0,
+                disable_burst_wrap_data_mode: 0,
+                disable_burst_wrap_data: 0x10,
+                ..base
+            },
+        };
+
+        config.update_crc32();
+        config
+    }
+}
+
+impl fmt::Display for FlashPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({:06x}, {} KiB)",
+            self.manufacturer(),
+            self.name,
+            self.jedec_id,
+            self.size() / 1024
+        )
+    }
+}
+
+/// The built-in flash chip presets
+pub const FLASH_PRESETS: &[FlashPreset] = &[
+    // Winbond
+    preset(0xef4014, "W25Q80", FlashFamily::Sr2Write31),
+    preset(0xef4015, "W25Q16", FlashFamily::Sr2Write31),
+    preset(0xef4016, "W25Q32", FlashFamily::Sr2Write31),
+    preset(0xef4017, "W25Q64", FlashFamily::Sr2Write31),
+    preset(0xef4018, "W25Q128", FlashFamily::Sr2Write31),
+    preset(0xef6015, "W25Q16DW", FlashFamily::Sr2Write31),
+    preset(0xef6016, "W25Q32FW", FlashFamily::Sr2Write31),
+    preset(0xef7015, "W25Q16JV-IM", FlashFamily::Sr2Write31),
+    preset(0xef7016, "W25Q32JV-IM", FlashFamily::Sr2Write31),
+    preset(0xef7017, "W25Q64JV-IM", FlashFamily::Sr2Write31),
+    // GigaDevice
+    preset(0xc84014, "GD25Q80", FlashFamily::Sr2Write31),
+    preset(0xc84015, "GD25Q16", FlashFamily::Sr2Write31),
+    preset(0xc84016, "GD25Q32", FlashFamily::Sr2Write31),
+    preset(0xc84017, "GD25Q64", FlashFamily::Sr2Write31),
+    preset(0xc86015, "GD25LQ16", FlashFamily::Sr2Write31),
+    preset(0xc86016, "GD25LQ32", FlashFamily::Sr2Write31),
+    // XMC
+    preset(0x204014, "XM25QH80", FlashFamily::Sr2Write01),
+    preset(0x204015, "XM25QH16", FlashFamily::Sr2Write01),
+    preset(0x204016, "XM25QH32", FlashFamily::Sr2Write01),
+    preset(0x204017, "XM25QH64", FlashFamily::Sr2Write01),
+    // Puya
+    preset(0x856014, "P25Q80", FlashFamily::Sr2Write01),
+    preset(0x856015, "P25Q16", FlashFamily::Sr2Write01),
+    preset(0x856016, "P25Q32", FlashFamily::Sr2Write01),
+    // Boya
+    preset(0x684014, "BY25Q80", FlashFamily::Sr2Write31),
+    preset(0x684015, "BY25Q16", FlashFamily::Sr2Write31),
+    preset(0x684016, "BY25Q32", FlashFamily::Sr2Write31),
+    // Zbit
+    preset(0x5e6015, "ZB25VQ16", FlashFamily::Sr2Write31),
+    preset(0x5e4016, "ZB25VQ32", FlashFamily::Sr2Write31),
+    // Macronix
+    preset(0xc22015, "MX25L16", FlashFamily::Sr1Bit6),
+    preset(0xc22016, "MX25L32", FlashFamily::Sr1Bit6),
+    preset(0xc22815, "MX25R16", FlashFamily::Sr1Bit6),
+];
+
+const fn preset(jedec_id: u32, name: &'static str, family: FlashFamily) -> FlashPreset {
+    FlashPreset {
+        jedec_id,
+        name,
+        family,
+    }
+}
+
+/// Returns the preset for the given `jedec_id`, if any
+pub fn find_by_jedec_id(jedec_id: u32) -> Option<&'static FlashPreset> {
+    FLASH_PRESETS.iter().find(|p| p.jedec_id == jedec_id)
+}
+
+/// Returns the preset with the given chip `name`, ignoring case
+pub fn find_by_name(name: &str) -> Option<&'static FlashPreset> {
+    FLASH_PRESETS
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// Returns the preset that matches either a chip name or a hexadecimal JEDEC ID
+pub fn find(name_or_id: &str) -> Option<&'static FlashPreset> {
+    find_by_name(name_or_id).or_else(|| {
+        let id = name_or_id.trim_start_matches("0x");
+
+        u32::from_str_radix(id, 16).ok().and_then(find_by_jedec_id)
+    })
+}
+
+/// Returns the name of the manufacturer with the given JEDEC manufacturer ID
+pub fn manufacturer_name(manufacturer_id: u8) -> &'static str {
+    match manufacturer_id {
+        0xef => "Winbond",
+        0xc8 => "GigaDevice",
+        0x20 => "XMC",
+        0x85 => "Puya",
+        0x68 => "Boya",
+        0x5e => "Zbit",
+        0xc2 => "Macronix",
+        _ => "Unknown",
+    }
+}
+
+/// The configuration the SDK uses for the Winbond W25Q series, which most other chips are
+/// compatible with
+fn winbond_base() -> FlashConfig {
+    FlashConfig {
+        io_mode: 4,
+        continuous_read_support: 1,
+        clock_delay: 1,
+        clock_invert: 1,
+        reset_enable_cmd: 0x66,
+        reset_cmd: 0x99,
+        reset_continuous_read_cmd: 0xff,
+        reset_continuous_read_cmd_size: 3,
+        jedec_id_cmd: 0x9f,
+        jedec_id_cmd_dummy_clock: 0,
+        qpi_jedec_id_cmd: 0x9f,
+        qpi_jedec_id_cmd_dummy_clock: 0,
+        sector_size: 4,
+        manufacturer_id: 0xef,
+        page_size: 256,
+        chip_erase_cmd: 0xc7,
+        sector_erase_cmd: 0x20,
+        block_erase_32k_cmd: 0x52,
+        block_erase_64k_cmd: 0xd8,
+        write_enable_cmd: 0x06,
+        page_program_cmd: 0x02,
+        qio_page_program_cmd: 0x32,
+        qio_page_program_address_mode: 0,
+        fast_read_cmd: 0x0b,
+        fast_read_cmd_dummy_clock: 1,
+        qpi_fast_read_cmd: 0x0b,
+        qpi_fast_read_cmd_dummy_clock: 1,
+        fast_read_dual_output_cmd: 0x3b,
+        fast_read_dual_output_cmd_dummy_clock: 1,
+        fast_read_dual_io_cmd: 0xbb,
+        fast_read_dual_io_cmd_dummy_clock: 0,
+        fast_read_quad_output_cmd: 0x6b,
+        fast_read_quad_output_cmd_dummy_clock: 1,
+        fast_read_quad_io_cmd: 0xeb,
+        fast_read_quad_io_cmd_dummy_clock: 2,
+        qpi_fast_read_quad_io_cmd: 0xeb,
+        qpi_fast_read_quad_io_cmd_dummy_clock: 2,
+        qpi_program_cmd: 0x02,
+        volatile_register_write_enable_cmd: 0x50,
+        write_enable_reg_index: 0,
+        quad_mode_enable_reg_index: 1,
+        busy_status_reg_index: 0,
+        write_enable_bit_pos: 1,
+        quad_enable_bit_pos: 1,
+        busy_status_bit_pos: 0,
+        write_enable_reg_write_len: 2,
+        write_enable_reg_read_len: 1,
+        quad_enable_reg_write_len: 1,
+        quad_enable_reg_read_len: 1,
+        release_power_down_cmd: 0xab,
+        busy_status_reg_read_len: 1,
+        read_reg_cmd_buffer: [0x05, 0x35, 0x00, 0x00],
+        write_reg_cmd_buffer: [0x01, 0x31, 0x00, 0x00],
+        enter_qpi_cmd: 0x38,
+        exit_qpi_cmd: 0xff,
+        continuous_read_mode_cfg: 0x20,
+        continuous_read_mode_exit_cfg: 0xff,
+        enable_burst_wrap_cmd: 0x77,
+        enable_burst_wrap_cmd_dummy_clock: 3,
+        burst_wrap_data_mode: 2,
+        burst_wrap_data: 0x40,
+        disable_burst_wrap_cmd: 0x77,
+        disable_burst_wrap_cmd_dummy_clock: 3,
+        disable_burst_wrap_data_mode: 2,
+        disable_burst_wrap_data: 0xf0,
+        sector_erase_time_4k: 300,
+        sector_erase_time_32k: 1200,
+        sector_erase_time_64k: 1200,
+        page_program_time: 5,
+        chip_erase_time: 3392,
+        power_down_delay: 3,
+        quad_enable_data: 0,
+        crc32: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bl::Firmware;
+    use std::io::Cursor;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    #[test]
+    fn it_should_match_the_reference_winbond_config() {
+        let firmware = Firmware::from_reader(Cursor::new(REFERENCE_FIRMWARE)).unwrap();
+        let config = find_by_jedec_id(0xef7015).unwrap().flash_config();
+
+        assert_eq!(&config, firmware.flash_config());
+    }
+
+    #[test]
+    fn it_should_find_presets_by_name_or_id() {
+        assert_eq!(find("w25q32").unwrap().jedec_id, 0xef4016);
+        assert_eq!(find("0xc84015").unwrap().name, "GD25Q16");
+        assert_eq!(find("204016").unwrap().name, "XM25QH32");
+        assert!(find("ffffff").is_none());
+    }
+
+    #[test]
+    fn it_should_have_unique_jedec_ids() {
+        for (n, preset) in FLASH_PRESETS.iter().enumerate() {
+            assert!(FLASH_PRESETS[n + 1..]
+                .iter()
+                .all(|p| p.jedec_id != preset.jedec_id));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! An editable representation of the boot header of a firmware image
+//!
+//! The boot header can be exported to TOML or JSON, edited, and then applied to a firmware image
+//! again, which recomputes the checksums.
+
+use std::path::Path;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use super::{BootConfig, ClockConfig, Cpu, Firmware, FlashConfig};
+
+#[derive(Error, Debug)]
+pub enum HeaderError {
+    #[error("TOML serialization error: {}", _0)]
+    TomlSerializeError(#[from] toml::ser::Error),
+    #[error("TOML deserialization error: {}", _0)]
+    TomlDeserializeError(#[from] toml::de::Error),
+    #[error("JSON error: {}", _0)]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// The format of an exported boot header
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderFormat {
+    Toml,
+    Json,
+}
+
+impl HeaderFormat {
+    /// Returns the format based on the extension of `path`, defaulting to TOML
+    pub fn from_path<P: AsRef<Path>>(path: P) -> HeaderFormat {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => HeaderFormat::Json,
+            _ => HeaderFormat::Toml,
+        }
+    }
+}
+
+/// The boot header fields of a firmware image, without the checksums
+///
+/// The scalar fields come before the tables, since TOML requires it
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BootHeader {
+    /// The CPU the firmware is for
+    pub cpu: Cpu,
+    /// The boot header revision
+    pub revision: u32,
+    /// The entry point of the firmware image
+    pub entry_point: u32,
+    /// Image RAM addr or flash offset
+    pub image_start: u32,
+    /// The number of segments, or the length of the image if it has no segments
+    pub image_segment_info: u32,
+    /// The SHA-256 hash of the image, which is only kept for images without segments, since the
+    /// hash is recomputed from the segments otherwise
+    #[serde(
+        serialize_with = "serialize_hash",
+        deserialize_with = "deserialize_hash"
+    )]
+    pub hash: [u8; 32],
+    /// Boot configuration flags
+    pub boot_config: BootConfig,
+    /// Clock configuration
+    pub clock_config: ClockConfig,
+    /// Flash configuration
+    pub flash_config: FlashConfig,
+}
+
+impl BootHeader {
+    /// Serializes the boot header in the given `format`
+    pub fn to_string(&self, format: HeaderFormat) -> Result<String, HeaderError> {
+        match format {
+            HeaderFormat::Toml => Ok(toml::to_string(self)?),
+            HeaderFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+        }
+    }
+
+    /// Deserializes a boot header in the given `format`
+    pub fn from_str(s: &str, format: HeaderFormat) -> Result<BootHeader, HeaderError> {
+        match format {
+            HeaderFormat::Toml => Ok(toml::from_str(s)?),
+            HeaderFormat::Json => Ok(serde_json::from_str(s)?),
+        }
+    }
+}
+
+impl From<&Firmware> for BootHeader {
+    fn from(fw: &Firmware) -> BootHeader {
+        BootHeader {
+            cpu: fw.cpu(),
+            revision: fw.revision(),
+            entry_point: fw.entry_point(),
+            image_start: fw.image_start(),
+            image_segment_info: fw.image_segment_info(),
+            hash: *fw.hash(),
+            boot_config: *fw.boot_config(),
+            clock_config: *fw.clock_config(),
+            flash_config: *fw.flash_config(),
+        }
+    }
+}
+
+fn serialize_hash<S: Serializer>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+
+    serializer.serialize_str(&hex)
+}
+
+fn deserialize_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    let mut hash = [0u8; 32];
+
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(de::Error::custom("expected 64 hexadecimal characters"));
+    }
+
+    for (n, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[n * 2..n * 2 + 2], 16).map_err(de::Error::custom)?;
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bl::XtalType;
+    use std::io::Cursor;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    const EFLASH_FIRMWARE: &[u8] = include_bytes!("../../test/eflash_loader_40m.bin");
+
+    #[test]
+    fn it_should_round_trip_toml_and_json() {
+        let firmware = Firmware::from_reader(Cursor::new(REFERENCE_FIRMWARE)).unwrap();
+        let header = BootHeader::from(&firmware);
+
+        for format in &[HeaderFormat::Toml, HeaderFormat::Json] {
+            let s = header.to_string(*format).unwrap();
+            let mut parsed = BootHeader::from_str(&s, *format).unwrap();
+
+            // The checksums aren't part of the exported header
+            parsed.flash_config.update_crc32();
+            parsed.clock_config.update_crc32();
+
+            assert_eq!(parsed, header);
+        }
+    }
+
+    #[test]
+    fn it_should_round_trip_unknown_clock_values() {
+        let mut image = EFLASH_FIRMWARE.to_vec();
+        image[0x68] = 9;
+
+        let mut firmware = Firmware::from_reader(Cursor::new(&image)).unwrap();
+        let mut clock_config = *firmware.clock_config();
+        clock_config.update_crc32();
+
+        let header = BootHeader::from(&firmware);
+
+        for format in &[HeaderFormat::Toml, HeaderFormat::Json] {
+            let s = header.to_string(*format).unwrap();
+            let parsed = BootHeader::from_str(&s, *format).unwrap();
+
+            assert_eq!(parsed.clock_config.xtal_type, XtalType::Unknown(9));
+
+            firmware.set_boot_header(&parsed);
+
+            assert_eq!(firmware.clock_config(), &clock_config);
+        }
+    }
+
+    #[test]
+    fn it_should_recompute_checksums_on_import() {
+        let mut firmware = Firmware::from_reader(Cursor::new(EFLASH_FIRMWARE)).unwrap();
+        let s = BootHeader::from(&firmware)
+            .to_string(HeaderFormat::Toml)
+            .unwrap()
+            .replace("entry_point = 0", "entry_point = 570490880");
+
+        firmware.set_boot_header(&BootHeader::from_str(&s, HeaderFormat::Toml).unwrap());
+
+        let mut buf = vec![];
+        firmware.write_to(&mut buf).unwrap();
+
+        assert_eq!(firmware.entry_point(), 0x2201_0000);
+        assert_eq!(firmware.crc32(), crate::bl::crc32(&buf[0x0..0xac]));
+        assert_eq!(firmware.hash(), &firmware.segments_hash());
+    }
+}
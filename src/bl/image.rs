@@ -0,0 +1,570 @@
+//! Building firmware images from ELF files, Intel HEX and S-record files and raw binaries
+//!
+//! Applications linked to XIP flash are built as images without segments, with their code at
+//! `FLASH_IMAGE_START`, which the BootROM maps to the start of XIP flash. Applications linked to
+//! RAM are built as segments that the BootROM loads into RAM.
+
+use std::io::{self, Cursor};
+use std::path::Path;
+
+use thiserror::Error;
+
+use super::conf::{self, Conf, ConfError};
+use super::encryption::{AesKey, AES_IV_LEN};
+use super::flash_presets::{self, FlashPreset};
+use super::memory_map::{self, MemoryMapError};
+use super::{
+    BootConfig, BuilderError, ClockConfig, EncryptionType, Firmware, FlashClockType, FlashConfig,
+    ParseError, PllClock, SignatureType, XtalType,
+};
+use crate::elf_parser::{self, Class, ElfParser, FileType, ProgType, ProgramHeader};
+use crate::hex_file::{Block, HexError, HexFile};
+
+/// The flash offset of the code of an image that executes in place
+pub const FLASH_IMAGE_START: u32 = 0x1000;
+
+/// The flash chip used when none is given
+pub const DEFAULT_FLASH: &str = "ef4015";
+
+/// The file extensions of Intel HEX and S-record files
+const HEX_FILE_EXTENSIONS: &[&str] = &["hex", "ihex", "ihx", "srec", "s19", "s28", "s37", "mot"];
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("I/O error: {}", _0)]
+    IoError(#[from] io::Error),
+    #[error("Failed to parse ELF file: {}", _0)]
+    ElfError(#[from] elf_parser::ParseError),
+    #[error(
+        "It's an {} file, which is for {}, but BL602 images are built from {} files, which are for {}",
+        _0,
+        _0.targets(),
+        Class::Elf32,
+        Class::Elf32.targets()
+    )]
+    UnsupportedElfClass(Class),
+    #[error(
+        "It's a {}, but firmware images are built from executable files - it needs to be linked first",
+        _0
+    )]
+    NotExecutable(FileType),
+    #[error("Failed to read the segment at {:#010x}: {}", _0, _1)]
+    SegmentError(u64, elf_parser::ParseError),
+    #[error("Failed to parse records: {}", _0)]
+    HexError(#[from] HexError),
+    #[error("It's a raw binary without addresses, so it needs a base address")]
+    MissingBase,
+    #[error(
+        "The segments don't fit the BL602 memory map: {}",
+        _0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    MemoryMapError(Vec<MemoryMapError>),
+    #[error(
+        "It has segments both in XIP flash and in RAM, but an image either executes in place or is loaded into RAM"
+    )]
+    MixedSegments,
+    #[error("Configuration file error: {}", _0)]
+    ConfError(#[from] ConfError),
+    #[error(
+        "The image executes in place, which needs no_segment = 1 and cache_enable = 1 in the configuration file"
+    )]
+    ConfNotXip,
+    #[error("The image is loaded into RAM, which needs no_segment = 0 in the configuration file")]
+    ConfXip,
+    #[error(
+        "The configuration file sets the encryption to {}, but the image is encrypted with {}",
+        _0,
+        _1
+    )]
+    ConfEncryptionMismatch(EncryptionType, EncryptionType),
+    #[error("The configuration file enables signing, so sign the image after building it instead")]
+    ConfSignature,
+    #[error("No flash preset named {}", _0)]
+    UnknownFlash(String),
+    #[error("No flash preset for JEDEC ID {:06x}", _0)]
+    UnknownJedecId(u32),
+    #[error("Failed to build firmware image: {}", _0)]
+    BuilderError(#[from] BuilderError),
+    #[error("Failed to write firmware image: {}", _0)]
+    ParseError(#[from] ParseError),
+    #[error(
+        "The data at {:#010x} is linked for execute-in-place, which maps to the image in a partition rather than a flash offset, so build an image and write that instead",
+        _0
+    )]
+    XipFlashAddress(u32),
+    #[error("The data at {:#010x} is in {}, not in flash", _0, _1)]
+    NotInFlash(u32, &'static str),
+    #[error("The data at {:#010x} is outside of flash", _0)]
+    OutsideFlash(u32),
+}
+
+/// The flash and clock settings of a board, which go into the boot header
+#[derive(Debug, Clone, Default)]
+pub struct Board {
+    /// The vendor configuration file, with the flash configuration and possibly the clock
+    /// configuration and boot flags
+    pub conf: Option<Conf>,
+    /// The flash chip, which is used when there's no configuration file
+    pub flash: Option<&'static FlashPreset>,
+    /// The crystal, which takes precedence over the configuration file
+    pub xtal: Option<XtalType>,
+    /// The system clock, which takes precedence over the configuration file
+    pub pll: Option<PllClock>,
+    /// The flash clock, which takes precedence over the configuration file
+    pub flash_clock: Option<FlashClockType>,
+}
+
+impl Board {
+    /// Returns the flash configuration of the configuration file, or of the flash chip, which is
+    /// `DEFAULT_FLASH` if none is given
+    pub fn flash_config(&self) -> Result<FlashConfig, ImageError> {
+        if let Some(ref conf) = self.conf {
+            return Ok(conf::read_flash_config(conf.boot_header_section()?)?);
+        }
+
+        let preset = match self.flash {
+            Some(preset) => preset,
+            None => flash_preset(DEFAULT_FLASH)?,
+        };
+
+        Ok(preset.flash_config())
+    }
+
+    /// Returns the clock configuration of the configuration file if it has one, otherwise
+    /// `clock_config`, with the clocks of the board on top
+    pub fn clock_config(&self, clock_config: ClockConfig) -> Result<ClockConfig, ImageError> {
+        let mut clock_config = match self.conf {
+            Some(ref conf) => {
+                let section = conf.boot_header_section()?;
+
+                if section.contains("xtal_type") {
+                    conf::read_clock_config(section)?
+                } else {
+                    clock_config
+                }
+            }
+            None => clock_config,
+        };
+
+        if let Some(xtal) = self.xtal {
+            clock_config.xtal_type = xtal;
+        }
+
+        if let Some(pll) = self.pll {
+            clock_config.pll_clock = pll;
+        }
+
+        if let Some(flash_clock) = self.flash_clock {
+            clock_config.flash_clock_type = flash_clock;
+        }
+
+        Ok(clock_config)
+    }
+
+    /// Returns the boot flags of the configuration file, if there is one
+    ///
+    /// The flags that describe the image have to agree with the image that is built, which
+    /// executes in place if `xip` is set and is encrypted with `encryption`.
+    pub fn boot_config(
+        &self,
+        xip: bool,
+        encryption: EncryptionType,
+    ) -> Result<Option<BootConfig>, ImageError> {
+        let conf = match self.conf {
+            Some(ref conf) => conf,
+            None => return Ok(None),
+        };
+        let boot_config = conf::read_boot_config(conf.boot_header_section()?)?;
+
+        if xip && !(boot_config.no_segment && boot_config.cache_enable) {
+            return Err(ImageError::ConfNotXip);
+        }
+
+        if !xip && boot_config.no_segment {
+            return Err(ImageError::ConfXip);
+        }
+
+        if boot_config.encryption != encryption {
+            return Err(ImageError::ConfEncryptionMismatch(
+                boot_config.encryption,
+                encryption,
+            ));
+        }
+
+        if boot_config.signature != SignatureType::None {
+            return Err(ImageError::ConfSignature);
+        }
+
+        Ok(Some(boot_config))
+    }
+}
+
+/// Returns the flash preset with the given name or hexadecimal JEDEC ID
+pub fn flash_preset(name: &str) -> Result<&'static FlashPreset, ImageError> {
+    flash_presets::find(name).ok_or_else(|| ImageError::UnknownFlash(name.to_string()))
+}
+
+/// Returns the flash preset of the flash chip with the given `jedec_id`
+pub fn flash_preset_by_jedec_id(jedec_id: u32) -> Result<&'static FlashPreset, ImageError> {
+    flash_presets::find_by_jedec_id(jedec_id).ok_or(ImageError::UnknownJedecId(jedec_id))
+}
+
+/// A firmware image built from the blocks of an input file
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// The firmware, with its segments unless it executes in place
+    pub firmware: Firmware,
+    /// The data of an image that executes in place, which is stored at `image_start`
+    pub xip_data: Option<Vec<u8>>,
+}
+
+impl Image {
+    /// Returns the image as it's stored in flash
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ImageError> {
+        let mut buf = vec![];
+
+        match self.xip_data {
+            Some(ref data) => self.firmware.write_flash_image_to(data, &mut buf)?,
+            None => self.firmware.write_image_to(&mut buf)?,
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Returns whether the file at `path` is an Intel HEX or S-record file, going by its extension
+pub fn is_hex_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| HEX_FILE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Reads the blocks of data and the entry point of the ELF file, Intel HEX file, S-record file or
+/// raw binary at `path`
+///
+/// ELF files are told apart by their contents and the others by their extension. Raw binaries
+/// have no addresses, so they're placed at `base`.
+pub fn read_input_file(path: &Path, base: Option<u32>) -> Result<HexFile, ImageError> {
+    let data = std::fs::read(path)?;
+
+    if data.starts_with(b"\x7fELF") {
+        return read_elf(data);
+    }
+
+    if is_hex_file(path) {
+        return Ok(HexFile::from_path(path)?);
+    }
+
+    let addr = base.ok_or(ImageError::MissingBase)?;
+
+    Ok(HexFile {
+        blocks: vec![Block { addr, data }],
+        entry: None,
+    })
+}
+
+/// Reads the loadable segments and the entry point of the ELF file `data`
+fn read_elf(data: Vec<u8>) -> Result<HexFile, ImageError> {
+    let parser = ElfParser::parse(Cursor::new(data))?;
+
+    check_bl602_elf(parser.header())?;
+
+    let mut blocks = vec![];
+
+    // Applications that execute in place are stored in flash as they're laid out there, so their
+    // segments are placed at their load addresses, and the zero-filled parts are left to the
+    // startup code
+    let xip = parser.program_headers().iter().any(is_flash_segment);
+
+    // Add each loadable segment, including the zero-filled part that isn't in the file unless
+    // it's stored in flash - the addresses of an ELF32 file always fit in 32 bits
+    for segment in parser.program_headers() {
+        if segment.typ() != ProgType::Load || segment.mem_size() == 0 {
+            continue;
+        }
+
+        if xip && segment.file_size() == 0 {
+            continue;
+        }
+
+        let mut data = parser
+            .read_segment_data(segment)
+            .map_err(|err| ImageError::SegmentError(segment.virt_addr(), err))?;
+
+        let addr = if xip {
+            data.truncate(segment.file_size() as usize);
+            segment.phys_addr()
+        } else {
+            segment.virt_addr()
+        };
+
+        blocks.push(Block {
+            addr: addr as u32,
+            data,
+        });
+    }
+
+    Ok(HexFile {
+        blocks,
+        entry: Some(parser.header().entry_addr as u32),
+    })
+}
+
+/// Asserts that an ELF file is something the BL602 BootROM can load, which is a linked ELF32
+/// executable file
+fn check_bl602_elf(header: &elf_parser::Header) -> Result<(), ImageError> {
+    if header.class != Class::Elf32 {
+        return Err(ImageError::UnsupportedElfClass(header.class));
+    }
+
+    if header.file_type != FileType::Executable {
+        return Err(ImageError::NotExecutable(header.file_type));
+    }
+
+    Ok(())
+}
+
+/// Returns whether the ELF `segment` is loaded from XIP flash
+fn is_flash_segment(segment: &ProgramHeader) -> bool {
+    segment.typ() == ProgType::Load
+        && segment.file_size() > 0
+        && memory_map::is_flash_addr(segment.phys_addr() as u32)
+}
+
+/// Asserts that every block fits the memory map where it wants to go, using `validate` to
+/// validate their ranges
+pub fn validate_blocks<F>(blocks: &[Block], validate: F) -> Result<(), ImageError>
+where
+    F: FnOnce(&[(u32, u32)]) -> Vec<MemoryMapError>,
+{
+    let ranges: Vec<(u32, u32)> = blocks
+        .iter()
+        .map(|block| (block.addr, block.data.len() as u32))
+        .collect();
+    let errors = validate(&ranges);
+
+    if !errors.is_empty() {
+        return Err(ImageError::MemoryMapError(errors));
+    }
+
+    Ok(())
+}
+
+/// Builds a firmware image from the blocks and entry point of `input` with the settings of
+/// `board`, and encrypts it with the given AES key and IV
+///
+/// Inputs with blocks in XIP flash are built as images without segments that execute in place,
+/// where all of the blocks have to be in XIP flash.
+pub fn build_image(
+    input: &HexFile,
+    board: &Board,
+    aes: Option<(AesKey, [u8; AES_IV_LEN])>,
+) -> Result<Image, ImageError> {
+    validate_blocks(&input.blocks, memory_map::validate_segments)?;
+
+    let flash_blocks = input
+        .blocks
+        .iter()
+        .filter(|block| memory_map::is_flash_addr(block.addr))
+        .count();
+    let xip = flash_blocks > 0;
+
+    if xip && flash_blocks != input.blocks.len() {
+        return Err(ImageError::MixedSegments);
+    }
+
+    let encryption = aes
+        .as_ref()
+        .map_or(EncryptionType::None, |(key, _)| key.encryption_type());
+    let mut builder = Firmware::builder();
+
+    // The boot flags of the configuration file go first, since the other settings override them
+    if let Some(boot_config) = board.boot_config(xip, encryption)? {
+        builder.boot_config(boot_config);
+    }
+
+    builder
+        .flash_config(board.flash_config()?)
+        .clock_config(board.clock_config(ClockConfig::default())?)
+        .entry_point(entry_point(input));
+
+    if let Some((key, iv)) = aes {
+        builder.aes_key(key).aes_iv(iv);
+    }
+
+    if xip {
+        let data = xip_image_data(&input.blocks);
+
+        builder
+            .no_segment(true)
+            .xip(true)
+            .image_start(FLASH_IMAGE_START)
+            .image_len(data.len() as u32);
+
+        let mut firmware = builder.build()?;
+        firmware.set_image_data(&data);
+
+        Ok(Image {
+            firmware,
+            xip_data: Some(data),
+        })
+    } else {
+        for block in &input.blocks {
+            builder.add_segment(block.addr, block.data.clone());
+        }
+
+        Ok(Image {
+            firmware: builder.build()?,
+            xip_data: None,
+        })
+    }
+}
+
+/// Builds a firmware image that the BootROM loads into RAM from the blocks and entry point of
+/// `input`
+///
+/// The BootROM doesn't touch the flash when loading into RAM, but the boot header still needs a
+/// `flash_config`.
+pub fn build_ram_image(input: &HexFile, flash_config: FlashConfig) -> Result<Firmware, ImageError> {
+    validate_blocks(&input.blocks, memory_map::validate_ram_segments)?;
+
+    let mut builder = Firmware::builder();
+
+    builder
+        .flash_config(flash_config)
+        .entry_point(entry_point(input));
+
+    for block in &input.blocks {
+        builder.add_segment(block.addr, block.data.clone());
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Returns the entry point of `input`, or the address of its first block if it has none
+fn entry_point(input: &HexFile) -> u32 {
+    input
+        .entry
+        .or_else(|| input.blocks.first().map(|block| block.addr))
+        .unwrap_or_default()
+}
+
+/// Returns the data of an image that executes in place, which is the XIP `blocks` laid out from
+/// the start of XIP flash, where the gaps are erased flash
+fn xip_image_data(blocks: &[Block]) -> Vec<u8> {
+    let start = memory_map::flash_region().start;
+    let end = blocks.iter().map(Block::end).max().unwrap_or(start as u64);
+    let mut data = vec![0xffu8; (end - start as u64) as usize];
+
+    for block in blocks {
+        let offset = (block.addr - start) as usize;
+
+        data[offset..offset + block.data.len()].copy_from_slice(&block.data);
+    }
+
+    data
+}
+
+/// Returns `addr` as a flash offset, which is what the records of files written to flash have to
+/// be
+///
+/// Addresses in XIP flash are rejected, since they're mapped to the image in a partition rather
+/// than to the start of the flash, so writing them as offsets would overwrite boot2 and the
+/// partition tables.
+pub fn flash_offset(addr: u32) -> Result<u32, ImageError> {
+    match memory_map::region_of(addr) {
+        Some(region) if region.kind == memory_map::RegionKind::Flash => {
+            Err(ImageError::XipFlashAddress(addr))
+        }
+        Some(region) => Err(ImageError::NotInFlash(addr, region.name)),
+        None if addr < memory_map::flash_region().len => Ok(addr),
+        None => Err(ImageError::OutsideFlash(addr)),
+    }
+}
+
+/// Returns the blocks of `hex_file` at the flash offsets of their addresses
+pub fn flash_blocks(hex_file: HexFile) -> Result<Vec<Block>, ImageError> {
+    hex_file
+        .blocks
+        .into_iter()
+        .map(|block| {
+            Ok(Block {
+                addr: flash_offset(block.addr)?,
+                data: block.data,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_ELF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test/elf/bl602_minimal.elf");
+    const RAM_ELF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test/elf/bl602_ram.elf");
+    const REFERENCE_CONF: &str = include_str!("../../test/efuse_bootheader_cfg.conf");
+
+    #[test]
+    fn it_should_build_an_xip_image_from_elf() {
+        let input = read_input_file(Path::new(MINIMAL_ELF), None).unwrap();
+        let image = build_image(&input, &Board::default(), None)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        let fw = Firmware::from_reader(Cursor::new(&image)).unwrap();
+        let data = &image[FLASH_IMAGE_START as usize..];
+
+        assert!(fw.boot_config().no_segment);
+        assert!(fw.boot_config().cache_enable);
+        assert_eq!(fw.entry_point(), 0x2300_0000);
+        assert_eq!(fw.image_start(), FLASH_IMAGE_START);
+        assert_eq!(fw.hash(), &fw.image_hash(data));
+
+        // .text, .rodata and the initial value of .data are stored at their load addresses
+        assert_eq!(fw.image_segment_info(), 0x58);
+        assert_eq!(data.len(), 0x58);
+        assert_eq!(&data[0x44..0x52], b"Hello, BL602!\0");
+        assert_eq!(&data[0x54..0x58], &0x1337u32.to_le_bytes());
+    }
+
+    #[test]
+    fn it_should_use_the_boot_flags_of_the_conf() {
+        let board = Board {
+            conf: Some(REFERENCE_CONF.parse().unwrap()),
+            ..Board::default()
+        };
+        let input = read_input_file(Path::new(MINIMAL_ELF), None).unwrap();
+        let image = build_image(&input, &board, None).unwrap();
+        let boot_config = image.firmware.boot_config();
+
+        assert!(boot_config.no_segment);
+        assert!(boot_config.cache_enable);
+        assert!(boot_config.crc_ignore);
+        assert!(boot_config.hash_ignore);
+        assert_eq!(boot_config.cache_way_disable, 0x03);
+
+        // The configuration file is for images without segments, which an image loaded into RAM
+        // can't be
+        let input = read_input_file(Path::new(RAM_ELF), None).unwrap();
+
+        assert!(matches!(
+            build_image(&input, &board, None),
+            Err(ImageError::ConfXip)
+        ));
+    }
+
+    #[test]
+    fn it_should_only_accept_flash_offsets() {
+        assert_eq!(flash_offset(0x1000).unwrap(), 0x1000);
+        assert!(matches!(
+            flash_offset(0x2300_1000),
+            Err(ImageError::XipFlashAddress(0x2300_1000))
+        ));
+        assert!(matches!(
+            flash_offset(0x4201_4000),
+            Err(ImageError::NotInFlash(0x4201_4000, "DTCM"))
+        ));
+    }
+}
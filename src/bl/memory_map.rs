@@ -0,0 +1,288 @@
+//! The memory map of the BL602, used to validate where image segments are loaded or stored
+//!
+//! The RAM is mapped twice, at `0x2200_0000` through the cache and at `0x4200_0000` without it,
+//! so the regions are described by their cached address and uncached addresses are translated.
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// The offset from the cached to the uncached address of the RAM
+const UNCACHED_OFFSET: u32 = 0x2000_0000;
+
+/// The kind of memory in a region
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegionKind {
+    /// The BootROM
+    Rom,
+    /// RAM that the BootROM can load segments into
+    Ram,
+    /// RAM that keeps its contents while in hibernation
+    RetentionRam,
+    /// External flash mapped for execute-in-place
+    Flash,
+}
+
+/// A region of the memory map
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MemoryRegion {
+    /// The name of the region
+    pub name: &'static str,
+    /// The kind of memory in the region
+    pub kind: RegionKind,
+    /// The cached address of the region
+    pub start: u32,
+    /// The length of the region, in bytes
+    pub len: u32,
+}
+
+impl MemoryRegion {
+    /// Returns the address right after the end of the region
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.len as u64
+    }
+
+    /// Returns whether the region contains the cached address `addr`
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && (addr as u64) < self.end()
+    }
+
+    /// Returns whether the region is also mapped without the cache
+    pub fn has_uncached_alias(&self) -> bool {
+        self.kind == RegionKind::Ram
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:#010x}..{:#010x})",
+            self.name,
+            self.start,
+            self.end()
+        )
+    }
+}
+
+/// The regions of the BL602 memory map, in the order of their addresses
+pub const BL602_MEMORY_MAP: &[MemoryRegion] = &[
+    MemoryRegion {
+        name: "ROM",
+        kind: RegionKind::Rom,
+        start: 0x2100_0000,
+        len: 128 * 1024,
+    },
+    MemoryRegion {
+        name: "ITCM",
+        kind: RegionKind::Ram,
+        start: 0x2200_8000,
+        len: 48 * 1024,
+    },
+    MemoryRegion {
+        name: "DTCM",
+        kind: RegionKind::Ram,
+        start: 0x2201_4000,
+        len: 48 * 1024,
+    },
+    MemoryRegion {
+        name: "OCRAM",
+        kind: RegionKind::Ram,
+        start: 0x2202_0000,
+        len: 64 * 1024,
+    },
+    MemoryRegion {
+        name: "WRAM",
+        kind: RegionKind::Ram,
+        start: 0x2203_0000,
+        len: 112 * 1024,
+    },
+    MemoryRegion {
+        name: "XIP flash",
+        kind: RegionKind::Flash,
+        start: 0x2300_0000,
+        len: 16 * 1024 * 1024,
+    },
+    MemoryRegion {
+        name: "Retention RAM",
+        kind: RegionKind::RetentionRam,
+        start: 0x4001_0000,
+        len: 4 * 1024,
+    },
+];
+
+/// The start of the RAM the BootROM uses for its data and stack while loading an image
+///
+/// This is the start of the ITCM, which the SDK linker scripts leave out of the application RAM
+pub const BOOTROM_RESERVED_START: u32 = 0x2200_8000;
+
+/// The length of the RAM the BootROM uses while loading an image
+pub const BOOTROM_RESERVED_LEN: u32 = 16 * 1024;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum MemoryMapError {
+    #[error(
+        "The segment at {:#010x} ({} bytes) is outside of the memory map",
+        _0,
+        _1
+    )]
+    OutsideMemoryMap(u32, u32),
+    #[error(
+        "The segment at {:#010x} is in {}, which the BootROM can't load segments into",
+        _0,
+        _1
+    )]
+    NotLoadable(u32, &'static str),
+    #[error(
+        "The segment at {:#010x} overwrites the RAM the BootROM uses while loading ({:#010x}..{:#010x})",
+        _0,
+        BOOTROM_RESERVED_START,
+        BOOTROM_RESERVED_START + BOOTROM_RESERVED_LEN
+    )]
+    BootRomReserved(u32),
+    #[error("The segments at {:#010x} and {:#010x} overlap", _0, _1)]
+    Overlap(u32, u32),
+}
+
+/// Returns the cached address of `addr` if it's an uncached RAM address, otherwise `addr`
+pub fn cached_addr(addr: u32) -> u32 {
+    let cached = addr.wrapping_sub(UNCACHED_OFFSET);
+
+    match find_region(cached) {
+        Some(region) if addr >= UNCACHED_OFFSET && region.has_uncached_alias() => cached,
+        _ => addr,
+    }
+}
+
+/// Returns the region of the flash that's mapped for execute-in-place, which is as large as the
+/// flash can be
+pub fn flash_region() -> &'static MemoryRegion {
+    BL602_MEMORY_MAP
+        .iter()
+        .find(|region| region.kind == RegionKind::Flash)
+        .unwrap()
+}
+
+/// Returns whether `addr` is in XIP flash
+pub fn is_flash_addr(addr: u32) -> bool {
+    matches!(region_of(addr), Some(region) if region.kind == RegionKind::Flash)
+}
+
+/// Returns the region that contains `addr`, which may be cached or uncached
+pub fn region_of(addr: u32) -> Option<&'static MemoryRegion> {
+    find_region(cached_addr(addr))
+}
+
+fn find_region(addr: u32) -> Option<&'static MemoryRegion> {
+    BL602_MEMORY_MAP.iter().find(|region| region.contains(addr))
+}
+
+/// Validates that the segments at the given `(address, length)` pairs can be put in an image, and
+/// returns every problem that was found
+///
+/// Segments in XIP flash are accepted, since they're stored in flash as they are. A segment may
+/// span adjacent RAM regions, like the ITCM and the DTCM
+pub fn validate_segments(segments: &[(u32, u32)]) -> Vec<MemoryMapError> {
+    validate(segments, true)
+}
+
+/// Validates that the BootROM can load segments into RAM at the given `(address, length)` pairs,
+/// like `validate_segments` but without accepting segments in XIP flash
+pub fn validate_ram_segments(segments: &[(u32, u32)]) -> Vec<MemoryMapError> {
+    validate(segments, false)
+}
+
+fn validate(segments: &[(u32, u32)], allow_flash: bool) -> Vec<MemoryMapError> {
+    let mut errors = vec![];
+    let mut ranges: Vec<(u64, u64, u32)> = vec![];
+
+    for &(addr, len) in segments {
+        let start = cached_addr(addr) as u64;
+        let end = start + len as u64;
+
+        // Walk through the regions the segment spans
+        let mut cur = start;
+
+        while cur < end {
+            let region = match find_region(cur as u32) {
+                Some(region) => region,
+                None => {
+                    errors.push(MemoryMapError::OutsideMemoryMap(addr, len));
+                    break;
+                }
+            };
+
+            if region.kind == RegionKind::Rom || (region.kind == RegionKind::Flash && !allow_flash)
+            {
+                errors.push(MemoryMapError::NotLoadable(addr, region.name));
+                break;
+            }
+
+            cur = region.end();
+        }
+
+        // Assert that the segment doesn't overwrite the RAM used by the BootROM
+        let reserved_start = BOOTROM_RESERVED_START as u64;
+        let reserved_end = reserved_start + BOOTROM_RESERVED_LEN as u64;
+
+        if start < reserved_end && reserved_start < end {
+            errors.push(MemoryMapError::BootRomReserved(addr));
+        }
+
+        ranges.push((start, end, addr));
+    }
+
+    // Assert that none of the segments overlap, including through the uncached aliases
+    ranges.sort_unstable();
+
+    for pair in ranges.windows(2) {
+        if pair[1].0 < pair[0].1 {
+            errors.push(MemoryMapError::Overlap(pair[0].2, pair[1].2));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_accept_the_eflash_loader() {
+        // The eflash loader spans the end of the ITCM and the start of the DTCM
+        assert!(validate_segments(&[(0x2201_0000, 21872)]).is_empty());
+        assert_eq!(region_of(0x4201_4000).unwrap().name, "DTCM");
+    }
+
+    #[test]
+    fn it_should_accept_xip_flash_segments() {
+        assert!(validate_segments(&[(0x2300_0000, 0x44), (0x2300_0054, 0x4)]).is_empty());
+        assert_eq!(
+            validate_ram_segments(&[(0x2300_0000, 0x44)]),
+            [MemoryMapError::NotLoadable(0x2300_0000, "XIP flash")]
+        );
+    }
+
+    #[test]
+    fn it_should_report_invalid_segments() {
+        let errors = validate_segments(&[
+            (0x2300_0000, 0x44),
+            (0x2100_0000, 0x10),
+            (0x2200_8000, 0x100),
+            (0x5000_0000, 0x10),
+            (0x4202_0000, 0x10),
+            (0x2202_0008, 0x10),
+        ]);
+
+        assert_eq!(
+            errors,
+            [
+                MemoryMapError::NotLoadable(0x2100_0000, "ROM"),
+                MemoryMapError::BootRomReserved(0x2200_8000),
+                MemoryMapError::OutsideMemoryMap(0x5000_0000, 0x10),
+                MemoryMapError::Overlap(0x4202_0000, 0x2202_0008),
+            ]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! OTA update images in the `BL60X_OTA_Ver1.0` format
+//!
+//! An OTA image is a 512 byte header followed by the firmware partition image, which may be
+//! compressed with xz. The header has the type of the payload, its length, the hardware and
+//! software versions and the SHA-256 hash of the payload, which the OTA client verifies before
+//! switching partitions.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use xz2::stream::{Check, Filters, LzmaOptions, Stream};
+
+/// The length of the OTA header
+pub const OTA_HEADER_LEN: usize = 512;
+
+/// The magic value at the start of the OTA header
+pub const OTA_MAGIC: &[u8; 16] = b"BL60X_OTA_Ver1.0";
+
+/// The maximum length of the hardware and software version strings
+pub const MAX_VERSION_LEN: usize = 16;
+
+/// The dictionary size to compress with, which is what the xz decoder of the OTA client expects
+const XZ_DICT_SIZE: u32 = 32 * 1024;
+
+#[derive(Error, Debug)]
+pub enum OtaError {
+    #[error("The file is too short to be an OTA image ({} bytes)", _0)]
+    MissingHeader(usize),
+    #[error("The OTA header doesn't start with BL60X_OTA_Ver1.0")]
+    InvalidMagic,
+    #[error("Unknown OTA payload type {:?}", _0)]
+    InvalidType(String),
+    #[error("The version '{}' is longer than {} bytes", _0, MAX_VERSION_LEN)]
+    VersionTooLong(String),
+    #[error("The payload is {} bytes, but the header says it's {} bytes", _1, _0)]
+    LengthMismatch(u32, usize),
+    #[error("The SHA-256 hash of the payload doesn't match the header")]
+    HashMismatch,
+    #[error("Compression error: {}", _0)]
+    CompressionError(#[from] io::Error),
+}
+
+/// The type of the payload of an OTA image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OtaType {
+    /// The firmware image as is
+    Raw,
+    /// The firmware image compressed with xz
+    Xz,
+}
+
+impl OtaType {
+    /// Returns the type as it's stored in the header
+    pub fn as_bytes(&self) -> &'static [u8; 4] {
+        match self {
+            OtaType::Raw => b"RAW ",
+            OtaType::Xz => b"XZ  ",
+        }
+    }
+}
+
+impl fmt::Display for OtaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaType::Raw => write!(f, "RAW"),
+            OtaType::Xz => write!(f, "XZ"),
+        }
+    }
+}
+
+/// The header of an OTA image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OtaHeader {
+    /// The type of the payload
+    pub typ: OtaType,
+    /// The length of the payload, in bytes
+    pub len: u32,
+    /// The hardware version, which is usually empty
+    pub hardware_version: String,
+    /// The software version
+    pub software_version: String,
+    /// The SHA-256 hash of the payload
+    pub sha256: [u8; 32],
+}
+
+impl OtaHeader {
+    /// Parses the OTA header at the start of `data`
+    pub fn from_bytes(data: &[u8]) -> Result<OtaHeader, OtaError> {
+        if data.len() < OTA_HEADER_LEN {
+            return Err(OtaError::MissingHeader(data.len()));
+        }
+
+        if &data[0x00..0x10] != OTA_MAGIC {
+            return Err(OtaError::InvalidMagic);
+        }
+
+        let typ = match &data[0x10..0x14] {
+            b"RAW " => OtaType::Raw,
+            b"XZ  " => OtaType::Xz,
+            typ => return Err(OtaError::InvalidType(String::from_utf8_lossy(typ).into())),
+        };
+
+        let mut len = [0u8; 4];
+        let mut sha256 = [0u8; 32];
+
+        len.copy_from_slice(&data[0x14..0x18]);
+        sha256.copy_from_slice(&data[0x40..0x60]);
+
+        Ok(OtaHeader {
+            typ,
+            len: u32::from_le_bytes(len),
+            hardware_version: version_from_bytes(&data[0x20..0x30]),
+            software_version: version_from_bytes(&data[0x30..0x40]),
+            sha256,
+        })
+    }
+
+    /// Returns the 512 byte OTA header
+    pub fn to_bytes(&self) -> Result<[u8; OTA_HEADER_LEN], OtaError> {
+        let mut buf = [0u8; OTA_HEADER_LEN];
+
+        buf[0x00..0x10].copy_from_slice(OTA_MAGIC);
+        buf[0x10..0x14].copy_from_slice(self.typ.as_bytes());
+        buf[0x14..0x18].copy_from_slice(&self.len.to_le_bytes());
+        buf[0x20..0x30].copy_from_slice(&version_to_bytes(&self.hardware_version)?);
+        buf[0x30..0x40].copy_from_slice(&version_to_bytes(&self.software_version)?);
+        buf[0x40..0x60].copy_from_slice(&self.sha256);
+
+        Ok(buf)
+    }
+}
+
+/// An OTA image, which is the header and the payload
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OtaImage {
+    /// The OTA header
+    pub header: OtaHeader,
+    /// The payload, which is the firmware image, compressed if the type is `OtaType::Xz`
+    pub payload: Vec<u8>,
+}
+
+impl OtaImage {
+    /// Creates an OTA image of the firmware partition image `firmware` with the software
+    /// `version`, compressing the firmware with xz if `compress` is set
+    pub fn new(firmware: &[u8], version: &str, compress: bool) -> Result<OtaImage, OtaError> {
+        // Assert that the version fits before compressing
+        version_to_bytes(version)?;
+
+        let (typ, payload) = if compress {
+            (OtaType::Xz, xz_compress(firmware)?)
+        } else {
+            (OtaType::Raw, firmware.to_vec())
+        };
+
+        Ok(OtaImage {
+            header: OtaHeader {
+                typ,
+                len: payload.len() as u32,
+                hardware_version: String::new(),
+                software_version: version.to_string(),
+                sha256: Sha256::digest(&payload).into(),
+            },
+            payload,
+        })
+    }
+
+    /// Parses an OTA image and verifies the length and hash of the payload
+    pub fn from_bytes(data: &[u8]) -> Result<OtaImage, OtaError> {
+        let header = OtaHeader::from_bytes(data)?;
+        let payload = &data[OTA_HEADER_LEN..];
+
+        if header.len as usize != payload.len() {
+            return Err(OtaError::LengthMismatch(header.len, payload.len()));
+        }
+
+        if Sha256::digest(payload)[..] != header.sha256[..] {
+            return Err(OtaError::HashMismatch);
+        }
+
+        Ok(OtaImage {
+            header,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Returns the firmware image, decompressing it if it's compressed
+    pub fn firmware(&self) -> Result<Vec<u8>, OtaError> {
+        match self.header.typ {
+            OtaType::Raw => Ok(self.payload.clone()),
+            OtaType::Xz => {
+                let mut buf = vec![];
+
+                xz2::read::XzDecoder::new(&self.payload[..]).read_to_end(&mut buf)?;
+
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Writes the OTA header and the payload to `writer`
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), OtaError> {
+        writer.write_all(&self.header.to_bytes()?)?;
+        writer.write_all(&self.payload)?;
+
+        Ok(())
+    }
+}
+
+/// Compresses `data` with xz using the CRC32 check and the small dictionary that the OTA client
+/// can decompress with its limited RAM
+fn xz_compress(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut options = LzmaOptions::new_preset(9)?;
+    options.dict_size(XZ_DICT_SIZE);
+
+    let mut filters = Filters::new();
+    filters.lzma2(&options);
+
+    let stream = Stream::new_stream_encoder(&filters, Check::Crc32)?;
+    let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Returns a version string as a zero-padded 16 byte field
+fn version_to_bytes(version: &str) -> Result<[u8; MAX_VERSION_LEN], OtaError> {
+    let mut buf = [0u8; MAX_VERSION_LEN];
+
+    if version.len() > MAX_VERSION_LEN {
+        return Err(OtaError::VersionTooLong(version.to_string()));
+    }
+
+    buf[..version.len()].copy_from_slice(version.as_bytes());
+
+    Ok(buf)
+}
+
+fn version_from_bytes(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EFLASH_LOADER: &[u8] = include_bytes!("../../test/eflash_loader_40m.bin");
+
+    #[test]
+    fn it_should_write_the_ota_header() {
+        let ota = OtaImage::new(EFLASH_LOADER, "1.2.3", false).unwrap();
+        let mut buf = vec![];
+
+        ota.write_to(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), OTA_HEADER_LEN + EFLASH_LOADER.len());
+        assert_eq!(&buf[0x00..0x14], b"BL60X_OTA_Ver1.0RAW ");
+        assert_eq!(&buf[0x14..0x18], (EFLASH_LOADER.len() as u32).to_le_bytes());
+        assert_eq!(&buf[0x30..0x36], b"1.2.3\0");
+        assert_eq!(&buf[0x40..0x60], &Sha256::digest(EFLASH_LOADER)[..]);
+        assert_eq!(&buf[OTA_HEADER_LEN..], EFLASH_LOADER);
+    }
+
+    #[test]
+    fn it_should_compress_and_verify_ota_images() {
+        let ota = OtaImage::new(EFLASH_LOADER, "1.2.3", true).unwrap();
+        let mut buf = vec![];
+
+        ota.write_to(&mut buf).unwrap();
+
+        let parsed = OtaImage::from_bytes(&buf).unwrap();
+
+        assert_eq!(parsed.header.typ, OtaType::Xz);
+        assert_eq!(parsed.header.software_version, "1.2.3");
+        assert!(parsed.payload.len() < EFLASH_LOADER.len());
+        assert_eq!(parsed.firmware().unwrap(), EFLASH_LOADER);
+
+        // Corrupt the payload
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+
+        assert!(matches!(
+            OtaImage::from_bytes(&buf),
+            Err(OtaError::HashMismatch)
+        ));
+        assert!(matches!(
+            OtaImage::from_bytes(&buf[..last]),
+            Err(OtaError::LengthMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn it_should_reject_long_versions() {
+        assert!(matches!(
+            OtaImage::new(EFLASH_LOADER, "1.2.3-rc1+build.42", false),
+            Err(OtaError::VersionTooLong(_))
+        ));
+    }
+}
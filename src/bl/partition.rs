@@ -0,0 +1,263 @@
+//! The partition table that boot2 uses to find the firmware in flash
+//!
+//! There are two copies of the partition table in flash, at `0xE000` and `0xF000`, and the one
+//! with the highest age is the active one.
+
+use std::io::{self, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use thiserror::Error;
+
+use super::crc32;
+
+/// The flash offsets of the two copies of the partition table
+pub const PARTITION_TABLE_OFFSETS: [u32; 2] = [0xe000, 0xf000];
+
+/// The size of an entry in the partition table
+const ENTRY_LEN: usize = 36;
+
+/// The maximum number of entries in the partition table
+const MAX_ENTRIES: u16 = 16;
+
+#[derive(Error, Debug)]
+pub enum PartitionError {
+    #[error("The magic header value is invalid: {:?}", _0)]
+    InvalidMagicHeader([u8; 4]),
+    #[error("Invalid number of entries: {}", _0)]
+    InvalidEntryCount(u16),
+    #[error("Invalid header checksum (expected {:#010x}, got {:#010x})", _0, _1)]
+    InvalidHeaderChecksum(u32, u32),
+    #[error("Invalid entries checksum (expected {:#010x}, got {:#010x})", _0, _1)]
+    InvalidEntriesChecksum(u32, u32),
+    #[error("No valid partition table found")]
+    NotFound,
+    #[error("IO error: {}", _0)]
+    IoError(#[from] io::Error),
+}
+
+/// An entry in the partition table
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartitionEntry {
+    /// The type of the partition
+    pub kind: u8,
+    /// The flash device the partition is on
+    pub device: u8,
+    /// Which of the two addresses is active
+    pub active_index: u8,
+    /// The name of the partition
+    pub name: String,
+    /// The flash offsets of the two copies of the partition
+    pub address: [u32; 2],
+    /// The maximum length of each copy of the partition
+    pub max_len: [u32; 2],
+    /// The length of the data in the partition
+    pub len: u32,
+    /// The age of the partition, which is incremented on every update
+    pub age: u32,
+}
+
+impl PartitionEntry {
+    /// Returns the flash offset of the active copy of the partition
+    pub fn active_address(&self) -> u32 {
+        self.address[(self.active_index & 1) as usize]
+    }
+
+    /// Returns the maximum length of the active copy of the partition
+    pub fn active_max_len(&self) -> u32 {
+        self.max_len[(self.active_index & 1) as usize]
+    }
+}
+
+/// A partition table
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartitionTable {
+    /// The version of the partition table
+    pub version: u16,
+    /// The age of the partition table, which is incremented on every update
+    pub age: u32,
+    /// The partitions
+    pub entries: Vec<PartitionEntry>,
+}
+
+impl PartitionTable {
+    /// Parses a partition table from `buf`, verifying its checksums
+    pub fn parse(buf: &[u8]) -> Result<PartitionTable, PartitionError> {
+        let mut reader = buf;
+        let mut magic = [0u8; 4];
+
+        // Read the magic header
+        reader.read_exact(&mut magic)?;
+
+        if &magic != b"BFPT" {
+            return Err(PartitionError::InvalidMagicHeader(magic));
+        }
+
+        let version = reader.read_u16::<LittleEndian>()?;
+        let count = reader.read_u16::<LittleEndian>()?;
+        let age = reader.read_u32::<LittleEndian>()?;
+        let header_crc32 = reader.read_u32::<LittleEndian>()?;
+
+        // Assert that the header checksum is correct
+        let expected = crc32(&buf[0x0..0xc]);
+
+        if header_crc32 != expected {
+            return Err(PartitionError::InvalidHeaderChecksum(
+                expected,
+                header_crc32,
+            ));
+        }
+
+        if count > MAX_ENTRIES {
+            return Err(PartitionError::InvalidEntryCount(count));
+        }
+
+        let entries_len = count as usize * ENTRY_LEN;
+        let mut entries = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let kind = reader.read_u8()?;
+            let device = reader.read_u8()?;
+            let active_index = reader.read_u8()?;
+            let mut name = [0u8; 9];
+            reader.read_exact(&mut name)?;
+
+            let address = [
+                reader.read_u32::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            ];
+            let max_len = [
+                reader.read_u32::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            ];
+            let len = reader.read_u32::<LittleEndian>()?;
+            let age = reader.read_u32::<LittleEndian>()?;
+
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+
+            entries.push(PartitionEntry {
+                kind,
+                device,
+                active_index,
+                name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+                address,
+                max_len,
+                len,
+                age,
+            });
+        }
+
+        // Assert that the entries checksum is correct
+        let entries_crc32 = reader.read_u32::<LittleEndian>()?;
+        let expected = crc32(&buf[0x10..0x10 + entries_len]);
+
+        if entries_crc32 != expected {
+            return Err(PartitionError::InvalidEntriesChecksum(
+                expected,
+                entries_crc32,
+            ));
+        }
+
+        Ok(PartitionTable {
+            version,
+            age,
+            entries,
+        })
+    }
+
+    /// Returns the active partition table of a whole-flash dump, which is the valid copy with the
+    /// highest age
+    pub fn from_flash(flash: &[u8]) -> Result<PartitionTable, PartitionError> {
+        PARTITION_TABLE_OFFSETS
+            .iter()
+            .filter_map(|&offset| flash.get(offset as usize..))
+            .filter_map(|buf| PartitionTable::parse(buf).ok())
+            .max_by_key(|table| table.age)
+            .ok_or(PartitionError::NotFound)
+    }
+
+    /// Returns whether `flash` looks like a whole-flash dump with a partition table
+    pub fn is_flash_dump(flash: &[u8]) -> bool {
+        PARTITION_TABLE_OFFSETS
+            .iter()
+            .any(|&offset| flash.get(offset as usize..offset as usize + 4) == Some(&b"BFPT"[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a partition table with the given `(name, address, len)` entries
+    fn partition_table(age: u32, entries: &[(&str, u32, u32)]) -> Vec<u8> {
+        let mut buf = vec![];
+
+        buf.extend_from_slice(b"BFPT");
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&age.to_le_bytes());
+        buf.extend_from_slice(&crc32(&buf[0x0..0xc]).to_le_bytes());
+
+        for (name, address, len) in entries {
+            let mut name_buf = [0u8; 9];
+            name_buf[..name.len()].copy_from_slice(name.as_bytes());
+
+            buf.extend_from_slice(&[0, 0, 0]);
+            buf.extend_from_slice(&name_buf);
+            buf.extend_from_slice(&address.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+        }
+
+        let crc = crc32(&buf[0x10..]);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    #[test]
+    fn it_should_read_reference_partition_table() {
+        let table = PartitionTable::from_flash(REFERENCE_FIRMWARE).unwrap();
+        let names: Vec<&str> = table.entries.iter().map(|e| e.name.as_str()).collect();
+
+        assert_eq!(
+            names,
+            ["FW", "mfg", "media", "PSM", "KEY", "DATA", "factory"]
+        );
+        assert_eq!(table.entries[0].active_address(), 0x10000);
+        assert_eq!(table.entries[0].active_max_len(), 0xc8000);
+    }
+
+    #[test]
+    fn it_should_parse_partition_table() {
+        let buf = partition_table(3, &[("FW", 0x10000, 0xc8000), ("mfg", 0xd8000, 0x32000)]);
+        let table = PartitionTable::parse(&buf).unwrap();
+
+        assert_eq!(table.age, 3);
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.entries[0].name, "FW");
+        assert_eq!(table.entries[1].active_address(), 0xd8000);
+    }
+
+    #[test]
+    fn it_should_pick_the_newest_partition_table() {
+        let mut flash = vec![0xffu8; 0x10000];
+        let old = partition_table(1, &[("FW", 0x10000, 0x1000)]);
+        let new = partition_table(2, &[("FW", 0x20000, 0x1000)]);
+
+        flash[0xe000..0xe000 + old.len()].copy_from_slice(&old);
+        flash[0xf000..0xf000 + new.len()].copy_from_slice(&new);
+
+        assert!(PartitionTable::is_flash_dump(&flash));
+        assert_eq!(PartitionTable::from_flash(&flash).unwrap().age, 2);
+
+        // Corrupt the newest table, which should make the old one the active one
+        flash[0xf010] ^= 0xff;
+
+        assert_eq!(PartitionTable::from_flash(&flash).unwrap().age, 1);
+    }
+}
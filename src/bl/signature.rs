@@ -0,0 +1,258 @@
+//! ECDSA signing and verification of firmware images
+//!
+//! Signed images have a public key block and a signature block right after the boot header. The
+//! public key block is the uncompressed secp256r1 public key (`X || Y`) followed by its CRC32
+//! checksum, and the signature block is the length of the DER-encoded signature, the signature
+//! and a CRC32 checksum of both.
+//!
+//! The signature is an ECDSA-SHA256 signature of the image hash in the boot header, and the
+//! SHA-256 hash of the public key is what gets burned into the eFuse for secure boot.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use p256::SecretKey;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::crc32;
+
+/// The length of an uncompressed public key without the SEC1 tag
+pub const PUBLIC_KEY_LEN: usize = 64;
+
+/// The maximum length of a DER-encoded secp256r1 signature
+pub const MAX_SIGNATURE_LEN: usize = 72;
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("Invalid private key: {}", _0)]
+    InvalidPrivateKey(String),
+    #[error("Invalid public key: {}", _0)]
+    InvalidPublicKey(String),
+    #[error(
+        "Invalid public key checksum (expected {:#010x}, got {:#010x})",
+        _0,
+        _1
+    )]
+    InvalidPublicKeyChecksum(u32, u32),
+    #[error("Invalid signature length: {}", _0)]
+    InvalidSignatureLength(u32),
+    #[error("Invalid signature checksum (expected {:#010x}, got {:#010x})", _0, _1)]
+    InvalidSignatureChecksum(u32, u32),
+    #[error("The image is not signed")]
+    NotSigned,
+    #[error("The image hash doesn't match the image")]
+    HashMismatch,
+    #[error("The image is signed with a different public key")]
+    PublicKeyMismatch,
+    #[error("The signature is invalid")]
+    InvalidSignature,
+    #[error("Signed images can't ignore the image hash, since the hash is what is signed")]
+    IgnoredHash,
+    #[error("The segments are encrypted, so the image hash can't be checked without the AES key")]
+    EncryptedSegments,
+    #[error("The image has no segments, so its hash has to be calculated from the image data")]
+    NoSegments,
+    #[error("I/O error: {}", _0)]
+    IoError(#[from] io::Error),
+}
+
+/// The public key and signature blocks of a signed image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ImageSignature {
+    /// The uncompressed public key, `X || Y`
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+    /// The DER-encoded signature of the image hash
+    pub signature: Vec<u8>,
+}
+
+impl ImageSignature {
+    /// Signs the image `hash` with `key`
+    ///
+    /// The nonce is derived from the key and the hash as described in RFC 6979, so signing the
+    /// same image with the same key always gives the same signature
+    pub fn sign(key: &SigningKey, hash: &[u8; 32]) -> ImageSignature {
+        let signature: Signature = key.sign(hash);
+
+        ImageSignature {
+            public_key: public_key_bytes(key.verifying_key()),
+            signature: signature.to_der().as_bytes().to_vec(),
+        }
+    }
+
+    /// Verifies that this is a valid signature of the image `hash`
+    pub fn verify(&self, hash: &[u8; 32]) -> Result<(), SignatureError> {
+        let key = self.verifying_key()?;
+        let signature =
+            Signature::from_der(&self.signature).map_err(|_| SignatureError::InvalidSignature)?;
+
+        key.verify(hash, &signature)
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+
+    /// Returns the public key of the signature
+    pub fn verifying_key(&self) -> Result<VerifyingKey, SignatureError> {
+        let mut sec1 = [0x04u8; PUBLIC_KEY_LEN + 1];
+        sec1[1..].copy_from_slice(&self.public_key);
+
+        VerifyingKey::from_sec1_bytes(&sec1)
+            .map_err(|err| SignatureError::InvalidPublicKey(err.to_string()))
+    }
+
+    /// Returns the SHA-256 hash of the public key
+    pub fn public_key_hash(&self) -> [u8; 32] {
+        Sha256::digest(&self.public_key).into()
+    }
+
+    /// Returns the combined length of the public key and signature blocks
+    pub fn block_len(&self) -> usize {
+        PUBLIC_KEY_LEN + 4 + 4 + self.signature.len() + 4
+    }
+
+    /// Reads the public key and signature blocks, verifying their checksums
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<ImageSignature, SignatureError> {
+        let mut public_key = [0u8; PUBLIC_KEY_LEN];
+
+        // Read the public key and assert that its checksum is correct
+        reader.read_exact(&mut public_key)?;
+
+        let expected = crc32(&public_key);
+        let actual = reader.read_u32::<LittleEndian>()?;
+
+        if expected != actual {
+            return Err(SignatureError::InvalidPublicKeyChecksum(expected, actual));
+        }
+
+        // Read the signature and assert that its checksum is correct
+        let len = reader.read_u32::<LittleEndian>()?;
+
+        if len as usize > MAX_SIGNATURE_LEN {
+            return Err(SignatureError::InvalidSignatureLength(len));
+        }
+
+        let mut signature = vec![0u8; len as usize];
+        reader.read_exact(&mut signature)?;
+
+        let mut buf = len.to_le_bytes().to_vec();
+        buf.extend_from_slice(&signature);
+
+        let expected = crc32(&buf);
+        let actual = reader.read_u32::<LittleEndian>()?;
+
+        if expected != actual {
+            return Err(SignatureError::InvalidSignatureChecksum(expected, actual));
+        }
+
+        Ok(ImageSignature {
+            public_key,
+            signature,
+        })
+    }
+
+    /// Writes the public key and signature blocks to `writer`
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&self.public_key)?;
+        writer.write_all(&crc32(&self.public_key).to_le_bytes())?;
+
+        let mut buf = (self.signature.len() as u32).to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.signature);
+
+        writer.write_all(&buf)?;
+        writer.write_all(&crc32(&buf).to_le_bytes())
+    }
+}
+
+/// Returns the uncompressed public key, `X || Y`, without the SEC1 tag
+pub fn public_key_bytes(key: &VerifyingKey) -> [u8; PUBLIC_KEY_LEN] {
+    let point = key.to_encoded_point(false);
+    let mut bytes = [0u8; PUBLIC_KEY_LEN];
+
+    bytes.copy_from_slice(&point.as_bytes()[1..]);
+    bytes
+}
+
+/// Returns the SHA-256 hash of the public key that is burned into the eFuse
+pub fn public_key_hash(key: &VerifyingKey) -> [u8; 32] {
+    Sha256::digest(&public_key_bytes(key)).into()
+}
+
+/// Parses a PEM-encoded secp256r1 private key, in either the PKCS#8 or the SEC1 format
+pub fn signing_key_from_pem(pem: &str) -> Result<SigningKey, SignatureError> {
+    let secret_key = if pem.contains("BEGIN EC PRIVATE KEY") {
+        SecretKey::from_sec1_pem(pem).map_err(|err| err.to_string())
+    } else {
+        SecretKey::from_pkcs8_pem(pem).map_err(|err| err.to_string())
+    };
+
+    secret_key
+        .map(SigningKey::from)
+        .map_err(SignatureError::InvalidPrivateKey)
+}
+
+/// Parses a PEM-encoded secp256r1 public key
+pub fn verifying_key_from_pem(pem: &str) -> Result<VerifyingKey, SignatureError> {
+    VerifyingKey::from_public_key_pem(pem)
+        .map_err(|err| SignatureError::InvalidPublicKey(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = include_str!("../../test/ecdsa_private_key.pem");
+    const PUBLIC_KEY: &str = include_str!("../../test/ecdsa_public_key.pem");
+
+    #[test]
+    fn it_should_sign_reproducibly() {
+        let key = signing_key_from_pem(PRIVATE_KEY).unwrap();
+        let hash = [0x5au8; 32];
+
+        let first = ImageSignature::sign(&key, &hash);
+        let second = ImageSignature::sign(&key, &hash);
+
+        assert_eq!(first, second);
+        assert!(first.verify(&hash).is_ok());
+        assert!(matches!(
+            first.verify(&[0u8; 32]),
+            Err(SignatureError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn it_should_read_and_write_signature_blocks() {
+        let key = signing_key_from_pem(PRIVATE_KEY).unwrap();
+        let signature = ImageSignature::sign(&key, &[0x5au8; 32]);
+
+        let mut buf = vec![];
+        signature.write_to(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), signature.block_len());
+        assert_eq!(
+            ImageSignature::from_reader(&mut &buf[..]).unwrap(),
+            signature
+        );
+
+        // Corrupt the public key
+        buf[0] ^= 0xff;
+
+        assert!(matches!(
+            ImageSignature::from_reader(&mut &buf[..]),
+            Err(SignatureError::InvalidPublicKeyChecksum(_, _))
+        ));
+    }
+
+    #[test]
+    fn it_should_hash_the_public_key() {
+        let key = signing_key_from_pem(PRIVATE_KEY).unwrap();
+        let public_key = verifying_key_from_pem(PUBLIC_KEY).unwrap();
+
+        assert_eq!(key.verifying_key(), &public_key);
+        assert_eq!(
+            public_key_hash(&public_key),
+            ImageSignature::sign(&key, &[0u8; 32]).public_key_hash()
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Flash and RAM usage of an ELF file per region of the BL602 memory map
+//!
+//! Every allocated section is counted in the region it runs from, and sections with data that is
+//! copied from flash at startup, like `.data`, are also counted in the region they're loaded from.
+
+use std::convert::TryFrom;
+use std::io::{Read, Seek};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::memory_map::{self, MemoryRegion};
+use crate::elf_parser::{
+    ElfParser, ParseError, ProgType, SectionType, Symbol, SymbolSection, SymbolType,
+};
+
+/// The section flag that indicates that the section occupies memory
+const SHF_ALLOC: u64 = 0x2;
+
+/// The name of the pseudo-region of sections outside of the memory map
+pub const UNKNOWN_REGION: &str = "Unknown";
+
+/// The memory usage of a section
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SectionUsage {
+    /// The name of the section
+    pub name: String,
+    /// The address of the section in the region
+    pub addr: u64,
+    /// The size of the section, in bytes
+    pub size: u64,
+    /// Whether this is the load image of a section that runs from another region
+    pub load_image: bool,
+}
+
+/// The memory usage of a region
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegionUsage {
+    /// The name of the region
+    pub name: &'static str,
+    /// The size of the region, or `None` if it's outside of the memory map
+    pub size: Option<u32>,
+    /// The sections in the region
+    pub sections: Vec<SectionUsage>,
+}
+
+impl RegionUsage {
+    /// Returns the number of bytes used in the region
+    pub fn used(&self) -> u64 {
+        self.sections.iter().map(|s| s.size).sum()
+    }
+}
+
+/// The memory usage of an ELF file
+#[derive(Debug, Clone, Default)]
+pub struct SizeReport {
+    /// The regions with at least one section, in the order of the memory map
+    pub regions: Vec<RegionUsage>,
+    /// The function and object symbols with a size, by the name of their section
+    symbols: Vec<(String, Symbol)>,
+}
+
+impl SizeReport {
+    /// Creates a size report from the section headers, program headers and symbols of an ELF file
+    pub fn from_elf<R: Read + Seek>(parser: &ElfParser<R>) -> Result<SizeReport, ParseError> {
+        let mut report = SizeReport::default();
+        let sections = parser.section_headers();
+
+        for section in sections.iter().filter(|sh| sh.flags & SHF_ALLOC != 0) {
+            if section.size == 0 {
+                continue;
+            }
+
+            let name = section.name.clone().unwrap_or_default();
+
+            report.add(section.virt_addr, name.clone(), section.size, false);
+
+            // Count the load image of sections with data that is copied from another region
+            if let SectionType::NoBits = section.typ {
+                continue;
+            }
+
+            let load_addr = parser
+                .program_headers()
+                .iter()
+                .filter(|ph| ph.typ() == ProgType::Load)
+                .find(|ph| {
+                    section.virt_addr >= ph.virt_addr()
+                        && (section.virt_addr - ph.virt_addr()) < ph.mem_size()
+                })
+                .map(|ph| section.virt_addr - ph.virt_addr() + ph.phys_addr());
+
+            if let Some(load_addr) = load_addr {
+                if region_name(load_addr) != region_name(section.virt_addr) {
+                    report.add(load_addr, name, section.size, true);
+                }
+            }
+        }
+
+        // Sort the regions in the order of the memory map, with unknown regions last
+        report.regions.sort_by_key(|region| {
+            memory_map::BL602_MEMORY_MAP
+                .iter()
+                .position(|r| r.name == region.name)
+                .unwrap_or(usize::MAX)
+        });
+
+        // Keep the function and object symbols with a size, by section
+        for symbol in parser.symbol_table()?.iter() {
+            let index = match symbol.section {
+                SymbolSection::Index(index) => index as usize,
+                _ => continue,
+            };
+
+            if symbol.size == 0 || !matches!(symbol.typ, SymbolType::Func | SymbolType::Object) {
+                continue;
+            }
+
+            if let Some(name) = sections.get(index).and_then(|sh| sh.name.clone()) {
+                report.symbols.push((name, symbol.clone()));
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the usage of the region with the given `name`
+    pub fn region(&self, name: &str) -> Option<&RegionUsage> {
+        self.regions
+            .iter()
+            .find(|region| region.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the number of bytes used in the region with the given `name`
+    pub fn used(&self, name: &str) -> u64 {
+        self.region(name).map(RegionUsage::used).unwrap_or(0)
+    }
+
+    /// Returns up to `count` of the largest symbols in the section with the given `name`
+    pub fn largest_symbols(&self, section: &str, count: usize) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self
+            .symbols
+            .iter()
+            .filter(|(name, _)| name == section)
+            .map(|(_, symbol)| symbol)
+            .collect();
+
+        symbols.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        symbols.truncate(count);
+        symbols
+    }
+
+    fn add(&mut self, addr: u64, name: String, size: u64, load_image: bool) {
+        let region = region_of(addr);
+        let region_name = region.map(|r| r.name).unwrap_or(UNKNOWN_REGION);
+
+        let usage = match self.regions.iter_mut().position(|r| r.name == region_name) {
+            Some(index) => &mut self.regions[index],
+            None => {
+                self.regions.push(RegionUsage {
+                    name: region_name,
+                    size: region.map(|r: &MemoryRegion| r.len),
+                    sections: vec![],
+                });
+                self.regions.last_mut().unwrap()
+            }
+        };
+
+        usage.sections.push(SectionUsage {
+            name,
+            addr,
+            size,
+            load_image,
+        });
+    }
+}
+
+/// Returns the region that contains `addr`, where addresses beyond 32 bits are never in the
+/// memory map
+fn region_of(addr: u64) -> Option<&'static MemoryRegion> {
+    u32::try_from(addr).ok().and_then(memory_map::region_of)
+}
+
+fn region_name(addr: u64) -> &'static str {
+    region_of(addr)
+        .map(|region| region.name)
+        .unwrap_or(UNKNOWN_REGION)
+}
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum BudgetError {
+    #[error("Invalid budget '{}', expected REGION=SIZE, i.e. ITCM=32K", _0)]
+    InvalidBudget(String),
+    #[error("Unknown region '{}'", _0)]
+    UnknownRegion(String),
+}
+
+/// The maximum number of bytes to use in a region
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Budget {
+    /// The name of the region
+    pub region: &'static str,
+    /// The maximum number of bytes to use
+    pub limit: u64,
+}
+
+impl Budget {
+    /// Returns whether the usage of `report` is within this budget
+    pub fn is_met(&self, report: &SizeReport) -> bool {
+        report.used(self.region) <= self.limit
+    }
+}
+
+impl FromStr for Budget {
+    type Err = BudgetError;
+
+    /// Parses a budget like `ITCM=32K`, where the size may have a `K` or `M` suffix
+    fn from_str(s: &str) -> Result<Budget, BudgetError> {
+        let invalid = || BudgetError::InvalidBudget(s.to_string());
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().ok_or_else(invalid)?.trim();
+        let size = parts
+            .next()
+            .ok_or_else(invalid)?
+            .trim()
+            .to_ascii_uppercase();
+
+        let region = memory_map::BL602_MEMORY_MAP
+            .iter()
+            .find(|region| region.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| BudgetError::UnknownRegion(name.to_string()))?;
+
+        let (digits, multiplier) = if let Some(digits) = size.strip_suffix('K') {
+            (digits, 1024)
+        } else if let Some(digits) = size.strip_suffix('M') {
+            (digits, 1024 * 1024)
+        } else {
+            (size.as_str(), 1)
+        };
+
+        let limit: u64 = digits.parse().map_err(|_| invalid())?;
+
+        Ok(Budget {
+            region: region.name,
+            limit: limit * multiplier,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MINIMAL_ELF: &[u8] = include_bytes!("../../test/elf/bl602_minimal.elf");
+    const RAM_ELF: &[u8] = include_bytes!("../../test/elf/bl602_ram.elf");
+
+    #[test]
+    fn it_should_report_usage_per_region() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+        let report = SizeReport::from_elf(&parser).unwrap();
+
+        // .text, .rodata and the load image of .data are in flash
+        assert_eq!(report.used("XIP flash"), 0x44 + 0xe + 0x4);
+        assert_eq!(report.used("OCRAM"), 0x4 + 0x40);
+
+        let flash = report.region("XIP flash").unwrap();
+
+        assert!(flash
+            .sections
+            .iter()
+            .any(|s| s.name == ".data" && s.load_image));
+
+        let text: Vec<&str> = report
+            .largest_symbols(".text", 2)
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+
+        assert_eq!(text, ["helper", "main"]);
+    }
+
+    #[test]
+    fn it_should_check_budgets() {
+        let parser = ElfParser::parse(Cursor::new(RAM_ELF)).unwrap();
+        let report = SizeReport::from_elf(&parser).unwrap();
+
+        assert_eq!(report.used("ITCM"), 0x44 + 0xe + 0x4 + 0x40);
+        assert!("itcm=1K".parse::<Budget>().unwrap().is_met(&report));
+        assert!(!"ITCM=100".parse::<Budget>().unwrap().is_met(&report));
+        assert_eq!(
+            "SRAM=1K".parse::<Budget>(),
+            Err(BudgetError::UnknownRegion("SRAM".to_string()))
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::convert::TryInto;
 use std::ffi::OsStr;
+use std::fmt;
 use std::io::{self, Read, Write};
+use std::thread;
 use std::time::Duration;
 
 use log::{debug, trace, warn};
@@ -10,6 +12,22 @@ use thiserror::Error;
 
 use crate::bl::bootrom;
 pub use crate::error::SerialError;
+use crate::hex_file::{self, Block};
+
+pub mod trace;
+mod transport;
+
+pub use transport::{open_serial, Transport};
+
+/// The baud rates to fall back to when the link isn't stable at the requested baud rate, in
+/// descending order
+pub const FALLBACK_BAUD_RATES: [u32; 5] = [2_000_000, 1_500_000, 1_000_000, 921_600, 500_000];
+
+/// The size of the sectors the flash is erased in
+pub const FLASH_SECTOR_LEN: u32 = 4096;
+
+/// The number of bytes read from flash, twice, to determine whether the link is stable
+const LINK_TEST_SIZE: usize = 256;
 
 /// The serial settings expected by the BootROM on the bl602
 pub const BL602_BOOTROM_SERIAL_SETTINGS: SerialPortSettings = SerialPortSettings {
@@ -22,7 +40,81 @@ pub const BL602_BOOTROM_SERIAL_SETTINGS: SerialPortSettings = SerialPortSettings
 };
 
 pub struct Bl60xSerialPort {
-    port: Box<dyn SerialPort>,
+    port: Box<dyn Transport>,
+    retry_policy: RetryPolicy,
+    progress: Option<Box<dyn ProgressObserver>>,
+}
+
+/// The phase of a long-running operation that progress is reported for
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Phase {
+    /// Erasing flash
+    Erase,
+    /// Writing to flash
+    Write,
+    /// Reading from flash
+    Read,
+    /// Loading a segment into RAM
+    Load,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Phase::Erase => "Erasing",
+            Phase::Write => "Writing",
+            Phase::Read => "Reading",
+            Phase::Load => "Loading",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Receives progress updates from long-running operations on a `Bl60xSerialPort`
+pub trait ProgressObserver: Send {
+    /// Called when a new `phase` that will process `total` bytes starts
+    fn start(&mut self, phase: Phase, total: u64);
+
+    /// Called when `done` out of the total number of bytes of the current phase are processed
+    fn update(&mut self, done: u64);
+
+    /// Called when the current phase has finished successfully
+    fn finish(&mut self);
+}
+
+/// Determines how many times, and how often, a failed transfer is retried before giving up
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of times a failed transfer is retried
+    pub max_retries: u32,
+    /// The time to wait before the first retry
+    pub initial_backoff: Duration,
+    /// The upper limit of the time to wait between retries, as the backoff doubles every attempt
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns the time to wait before the given retry `attempt`, starting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
 }
 
 pub trait SerialWritableCommand {
@@ -79,6 +171,9 @@ pub enum IspError {
     #[error("The device returned an unexpected reply")]
     UnexpectedReply,
 
+    #[error("The device returned {} bytes, but {} bytes were requested", _0, _1)]
+    UnexpectedLength(usize, usize),
+
     #[error("Handshake failed - expected OK, got {:x?}", _0)]
     HandshakeFailed([u8; 2]),
     #[error("Boot ROM error: {}", _0)]
@@ -87,6 +182,25 @@ pub enum IspError {
     IoError(#[from] io::Error),
 }
 
+impl IspError {
+    /// Returns true if the error is likely caused by a glitch on the serial line, in which case
+    /// the transfer can be retried after resynchronizing with the device
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            IspError::UnexpectedReply
+            | IspError::UnexpectedLength(..)
+            | IspError::HandshakeFailed(_) => true,
+            IspError::BootRomError(bootrom::Error::CommandCrcError)
+            | IspError::BootRomError(bootrom::Error::CommandLengthError) => true,
+            IspError::IoError(err) => matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl Bl60xSerialPort {
     /// Opens the given `port` and configures it to use the communication settings expected by the
     /// BL60x bootrom
@@ -94,26 +208,105 @@ impl Bl60xSerialPort {
         port: &T,
         baud_rate: usize,
     ) -> Result<Bl60xSerialPort, SerialError> {
-        debug!("Opening serial port {:?}", port.as_ref());
+        let port = open_serial(port, baud_rate)?;
 
-        let mut settings = BL602_BOOTROM_SERIAL_SETTINGS;
-        let timeout = Duration::from_millis(2000);
+        Ok(Bl60xSerialPort::with_transport(Box::new(port)))
+    }
+
+    /// Creates a new `Bl60xSerialPort` that communicates with the device over the given
+    /// `transport`
+    pub fn with_transport(transport: Box<dyn Transport>) -> Bl60xSerialPort {
+        Bl60xSerialPort {
+            port: transport,
+            retry_policy: RetryPolicy::default(),
+            progress: None,
+        }
+    }
 
-        settings.baud_rate = baud_rate as u32;
+    /// Sets the observer that receives progress updates from long-running operations
+    pub fn set_progress_observer(&mut self, observer: Box<dyn ProgressObserver>) {
+        self.progress = Some(observer);
+    }
 
-        debug!("Setting baud rate to {}", settings.baud_rate);
-        debug!("Setting timeout to {:?}", timeout);
+    fn progress_start(&mut self, phase: Phase, total: u64) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(phase, total);
+        }
+    }
 
-        let port = serialport::open_with_settings(port, &settings).map_err(|err| {
-            SerialError::OpenError(port.as_ref().to_string_lossy().into_owned(), err)
-        })?;
-        // let mut port = serialport::open(port).map_err(|err| {
-        //     SerialError::OpenError(port.as_ref().to_string_lossy().into_owned(), err)
-        // })?;
+    fn progress_update(&mut self, done: u64) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.update(done);
+        }
+    }
+
+    fn progress_finish(&mut self) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.finish();
+        }
+    }
+
+    /// Sets the policy used to retry failed transfers
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        debug!("Setting retry policy to {:?}", retry_policy);
+
+        self.retry_policy = retry_policy;
+    }
 
-        Ok(Bl60xSerialPort { port })
+    /// Discards any pending input and performs the UART handshake again, so that the device is
+    /// ready to receive a new command after a failed transfer
+    pub fn resync(&mut self) -> Result<(), IspError> {
+        debug!("Resynchronizing with device");
+
+        // Give the device time to finish sending whatever it was sending before discarding it
+        thread::sleep(Duration::from_millis(20));
+
+        self.port.clear_input()?;
+        self.enter_uart_mode()?;
+
+        // Wait for 20ms
+        thread::sleep(Duration::from_millis(20));
+
+        Ok(())
+    }
+
+    /// Runs `f` and retries it according to the retry policy if it fails with a recoverable
+    /// error, resynchronizing with the device before each retry
+    fn with_retry<T, F>(&mut self, what: &str, mut f: F) -> Result<T, IspError>
+    where
+        F: FnMut(&mut Self) -> Result<T, IspError>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let err = match f(self) {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+
+            if !err.is_recoverable() || attempt >= self.retry_policy.max_retries {
+                return Err(err);
+            }
+
+            attempt += 1;
+
+            let backoff = self.retry_policy.backoff(attempt);
+
+            warn!(
+                "{} failed: {} - retrying in {:?} [{}/{}]",
+                what, err, backoff, attempt, self.retry_policy.max_retries
+            );
+
+            thread::sleep(backoff);
+
+            // A failed resync is retried together with the transfer itself
+            if let Err(err) = self.resync() {
+                warn!("Could not resynchronize with device: {}", err);
+            }
+        }
     }
 
+    /// Sets the baud rate of the serial port on the host side only
     pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), serialport::Error> {
         debug!("Setting serial port baud rate to {}", baud_rate);
 
@@ -122,11 +315,130 @@ impl Bl60xSerialPort {
         Ok(())
     }
 
+    /// Asks the eflash_loader to change its baud rate to `baud_rate` and changes the baud rate of
+    /// the host to match it
+    ///
+    /// Note: This is only supported by the eflash_loader, not by the BootROM
+    pub fn change_baud_rate(&mut self, baud_rate: u32) -> Result<(), IspError> {
+        let mut cmd = [0u8; 12];
+        let old_baud_rate = self.port.baud_rate()?;
+
+        debug!("Changing baud rate from {} to {}", old_baud_rate, baud_rate);
+
+        // Write the command id
+        cmd[0x00] = 0x20;
+        // Write the length of the command
+        cmd[0x02] = 0x08;
+        // Write the current baud rate
+        cmd[0x04..0x08].copy_from_slice(&old_baud_rate.to_le_bytes());
+        // Write the new baud rate
+        cmd[0x08..0x0c].copy_from_slice(&baud_rate.to_le_bytes());
+
+        // Calculate and write the checksum for the command data
+        cmd[0x01] = cmd[0x02..0x0c]
+            .iter()
+            .fold(0u8, |acc, &x| acc.wrapping_add(x));
+
+        self.port.write_all(&cmd)?;
+        self.port.flush()?;
+
+        // Give the command time to leave the host at the old baud rate before switching, which is
+        // at least the time it takes to transmit ~110 bits
+        let transmit_time = Duration::from_micros(110 * 1_000_000 / old_baud_rate.max(1) as u64);
+        thread::sleep(transmit_time.max(Duration::from_millis(3)));
+
+        self.set_baud_rate(baud_rate).map_err(io::Error::from)?;
+
+        // The eflash_loader replies at the new baud rate
+        self.read_reply()?;
+
+        trace!("Baud rate successfully changed to {}", baud_rate);
+
+        Ok(())
+    }
+
+    /// Reads the start of the flash twice to determine whether the data received at the current
+    /// baud rate is reliable
+    pub fn verify_link(&mut self) -> Result<(), IspError> {
+        let mut first = [0u8; LINK_TEST_SIZE];
+        let mut second = [0u8; LINK_TEST_SIZE];
+
+        self.read_flash_exact_once(0, &mut first)?;
+        self.read_flash_exact_once(0, &mut second)?;
+
+        if first[..] != second[..] {
+            warn!("Link test failed - the same flash data was read differently twice");
+
+            return Err(IspError::UnexpectedReply);
+        }
+
+        Ok(())
+    }
+
+    /// Negotiates the highest stable baud rate with the eflash_loader, starting with `baud_rate`
+    /// and falling back to the lower rates in `FALLBACK_BAUD_RATES`
+    ///
+    /// The fallback rates are only tried when going up from the current baud rate - a lower
+    /// `baud_rate` is asked for explicitly to cope with an unreliable adapter, so it's the only
+    /// one that is tried. If no candidate turns out to be stable, the current baud rate is kept.
+    /// Returns the baud rate that is used afterwards.
+    pub fn negotiate_baud_rate(&mut self, baud_rate: u32) -> Result<u32, IspError> {
+        let initial_baud_rate = self.port.baud_rate()?;
+        let fallbacks: &[u32] = if baud_rate > initial_baud_rate {
+            &FALLBACK_BAUD_RATES
+        } else {
+            &[]
+        };
+        let candidates = std::iter::once(baud_rate)
+            .chain(
+                fallbacks
+                    .iter()
+                    .copied()
+                    .filter(|&rate| rate < baud_rate && rate > initial_baud_rate),
+            )
+            .filter(|&rate| rate != initial_baud_rate);
+
+        for candidate in candidates {
+            let err = match self
+                .change_baud_rate(candidate)
+                .and_then(|_| self.verify_link())
+            {
+                Ok(_) => return Ok(candidate),
+                Err(err) => err,
+            };
+
+            warn!("Baud rate of {} is not stable: {}", candidate, err);
+
+            self.restore_baud_rate(initial_baud_rate, candidate)?;
+        }
+
+        Ok(initial_baud_rate)
+    }
+
+    /// Brings both the host and the eflash_loader back to `baud_rate` after a failed attempt at
+    /// switching to `failed_baud_rate`
+    fn restore_baud_rate(&mut self, baud_rate: u32, failed_baud_rate: u32) -> Result<(), IspError> {
+        debug!("Restoring baud rate {}", baud_rate);
+
+        // If the eflash_loader never received the command, it's still at the original baud rate
+        self.port.set_baud_rate(baud_rate)?;
+
+        if self.resync().is_ok() {
+            return Ok(());
+        }
+
+        // Otherwise it switched, and has to be told to switch back from the failed baud rate
+        self.port.set_baud_rate(failed_baud_rate)?;
+        self.port.clear_input()?;
+        self.change_baud_rate(baud_rate)?;
+        self.resync()
+    }
+
     /// Sets the timeout of the serial port
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), serialport::Error> {
         debug!("Setting serial timeout to {}", timeout.as_secs());
 
-        self.port.set_timeout(timeout)
+        Ok(self.port.set_timeout(timeout)?)
     }
 
     /// Makes the BootROM enter UART mode, returns `()` on success, `IspError` otherwise
@@ -139,13 +451,7 @@ impl Bl60xSerialPort {
 
         // Calculate the number of bytes to send in order to to keep the UART busy for 5ms
         // bauds * 3s / (8 data bits + 1 start bit + 1 stop bit) / 1000 ms
-        let num_bytes = self
-            .port
-            .baud_rate()
-            .expect("Could not get baud rate for serial port")
-            .saturating_mul(3)
-            / 10
-            / 1000;
+        let num_bytes = self.port.baud_rate()?.saturating_mul(3) / 10 / 1000;
 
         trace!("Trying to put device in UART mode");
         trace!("Sending {} x 0x55 bytes", num_bytes);
@@ -188,9 +494,6 @@ impl Bl60xSerialPort {
         Ok(u16::from_le_bytes([buf[0], buf[1]]))
     }
 
-    /// Attempts to read data from external flash at `address` and `size` bytes forward
-    //pub fn read_flash(&mut self, addr: usize, size: usize) -> Result<(), IspError> {}
-
     /// Sends the given buf as a boot header and attempts to load it
     pub fn load_boot_header(&mut self, boot_header: [u8; 176]) -> Result<(), IspError> {
         trace!("Trying to load boot header to RAM");
@@ -200,14 +503,17 @@ impl Bl60xSerialPort {
         })?;
 
         let mut buf = [0u8; 2];
-        let _ = self.port.read_exact(&mut buf)?;
+        self.port.read_exact(&mut buf)?;
 
         if &buf != b"OK" {
             let err = self.read_error()?;
 
-            println!("error code: {:#x}", err);
+            debug!(
+                "Error code from device when loading boot header: {:#x}",
+                err
+            );
 
-            return Err(IspError::HandshakeFailed([buf[0], buf[1]]));
+            return Err(IspError::BootRomError(bootrom::Error::from_primitive(err)));
         }
 
         Ok(())
@@ -246,8 +552,38 @@ impl Bl60xSerialPort {
         Ok(())
     }
 
+    /// Reads the flash starting at `addr` to fill `buf`, in as many transfers as necessary
+    pub fn read_flash(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), IspError> {
+        const READ_SIZE: usize = 8192;
+
+        let total = buf.len() as u64;
+        let mut done = 0;
+
+        self.progress_start(Phase::Read, total);
+
+        for chunk in buf.chunks_mut(READ_SIZE) {
+            self.read_flash_exact(addr + done as u32, chunk)?;
+
+            done += chunk.len() as u64;
+            self.progress_update(done);
+        }
+
+        self.progress_finish();
+
+        Ok(())
+    }
+
     /// Reads the exact number of bytes at flash `addr` to full `buf`.
+    ///
+    /// The read is retried according to the retry policy if it fails.
     pub fn read_flash_exact(&mut self, addr: u32, out_buf: &mut [u8]) -> Result<(), IspError> {
+        self.with_retry("Flash read", |port| {
+            port.read_flash_exact_once(addr, out_buf)
+        })
+    }
+
+    /// Reads the exact number of bytes at flash `addr` to fill `buf` without retrying
+    fn read_flash_exact_once(&mut self, addr: u32, out_buf: &mut [u8]) -> Result<(), IspError> {
         let mut buf = [0u8; 12];
 
         debug!(
@@ -271,15 +607,18 @@ impl Bl60xSerialPort {
         self.port.write_all(&buf)?;
 
         // Read the response and assert that it is OK
-        let _ = self.read_reply()?;
+        self.read_reply()?;
 
         // Read the flash data length
         let mut len_buf = [0u8; 2];
         self.port.read_exact(&mut len_buf)?;
-        let length = u16::from_le_bytes([len_buf[0], len_buf[1]]);
+        let length = u16::from_le_bytes([len_buf[0], len_buf[1]]) as usize;
 
-        // Assert that the returned length is smaller than the buffer used in the eflash loader
-        assert!(length <= 8192);
+        // Assert that the device is returning what was asked for - a garbled length means that
+        // the reply can't be trusted
+        if length != out_buf.len() {
+            return Err(IspError::UnexpectedLength(length, out_buf.len()));
+        }
 
         // Read the flash data
         self.port.read_exact(out_buf)?;
@@ -316,61 +655,95 @@ impl Bl60xSerialPort {
             addr + size
         );
 
+        // The device doesn't report progress while erasing, so this is all or nothing
+        self.progress_start(Phase::Erase, size as u64);
+
         self.port.write_all(&cmd)?;
         self.read_reply()?;
 
+        self.progress_update(size as u64);
+        self.progress_finish();
+
         trace!("Flash regions successfully erased");
 
         Ok(())
     }
 
     /// Writes the given `data` to the flash at offset `addr`, starting from 0
+    ///
+    /// Each chunk is retried according to the retry policy if it fails.
     pub fn write_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), IspError> {
         const WRITE_SIZE: usize = 8192;
-        let mut cmd = [0u8; 8];
 
         // Erase the flash we want to write to, to ensure that it's all zeros
-        self.erase_flash(addr, data.len().try_into().unwrap())?;
+        let size = data.len().try_into().unwrap();
+        self.with_retry("Flash erase", |port| port.erase_flash(addr, size))?;
 
-        let mut remaining = data.len();
         let mut start = addr;
-        let n = remaining / WRITE_SIZE;
-        let n = if remaining % WRITE_SIZE > 0 { n + 1 } else { n };
+        let mut done = 0;
 
-        for i in 0..n {
-            let num_bytes = std::cmp::min(remaining, WRITE_SIZE);
+        self.progress_start(Phase::Write, data.len() as u64);
 
-            // Write the command id
-            cmd[0x00] = 0x31;
-            // Write the length of the payload
-            cmd[0x02..0x04].copy_from_slice(&((num_bytes as u16) + 4).to_le_bytes());
-            // Write the start address
-            cmd[0x04..0x08].copy_from_slice(&start.to_le_bytes());
+        for payload in data.chunks(WRITE_SIZE) {
+            self.with_retry("Flash write", |port| port.write_flash_chunk(start, payload))?;
 
-            let off = i * WRITE_SIZE;
-            let payload = &data[off..off + num_bytes];
+            start += payload.len() as u32;
+            done += payload.len() as u64;
+            self.progress_update(done);
+        }
 
-            // Calculate and write the checksum
-            let chksum = cmd[0x02..0x08]
-                .iter()
-                .fold(0u8, |acc, &x| acc.wrapping_add(x));
+        self.progress_finish();
 
-            let chksum = payload.iter().fold(chksum, |acc, &x| acc.wrapping_add(x));
+        Ok(())
+    }
 
-            cmd[0x01] = chksum;
+    /// Writes the data of `blocks` to the flash at their offsets
+    ///
+    /// The blocks that share a sector are written as one, so erasing the sector for one block
+    /// doesn't erase another.
+    pub fn write_flash_blocks(&mut self, blocks: &[Block]) -> Result<(), IspError> {
+        for block in hex_file::merge_by_sector(blocks, FLASH_SECTOR_LEN) {
+            debug!(
+                "Writing {} bytes to flash at {:#010x}",
+                block.data.len(),
+                block.addr
+            );
 
-            trace!("Writing {} bytes to flash @ 0x{:08x}", num_bytes, start);
+            self.write_flash(block.addr, &block.data)?;
+        }
 
-            self.port.write_all(&cmd)?;
-            self.port.write_all(&payload)?;
+        Ok(())
+    }
 
-            self.read_reply()?;
+    /// Writes a single chunk of at most 8192 bytes of `payload` to the flash at offset `start`
+    fn write_flash_chunk(&mut self, start: u32, payload: &[u8]) -> Result<(), IspError> {
+        let mut cmd = [0u8; 8];
+        let num_bytes = payload.len();
 
-            trace!("Successfully wrote {} bytes", num_bytes);
+        // Write the command id
+        cmd[0x00] = 0x31;
+        // Write the length of the payload
+        cmd[0x02..0x04].copy_from_slice(&((num_bytes as u16) + 4).to_le_bytes());
+        // Write the start address
+        cmd[0x04..0x08].copy_from_slice(&start.to_le_bytes());
 
-            start += num_bytes as u32;
-            remaining -= num_bytes;
-        }
+        // Calculate and write the checksum
+        let chksum = cmd[0x02..0x08]
+            .iter()
+            .fold(0u8, |acc, &x| acc.wrapping_add(x));
+
+        let chksum = payload.iter().fold(chksum, |acc, &x| acc.wrapping_add(x));
+
+        cmd[0x01] = chksum;
+
+        trace!("Writing {} bytes to flash @ 0x{:08x}", num_bytes, start);
+
+        self.port.write_all(&cmd)?;
+        self.port.write_all(payload)?;
+
+        self.read_reply()?;
+
+        trace!("Successfully wrote {} bytes", num_bytes);
 
         Ok(())
     }
@@ -394,7 +767,7 @@ impl Bl60xSerialPort {
         self.port.write_all(&cmd)?;
 
         // Assert that the reponse is OK
-        let _ = self.read_reply()?;
+        self.read_reply()?;
 
         // Read the sha256 data length
         let mut len_buf = [0u8; 2];
@@ -410,6 +783,36 @@ impl Bl60xSerialPort {
         Ok(buf)
     }
 
+    /// Reads the JEDEC ID of the flash chip through the eflash_loader
+    ///
+    /// Returns the ID as `manufacturer << 16 | memory type << 8 | capacity`
+    pub fn read_jedec_id(&mut self) -> Result<u32, IspError> {
+        let cmd = [0x36, 0x00, 0x00, 0x00];
+
+        trace!("Sending read JEDEC ID command");
+
+        // Write the command to the serial device
+        self.port.write_all(&cmd)?;
+
+        // Assert that the reponse is OK
+        self.read_reply()?;
+
+        // Read the data length
+        let mut len_buf = [0u8; 2];
+        self.port.read_exact(&mut len_buf)?;
+        let length = u16::from_le_bytes(len_buf);
+
+        // Read the JEDEC ID, which is padded to 4 bytes
+        let mut buf = vec![0u8; length as usize];
+        self.port.read_exact(&mut buf)?;
+
+        if buf.len() < 3 {
+            return Err(IspError::UnexpectedReply);
+        }
+
+        Ok(u32::from_be_bytes([0, buf[0], buf[1], buf[2]]))
+    }
+
     pub fn check_image(&mut self) -> Result<(), IspError> {
         let mut buf = [0u8; 4];
 
@@ -434,7 +837,7 @@ impl Bl60xSerialPort {
 
         self.port.write_all(&buf)?;
 
-        let _ = self.read_reply()?;
+        self.read_reply()?;
 
         trace!("Successfully sent run image command");
 
@@ -483,6 +886,10 @@ impl Bl60xSerialPort {
             return Err(IspError::BootRomError(bootrom::Error::from_primitive(err)));
         }
 
+        let mut done = 0;
+
+        self.progress_start(Phase::Load, segment.data.len() as u64);
+
         for (idx, chunk) in chunks.enumerate() {
             std::thread::sleep(Duration::from_millis(100));
 
@@ -503,7 +910,7 @@ impl Bl60xSerialPort {
             // Write the length
             buf.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
             // Write the segment
-            buf.extend_from_slice(&chunk);
+            buf.extend_from_slice(chunk);
 
             self.port.write_all(&buf)?;
             self.port.read_exact(&mut res_buf)?;
@@ -511,8 +918,12 @@ impl Bl60xSerialPort {
             if &res_buf == b"FL" {
                 let err = self.read_error()?;
 
-                println!("Error when sending segment header: {:#x}", err);
-                println!("hdr res {:?} hdr: {:x?}", res_buf, hdr_buf);
+                debug!(
+                    "Error code from device when loading segment data: {:#x}",
+                    err
+                );
+
+                return Err(IspError::BootRomError(bootrom::Error::from_primitive(err)));
             } else if &res_buf == b"OK" {
                 // Do nothing
                 // debug!("OK");
@@ -527,8 +938,13 @@ impl Bl60xSerialPort {
                 // TODO: handle response
                 //println!("res_buf {:?}", res_buf);
             }
+
+            done += chunk.len() as u64;
+            self.progress_update(done);
         }
 
+        self.progress_finish();
+
         Ok(())
     }
 
@@ -539,7 +955,7 @@ impl Bl60xSerialPort {
         self.send_command(GetBootInfo)?;
 
         let mut buf = [0u8; 24];
-        let _ = self.port.read_exact(&mut buf)?;
+        self.port.read_exact(&mut buf)?;
 
         let rom_version = u32::from_le_bytes(buf[0x4..0x8].try_into().unwrap());
         let otp_info = buf[0x8..0x18].try_into().unwrap();
@@ -555,10 +971,17 @@ impl Bl60xSerialPort {
 
 #[cfg(test)]
 mod tests {
+    use super::trace::ReplayTransport;
     use super::*;
     use crate::bl::{self, Firmware};
     use std::io::Cursor;
 
+    fn replay(transcript: &str) -> Bl60xSerialPort {
+        let transport = ReplayTransport::from_reader(transcript.as_bytes()).unwrap();
+
+        Bl60xSerialPort::with_transport(Box::new(transport))
+    }
+
     #[test]
     fn it_should_serialize_get_boot_info_cmd() {
         let mut buf = vec![];
@@ -589,4 +1012,235 @@ mod tests {
         assert_eq!(&buf[..4], &[0x11, 0x00, 0xb0, 0x00]);
         assert_eq!(&buf[4..], &bl::EFLASH_LOADER_NONE_BIN[0..176]);
     }
+
+    #[test]
+    fn it_should_double_retry_backoff_up_to_max() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(300),
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(300));
+        assert_eq!(policy.backoff(40), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn it_should_only_retry_recoverable_errors() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "not found");
+
+        assert!(IspError::UnexpectedReply.is_recoverable());
+        assert!(IspError::IoError(timeout).is_recoverable());
+        assert!(IspError::BootRomError(bootrom::Error::CommandCrcError).is_recoverable());
+        assert!(!IspError::IoError(not_found).is_recoverable());
+        assert!(!IspError::BootRomError(bootrom::Error::FlashWriteError).is_recoverable());
+    }
+
+    #[test]
+    fn it_should_get_boot_info_from_transcript() {
+        let mut port = replay(
+            "0.000000 baud 500000
+             0.010000 > 10 00 00 00 ; get_boot_info
+             0.012000 < 4f 4b 14 00 01 00 00 00 00 00 00 00 03 00 00 00
+             0.012100 < 58 9e 02 42 e8 b4 1d 00",
+        );
+
+        let boot_info = port.get_boot_info().unwrap();
+
+        assert_eq!(boot_info.rom_version, 1);
+        assert_eq!(&boot_info.otp_info[4..8], &[3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn it_should_read_jedec_id_from_transcript() {
+        let mut port = replay(
+            "0.000000 baud 2000000
+             0.010000 > 36 00 00 00 ; flash_read_jedec_id
+             0.012000 < 4f 4b 04 00 ef 70 15 00",
+        );
+
+        assert_eq!(port.read_jedec_id().unwrap(), 0xef7015);
+    }
+
+    #[test]
+    fn it_should_retry_flash_read_after_timeout() {
+        let mut port = replay(
+            "0.000000 baud 20000
+             0.100000 > 32 1c 08 00 00 10 00 00 04 00 00 00 ; flash_read
+             2.100000 ! ; timeout
+             2.200000 clear
+             2.210000 > 55 55 55 55 55 55 ; handshake
+             2.220000 < 4f 4b ; OK
+             2.250000 > 32 1c 08 00 00 10 00 00 04 00 00 00 ; flash_read
+             2.260000 < 4f 4b ; OK
+             2.260100 < 04 00 de ad be ef",
+        );
+
+        let mut buf = [0u8; 4];
+        port.read_flash_exact(0x1000, &mut buf).unwrap();
+
+        assert_eq!(buf, [0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn it_should_retry_flash_read_after_garbled_length() {
+        let mut port = replay(
+            "0.000000 baud 20000
+             0.100000 > 32 1c 08 00 00 10 00 00 04 00 00 00 ; flash_read
+             0.110000 < 4f 4b ; OK
+             0.110100 < ff 7f
+             0.200000 clear
+             0.210000 > 55 55 55 55 55 55 ; handshake
+             0.220000 < 4f 4b ; OK
+             0.250000 > 32 1c 08 00 00 10 00 00 04 00 00 00 ; flash_read
+             0.260000 < 4f 4b ; OK
+             0.260100 < 04 00 de ad be ef",
+        );
+
+        let mut buf = [0u8; 4];
+        port.read_flash_exact(0x1000, &mut buf).unwrap();
+
+        assert_eq!(buf, [0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn it_should_report_flash_write_progress() {
+        use std::sync::{Arc, Mutex};
+
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl ProgressObserver for Recorder {
+            fn start(&mut self, phase: Phase, total: u64) {
+                self.0.lock().unwrap().push(format!("{} {}", phase, total));
+            }
+
+            fn update(&mut self, done: u64) {
+                self.0.lock().unwrap().push(done.to_string());
+            }
+
+            fn finish(&mut self) {
+                self.0.lock().unwrap().push("done".to_string());
+            }
+        }
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut port = replay(
+            "0.000000 baud 500000
+             0.100000 > 30 0c 08 00 00 00 00 00 04 00 00 00 ; flash_erase
+             0.200000 < 4f 4b ; OK
+             0.300000 > 31 40 08 00 00 00 00 00 ; flash_write
+             0.300100 > de ad be ef
+             0.400000 < 4f 4b ; OK",
+        );
+
+        port.set_progress_observer(Box::new(Recorder(events.clone())));
+        port.write_flash(0, &[0xde, 0xad, 0xbe, 0xef]).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["Erasing 4", "4", "done", "Writing 4", "4", "done"]
+        );
+    }
+
+    #[test]
+    fn it_should_write_blocks_in_the_same_sector_together() {
+        let mut port = replay(
+            "0.000000 baud 500000
+             0.100000 > 30 32 08 00 00 10 00 00 0a 10 00 00 ; flash_erase
+             0.200000 < 4f 4b ; OK
+             0.300000 > 31 50 0e 00 00 10 00 00 ; flash_write
+             0.300100 > de ad ff ff ff ff ff ff be ef
+             0.400000 < 4f 4b ; OK
+             0.500000 > 30 49 08 00 00 20 00 00 01 20 00 00 ; flash_erase
+             0.600000 < 4f 4b ; OK
+             0.700000 > 31 26 05 00 00 20 00 00 ; flash_write
+             0.700100 > 01
+             0.800000 < 4f 4b ; OK",
+        );
+
+        // The first two blocks share a sector, so erasing it for the second block mustn't wipe
+        // the first one
+        let blocks = [
+            Block {
+                addr: 0x2000,
+                data: vec![0x01],
+            },
+            Block {
+                addr: 0x1008,
+                data: vec![0xbe, 0xef],
+            },
+            Block {
+                addr: 0x1000,
+                data: vec![0xde, 0xad],
+            },
+        ];
+
+        port.write_flash_blocks(&blocks).unwrap();
+    }
+
+    /// Returns a transcript line with the reply to the flash read done by `verify_link`
+    fn link_test_reply(fill: u8) -> String {
+        format!(
+            "0.0 < 4f 4b 00 01{}\n",
+            format!(" {:02x}", fill).repeat(LINK_TEST_SIZE)
+        )
+    }
+
+    #[test]
+    fn it_should_fall_back_to_lower_baud_rate() {
+        let link_test = "0.0 > 32 09 08 00 00 00 00 00 00 01 00 00\n";
+        let transcript = [
+            "0.0 baud 20000\n",
+            // The link is unstable at 2 Mbaud
+            "0.0 > 20 98 08 00 20 4e 00 00 80 84 1e 00 ; change_rate\n",
+            "0.0 baud 2000000\n",
+            "0.0 < 4f 4b\n",
+            link_test,
+            &link_test_reply(0xff),
+            link_test,
+            &link_test_reply(0xfe),
+            // The device never received the change
+            "0.0 baud 20000\n",
+            "0.0 clear\n",
+            "0.0 > 55 55 55 55 55 55\n",
+            "0.0 < 4f 4b\n",
+            // The link is stable at 1.5 Mbaud
+            "0.0 > 20 cf 08 00 20 4e 00 00 60 e3 16 00 ; change_rate\n",
+            "0.0 baud 1500000\n",
+            "0.0 < 4f 4b\n",
+            link_test,
+            &link_test_reply(0xff),
+            link_test,
+            &link_test_reply(0xff),
+        ]
+        .concat();
+
+        let mut port = replay(&transcript);
+
+        assert_eq!(port.negotiate_baud_rate(2_000_000).unwrap(), 1_500_000);
+    }
+
+    #[test]
+    fn it_should_lower_baud_rate_when_asked_to() {
+        let link_test = "0.0 > 32 09 08 00 00 00 00 00 00 01 00 00\n";
+        let transcript = [
+            "0.0 baud 500000\n",
+            "0.0 > 20 93 08 00 20 a1 07 00 00 c2 01 00 ; change_rate\n",
+            "0.0 baud 115200\n",
+            "0.0 < 4f 4b\n",
+            link_test,
+            &link_test_reply(0xff),
+            link_test,
+            &link_test_reply(0xff),
+        ]
+        .concat();
+
+        let mut port = replay(&transcript);
+
+        assert_eq!(port.negotiate_baud_rate(115_200).unwrap(), 115_200);
+    }
 }
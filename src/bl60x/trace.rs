@@ -0,0 +1,575 @@
+//! Recording and replaying of the raw communication with a device
+//!
+//! A transcript is a plain text file with one event per line, in the form of
+//!
+//! ```text
+//! <seconds since start> <kind> [data] [; annotation]
+//! ```
+//!
+//! where `kind` is `>` for bytes written to the device, `<` for bytes read from the device, `!`
+//! for a read that timed out, `baud` for a change of baud rate and `clear` for when the input
+//! buffer was discarded. Data is written as space-separated hex bytes and the annotation is a
+//! human readable description of the data, like the name of the command that was sent.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Read, Write};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use super::Transport;
+
+/// The first line of every transcript
+const TRANSCRIPT_HEADER: &str = "# bouffalo-cli transcript";
+
+/// Transcript parsing errors
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("Invalid transcript entry on line {}: {}", _0, _1)]
+    InvalidEntry(usize, String),
+    #[error("I/O error: {}", _0)]
+    IoError(#[from] io::Error),
+}
+
+/// A single event in a transcript
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// Bytes written to the device
+    Write(Vec<u8>),
+    /// Bytes read from the device
+    Read(Vec<u8>),
+    /// A read that timed out before anything was received
+    ReadTimeout,
+    /// The baud rate was changed
+    SetBaudRate(u32),
+    /// The input buffer was discarded
+    ClearInput,
+}
+
+/// An event and the time at which it happened, relative to the start of the transcript
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub timestamp: Duration,
+    pub event: Event,
+}
+
+/// Returns the name of the BootROM or eflash_loader command with the given `id`, if it's known
+pub fn command_name(id: u8) -> Option<&'static str> {
+    let name = match id {
+        0x10 => "get_boot_info",
+        0x11 => "load_boot_header",
+        0x12 => "load_public_key",
+        0x13 => "load_public_key2",
+        0x14 => "load_signature",
+        0x15 => "load_signature2",
+        0x16 => "load_aes_iv",
+        0x17 => "load_segment_header",
+        0x18 => "load_segment_data",
+        0x19 => "check_image",
+        0x1a => "run_image",
+        0x20 => "change_rate",
+        0x21 => "reset",
+        0x30 => "flash_erase",
+        0x31 => "flash_write",
+        0x32 => "flash_read",
+        0x33 => "flash_boot",
+        0x36 => "flash_read_jedec_id",
+        0x3a => "flash_write_check",
+        0x3b => "flash_set_parameters",
+        0x3c => "flash_chip_erase",
+        0x3d => "flash_read_sha256",
+        0x40 => "efuse_write",
+        0x41 => "efuse_read",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Writes transcript records to an underlying writer, annotating them along the way
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    /// Whether the next write is expected to be the start of a new command
+    at_command_start: bool,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Creates a new `TraceWriter` and writes the transcript header to `writer`
+    pub fn new(mut writer: W) -> io::Result<TraceWriter<W>> {
+        writeln!(writer, "{}", TRANSCRIPT_HEADER)?;
+
+        Ok(TraceWriter {
+            writer,
+            at_command_start: true,
+        })
+    }
+
+    /// Writes the given `record` as a single line
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = format!(
+            "{}.{:06}",
+            record.timestamp.as_secs(),
+            record.timestamp.subsec_micros()
+        );
+
+        let annotation = match &record.event {
+            Event::Write(data) => {
+                line.push_str(" >");
+                push_hex(&mut line, data);
+
+                self.annotate_write(data)
+            }
+            Event::Read(data) => {
+                line.push_str(" <");
+                push_hex(&mut line, data);
+
+                // Whatever the host writes after reading a reply is the next command
+                self.at_command_start = true;
+
+                match data.as_slice() {
+                    b"OK" => Some("OK"),
+                    b"FL" => Some("FAIL"),
+                    _ => None,
+                }
+            }
+            Event::ReadTimeout => {
+                line.push_str(" !");
+                self.at_command_start = true;
+
+                Some("timeout")
+            }
+            Event::SetBaudRate(baud_rate) => {
+                let _ = write!(line, " baud {}", baud_rate);
+                self.at_command_start = true;
+
+                None
+            }
+            Event::ClearInput => {
+                line.push_str(" clear");
+                self.at_command_start = true;
+
+                None
+            }
+        };
+
+        if let Some(annotation) = annotation {
+            line.push_str(" ; ");
+            line.push_str(annotation);
+        }
+
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+
+    /// Returns an annotation for the written `data`, if it can be decoded
+    fn annotate_write(&mut self, data: &[u8]) -> Option<&'static str> {
+        if !data.is_empty() && data.iter().all(|&b| b == 0x55) {
+            self.at_command_start = true;
+
+            return Some("handshake");
+        }
+
+        if !self.at_command_start {
+            return None;
+        }
+
+        self.at_command_start = false;
+
+        data.first()
+            .map(|&id| command_name(id).unwrap_or("unknown command"))
+    }
+}
+
+/// Appends the given `data` to `line` as space-separated hex bytes
+fn push_hex(line: &mut String, data: &[u8]) {
+    for byte in data {
+        let _ = write!(line, " {:02x}", byte);
+    }
+}
+
+/// Parses a transcript from the given `reader`
+pub fn parse_transcript<R: BufRead>(reader: R) -> Result<Vec<Record>, TraceError> {
+    let mut records = Vec::new();
+
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = idx + 1;
+        let invalid = |msg: &str| TraceError::InvalidEntry(line_no, msg.to_string());
+
+        // Strip the annotation and skip empty lines and comments
+        let line = line.split(';').next().unwrap_or("").trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+        let timestamp = tokens
+            .next()
+            .and_then(parse_timestamp)
+            .ok_or_else(|| invalid("missing or invalid timestamp"))?;
+
+        let event = match tokens.next() {
+            Some(">") => Event::Write(parse_hex(tokens).map_err(|e| invalid(&e))?),
+            Some("<") => Event::Read(parse_hex(tokens).map_err(|e| invalid(&e))?),
+            Some("!") => Event::ReadTimeout,
+            Some("clear") => Event::ClearInput,
+            Some("baud") => Event::SetBaudRate(
+                tokens
+                    .next()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or_else(|| invalid("missing or invalid baud rate"))?,
+            ),
+            Some(kind) => return Err(invalid(&format!("unknown event kind `{}`", kind))),
+            None => return Err(invalid("missing event kind")),
+        };
+
+        records.push(Record { timestamp, event });
+    }
+
+    Ok(records)
+}
+
+/// Parses a timestamp in the form of `<seconds>[.<fraction>]`
+fn parse_timestamp(s: &str) -> Option<Duration> {
+    let mut parts = s.splitn(2, '.');
+    let secs = parts.next()?.parse().ok()?;
+    let nanos = match parts.next() {
+        Some(frac) if !frac.is_empty() && frac.len() <= 9 => {
+            frac.parse::<u32>().ok()? * 10u32.pow(9 - frac.len() as u32)
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+
+    Some(Duration::new(secs, nanos))
+}
+
+/// Parses space-separated hex bytes
+fn parse_hex<'a, I: Iterator<Item = &'a str>>(tokens: I) -> Result<Vec<u8>, String> {
+    tokens
+        .map(|t| u8::from_str_radix(t, 16).map_err(|_| format!("invalid hex byte `{}`", t)))
+        .collect()
+}
+
+/// A transport that records everything that passes through the `inner` transport
+pub struct TracingTransport<T: Transport, W: Write + Send> {
+    inner: T,
+    writer: TraceWriter<W>,
+    start: Instant,
+}
+
+impl<T: Transport, W: Write + Send> TracingTransport<T, W> {
+    /// Wraps the `inner` transport and records the communication to `writer`
+    pub fn new(inner: T, writer: W) -> io::Result<TracingTransport<T, W>> {
+        let mut transport = TracingTransport {
+            inner,
+            writer: TraceWriter::new(writer)?,
+            start: Instant::now(),
+        };
+
+        // Record the initial baud rate so the replay knows what it started with
+        let baud_rate = transport.inner.baud_rate()?;
+        transport.record(Event::SetBaudRate(baud_rate))?;
+
+        Ok(transport)
+    }
+
+    fn record(&mut self, event: Event) -> io::Result<()> {
+        self.writer.write_record(&Record {
+            timestamp: self.start.elapsed(),
+            event,
+        })
+    }
+}
+
+impl<T: Transport, W: Write + Send> Read for TracingTransport<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.record(Event::Read(buf[..n].to_vec()))?;
+
+                Ok(n)
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                self.record(Event::ReadTimeout)?;
+
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T: Transport, W: Write + Send> Write for TracingTransport<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+
+        self.record(Event::Write(buf[..n].to_vec()))?;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport, W: Write + Send> Transport for TracingTransport<T, W> {
+    fn baud_rate(&self) -> io::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)?;
+        self.record(Event::SetBaudRate(baud_rate))
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.inner.clear_input()?;
+        self.record(Event::ClearInput)
+    }
+}
+
+/// A transport that plays the part of the device in a recorded transcript
+///
+/// Everything the host writes is compared against what was written in the transcript, and reads
+/// are served from what the device replied in the transcript. Any deviation from the transcript
+/// results in an error.
+pub struct ReplayTransport {
+    events: VecDeque<Event>,
+    baud_rate: u32,
+}
+
+impl ReplayTransport {
+    /// Creates a new `ReplayTransport` that replays the given `records`
+    pub fn new(records: Vec<Record>) -> ReplayTransport {
+        let mut events: VecDeque<Event> = records.into_iter().map(|r| r.event).collect();
+
+        // The first event is the baud rate the port was opened with
+        let baud_rate = match events.front() {
+            Some(Event::SetBaudRate(baud_rate)) => {
+                let baud_rate = *baud_rate;
+                events.pop_front();
+                baud_rate
+            }
+            _ => super::BL602_BOOTROM_SERIAL_SETTINGS.baud_rate,
+        };
+
+        ReplayTransport { events, baud_rate }
+    }
+
+    /// Parses the transcript from `reader` and creates a new `ReplayTransport` that replays it
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<ReplayTransport, TraceError> {
+        Ok(ReplayTransport::new(parse_transcript(reader)?))
+    }
+
+    fn unexpected(&self, what: &str) -> io::Error {
+        let expected = match self.events.front() {
+            Some(Event::Write(data)) => format!("a write of {} bytes", data.len()),
+            Some(Event::Read(data)) => format!("a read of {} bytes", data.len()),
+            Some(Event::ReadTimeout) => "a read timeout".to_string(),
+            Some(Event::SetBaudRate(baud_rate)) => format!("a baud rate of {}", baud_rate),
+            Some(Event::ClearInput) => "the input to be cleared".to_string(),
+            None => "the end of the transcript".to_string(),
+        };
+
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Replay diverged: got {}, expected {}", what, expected),
+        )
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.events.front_mut() {
+            Some(Event::Read(data)) => {
+                let n = buf.len().min(data.len());
+
+                buf[..n].copy_from_slice(&data[..n]);
+                data.drain(..n);
+
+                if data.is_empty() {
+                    self.events.pop_front();
+                }
+
+                Ok(n)
+            }
+            Some(Event::ReadTimeout) => {
+                self.events.pop_front();
+
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Operation timed out",
+                ))
+            }
+            // The device won't reply until it has received what it expects
+            Some(Event::Write(_)) | None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            )),
+            Some(_) => Err(self.unexpected("a read")),
+        }
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.events.front_mut() {
+            Some(Event::Write(data)) => {
+                let n = buf.len().min(data.len());
+
+                if buf[..n] != data[..n] {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Replay diverged: wrote {:02x?}, expected {:02x?}",
+                            &buf[..n],
+                            &data[..n]
+                        ),
+                    ));
+                }
+
+                data.drain(..n);
+                n
+            }
+            _ => return Err(self.unexpected(&format!("a write of {} bytes", buf.len()))),
+        };
+
+        if let Some(Event::Write(data)) = self.events.front() {
+            if data.is_empty() {
+                self.events.pop_front();
+            }
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        match self.events.front() {
+            Some(Event::SetBaudRate(expected)) if *expected == baud_rate => {
+                self.events.pop_front();
+                self.baud_rate = baud_rate;
+
+                Ok(())
+            }
+            _ => Err(self.unexpected(&format!("a baud rate of {}", baud_rate))),
+        }
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        match self.events.front() {
+            Some(Event::ClearInput) => {
+                self.events.pop_front();
+
+                Ok(())
+            }
+            _ => Err(self.unexpected("the input to be cleared")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_annotate_commands_and_replies() {
+        let mut buf = vec![];
+        let mut writer = TraceWriter::new(&mut buf).unwrap();
+
+        let events = vec![
+            Event::Write(vec![0x55; 4]),
+            Event::Read(b"OK".to_vec()),
+            Event::Write(vec![0x31, 0x00, 0x06, 0x00]),
+            Event::Write(vec![0x10, 0x00]),
+            Event::Read(b"FL".to_vec()),
+            Event::ReadTimeout,
+        ];
+
+        for (n, event) in events.into_iter().enumerate() {
+            let timestamp = Duration::from_millis(n as u64);
+
+            writer.write_record(&Record { timestamp, event }).unwrap();
+        }
+
+        let transcript = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = transcript.lines().collect();
+
+        assert_eq!(lines[0], TRANSCRIPT_HEADER);
+        assert_eq!(lines[1], "0.000000 > 55 55 55 55 ; handshake");
+        assert_eq!(lines[2], "0.001000 < 4f 4b ; OK");
+        assert_eq!(lines[3], "0.002000 > 31 00 06 00 ; flash_write");
+        // Payload of the previous command
+        assert_eq!(lines[4], "0.003000 > 10 00");
+        assert_eq!(lines[5], "0.004000 < 46 4c ; FAIL");
+        assert_eq!(lines[6], "0.005000 ! ; timeout");
+    }
+
+    #[test]
+    fn it_should_parse_written_transcript() {
+        let records = vec![
+            Record {
+                timestamp: Duration::from_micros(0),
+                event: Event::SetBaudRate(500_000),
+            },
+            Record {
+                timestamp: Duration::from_micros(1500),
+                event: Event::Write(vec![0x10, 0x00, 0x00, 0x00]),
+            },
+            Record {
+                timestamp: Duration::from_micros(2_000_001),
+                event: Event::Read(b"OK".to_vec()),
+            },
+            Record {
+                timestamp: Duration::from_secs(3),
+                event: Event::ClearInput,
+            },
+        ];
+
+        let mut buf = vec![];
+        let mut writer = TraceWriter::new(&mut buf).unwrap();
+
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+
+        assert_eq!(parse_transcript(&buf[..]).unwrap(), records);
+    }
+
+    #[test]
+    fn it_should_reject_invalid_entries() {
+        let err = parse_transcript(&b"0.1 > 10 zz\n"[..]).unwrap_err();
+
+        assert!(matches!(err, TraceError::InvalidEntry(1, _)));
+    }
+
+    #[test]
+    fn it_should_detect_divergence_during_replay() {
+        let transcript = b"0.0 baud 500000\n0.1 > 10 00 00 00\n0.2 < 4f 4b\n";
+        let mut replay = ReplayTransport::from_reader(&transcript[..]).unwrap();
+
+        assert_eq!(Transport::baud_rate(&replay).unwrap(), 500_000);
+        assert!(replay.write_all(&[0x11, 0x00, 0x00, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use log::debug;
+use serialport::prelude::*;
+
+use super::BL602_BOOTROM_SERIAL_SETTINGS;
+use crate::error::SerialError;
+
+/// The byte stream that is used to communicate with the device
+///
+/// This is implemented for serial ports, but can also be implemented by anything else that can
+/// stand in for the device, like a recorded transcript
+pub trait Transport: Read + Write + Send {
+    /// Returns the current baud rate
+    fn baud_rate(&self) -> io::Result<u32>;
+
+    /// Sets the baud rate
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()>;
+
+    /// Sets the read timeout
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Discards any data that has been received but not yet read
+    fn clear_input(&mut self) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(SerialPort::baud_rate(self.as_ref())?)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        Ok(SerialPort::set_baud_rate(self.as_mut(), baud_rate)?)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self.as_mut(), timeout)?)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+}
+
+/// Opens the given serial `port` and configures it to use the communication settings expected by
+/// the BL60x bootrom
+pub fn open_serial<T: AsRef<OsStr> + ?Sized>(
+    port: &T,
+    baud_rate: usize,
+) -> Result<Box<dyn SerialPort>, SerialError> {
+    debug!("Opening serial port {:?}", port.as_ref());
+
+    let mut settings = BL602_BOOTROM_SERIAL_SETTINGS;
+    let timeout = Duration::from_millis(2000);
+
+    settings.baud_rate = baud_rate as u32;
+
+    debug!("Setting baud rate to {}", settings.baud_rate);
+    debug!("Setting timeout to {:?}", timeout);
+
+    serialport::open_with_settings(port, &settings)
+        .map_err(|err| SerialError::OpenError(port.as_ref().to_string_lossy().into_owned(), err))
+}
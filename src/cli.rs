@@ -1,22 +1,338 @@
+use std::num::ParseIntError;
 use std::path::PathBuf;
 
+use bouffalo_cli::bl::size_report::Budget;
+use bouffalo_cli::bl::{FlashClockType, PllClock, XtalType};
+use bouffalo_cli::device_tree;
 use structopt::StructOpt;
 
+/// The serial device to connect to when neither the command-line nor the profile has one
+const DEFAULT_SERIAL_PORT: &str = "/dev/ttyUSB0";
+
+/// The baud rate to use with the Boot ROM when neither the command-line nor the profile has one
+const DEFAULT_BAUD_RATE: usize = 500000;
+
+/// The baud rate to use with the eflash_loader when neither the command-line nor the profile has
+/// one
+const DEFAULT_PROGRAMMING_BAUD_RATE: usize = 2000000;
+
+/// Parses an address as either a hexadecimal number with a `0x` prefix or a decimal number
+fn parse_address(s: &str) -> Result<u32, ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Parses a property assignment like `/wifi/region/country_code=<86>`
+fn parse_assignment(s: &str) -> Result<(String, Vec<u8>), String> {
+    let mut parts = s.splitn(2, '=');
+    let path = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or("expected PATH=VALUE, i.e. /wifi/region/country_code=<86>")?;
+
+    if !path.starts_with('/') {
+        return Err(format!("the property path '{}' must start with /", path));
+    }
+
+    let value = device_tree::parse_value(value).map_err(|err| err.to_string())?;
+
+    Ok((path.to_string(), value))
+}
+
 #[derive(StructOpt, Debug)]
 pub enum Command {
     /// Get and print the bootrom info
     Info,
     /// Operate on the external flash
     Flash(FlashCommand),
-    /// Convert an elf image to a firmware image
+    /// Operate on firmware images
+    Image(ImageCommand),
+    /// Load images into RAM through the BootROM
+    Ram(RamCommand),
+    /// Convert an elf image, Intel HEX file, S-record file or raw binary to a firmware image
     #[structopt(name = "elf2image")]
     Elf2Image(Elf2ImageOpts),
+    /// Inspect elf images
+    Elf(ElfCommand),
+    /// Inspect and install the boot2 bootloader
+    Boot2(Boot2Command),
+    /// Compile, print and patch the device tree with the board and RF parameters
+    Dts(DtsCommand),
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ElfCommand {
+    /// Print the flash and RAM usage per region of the memory map, with the largest symbols in
+    /// each section
+    Size {
+        /// The elf filename, which defaults to the elf image of the profile
+        filename: Option<PathBuf>,
+        /// A previous build of the elf image to compare the usage against
+        #[structopt(long = "compare")]
+        compare: Option<PathBuf>,
+        /// The maximum usage of a region, i.e. ITCM=32K - exits with an error when exceeded, and
+        /// may be given multiple times - defaults to the budgets of the profile
+        #[structopt(long = "budget", number_of_values = 1)]
+        budgets: Vec<Budget>,
+        /// The number of largest symbols to print per section
+        #[structopt(long = "top", default_value = "5")]
+        top: usize,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Boot2Command {
+    /// Print the version and boot header of a boot2 image, and which firmware image boot2 boots
+    /// when given a whole-flash image
+    Info {
+        /// The boot2 image or whole-flash image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+    },
+    /// Write a boot2 image to the start of flash with the flash and clock configuration of the
+    /// board, keeping the partition table intact
+    Install {
+        /// The boot2 image, whole-flash image or raw boot2 binary filename, which defaults to the
+        /// boot2 image of the profile
+        filename: Option<PathBuf>,
+        /// The flash and clock configuration of the board, where the flash chip defaults to the
+        /// one on the device and the clocks to the ones of the boot2 image
+        #[structopt(flatten)]
+        board: BoardOpts,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DtsCommand {
+    /// Compile a device tree source file (.dts) to a device tree blob (.dtb)
+    Compile {
+        /// The device tree source filename, which defaults to the device tree source of the profile
+        filename: Option<PathBuf>,
+        /// The file to write the device tree blob to, which defaults to the source filename with
+        /// a .dtb extension
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Print the device tree of a device tree blob or whole-flash image as source
+    Decompile {
+        /// The device tree blob or whole-flash image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+    },
+    /// Change the factory parameters in a device tree blob, a whole-flash image, or the flash of
+    /// the connected device
+    Patch {
+        /// The device tree blob or whole-flash image filename
+        #[structopt(required_unless = "device")]
+        filename: Option<PathBuf>,
+        /// Patch the device tree in the flash of the connected device instead of a file
+        #[structopt(long = "device", conflicts_with_all = &["filename", "output"])]
+        device: bool,
+        /// The file to write the patched file to, which defaults to patching the file in place
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+        /// The crystal capacitance code (0-63) for both the crystal input and output
+        #[structopt(long = "xtal-capcode")]
+        xtal_capcode: Option<u32>,
+        /// Set every entry of the Wi-Fi power tables to this power, in dBm
+        #[structopt(long = "tx-power")]
+        tx_power: Option<u32>,
+        /// The country code, which is the international calling code, i.e. 86 for China
+        #[structopt(long = "country-code")]
+        country_code: Option<u32>,
+        /// Set a property to a value in source format, i.e. /wifi/mac/sta_mac_addr=[c8 43 57 82
+        /// 73 40] - may be given multiple times
+        #[structopt(long = "set", number_of_values = 1, parse(try_from_str = parse_assignment))]
+        properties: Vec<(String, Vec<u8>)>,
+    },
 }
 
 #[derive(StructOpt, Debug)]
 pub struct Elf2ImageOpts {
-    /// The elf filename
-    pub filename: PathBuf,
+    /// The elf, Intel HEX (.hex), S-record (.srec, .s19, .s28, .s37) or raw binary (.bin) filename,
+    /// which defaults to the elf image of the profile
+    pub filename: Option<PathBuf>,
+    /// The address to load a raw binary to
+    #[structopt(long = "base", parse(try_from_str = parse_address))]
+    pub base: Option<u32>,
+    /// The file to write the firmware image to, which defaults to the elf filename with a .bin
+    /// extension
+    #[structopt(short = "o", long = "output")]
+    pub output: Option<PathBuf>,
+    /// The flash and clock configuration of the board
+    #[structopt(flatten)]
+    pub board: BoardOpts,
+    /// Encrypt the image with AES-CTR using the key in this file, either as a hexadecimal string
+    /// or raw bytes - the key size (16, 24 or 32 bytes) determines the type of encryption
+    #[structopt(long = "aes-key", requires = "aes-iv")]
+    pub aes_key: Option<PathBuf>,
+    /// The AES IV to encrypt the image with, as 16 hexadecimal bytes
+    #[structopt(long = "aes-iv", requires = "aes-key")]
+    pub aes_iv: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BoardOpts {
+    /// The crystal on the board (none, 24M, 32M, 38.4M, 40M, 26M, RC32M)
+    #[structopt(long = "xtal")]
+    pub xtal: Option<XtalType>,
+    /// The system clock (RC32M, XTAL, 48M, 120M, 160M, 192M)
+    #[structopt(long = "pll")]
+    pub pll: Option<PllClock>,
+    /// The flash clock (120M, XTAL, 48M, 80M, BCLK, 96M)
+    #[structopt(long = "flash-clock")]
+    pub flash_clock: Option<FlashClockType>,
+    /// The flash chip, either by name (i.e. W25Q16) or by hexadecimal JEDEC ID (i.e. ef4015)
+    /// - elf2image defaults to the profile, then ef4015
+    #[structopt(long = "flash")]
+    pub flash: Option<String>,
+    /// Read the JEDEC ID of the flash chip from the connected device to pick its configuration
+    #[structopt(long = "detect-flash", conflicts_with = "flash")]
+    pub detect_flash: bool,
+    /// Take the flash and clock configuration from a vendor SDK configuration file, i.e.
+    /// efuse_bootheader_cfg.conf or flash_para/*.conf
+    #[structopt(long = "conf", conflicts_with_all = &["flash", "detect-flash"])]
+    pub conf: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ImageCommand {
+    /// Print the boot header and segments of a firmware image
+    Info {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// Decrypt an encrypted image with the AES key in this file
+        #[structopt(long = "aes-key")]
+        aes_key: Option<PathBuf>,
+    },
+    /// Print the flash, clock and boot configuration of a firmware image as a vendor SDK
+    /// configuration file
+    Conf {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+    },
+    /// Export or import the boot header of a firmware image as TOML or JSON
+    Header(HeaderCommand),
+    /// Sign a firmware image with an ECDSA secp256r1 private key for secure boot
+    Sign {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The PEM-encoded private key to sign the image with
+        #[structopt(long = "key", required = true)]
+        key: PathBuf,
+        /// The file to write the signed firmware image to
+        #[structopt(short = "o", long = "output", required = true)]
+        output: PathBuf,
+        /// The AES key of an encrypted image, which is needed to hash its segments
+        #[structopt(long = "aes-key")]
+        aes_key: Option<PathBuf>,
+    },
+    /// Verify the signature of a signed firmware image
+    Verify {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The PEM-encoded public key the image must be signed with
+        #[structopt(long = "pubkey")]
+        pubkey: Option<PathBuf>,
+        /// The AES key of an encrypted image, which is needed to hash its segments
+        #[structopt(long = "aes-key")]
+        aes_key: Option<PathBuf>,
+    },
+    /// Export a firmware image or a flash dump as an Intel HEX file, i.e. for an external
+    /// programmer
+    ExportHex {
+        /// The firmware image or flash dump filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The file to write the Intel HEX file to
+        #[structopt(short = "o", long = "output", required = true)]
+        output: PathBuf,
+        /// The flash offset of the first byte of the file
+        #[structopt(long = "address", default_value = "0", parse(try_from_str = parse_address))]
+        address: u32,
+        /// Export the segments of a firmware image at their load addresses instead of the file as
+        /// it's stored in flash
+        #[structopt(long = "segments", conflicts_with = "address")]
+        segments: bool,
+    },
+    /// Create an OTA update image (FW_OTA.bin) of a firmware partition image
+    Ota {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The software version of the firmware, up to 16 characters
+        #[structopt(long = "version", required = true)]
+        version: String,
+        /// Compress the firmware with xz
+        #[structopt(long = "compress")]
+        compress: bool,
+        /// The file to write the OTA image to, which defaults to FW_OTA.bin, or FW_OTA.bin.xz.ota
+        /// when compressed, next to the firmware image
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Print and verify the header and firmware image of an OTA update image
+    OtaInfo {
+        /// The OTA image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+    },
+    /// Compare two firmware images, or two whole-flash dumps partition by partition
+    Diff {
+        /// The first firmware image or flash dump
+        #[structopt(required = true)]
+        left: PathBuf,
+        /// The second firmware image or flash dump
+        #[structopt(required = true)]
+        right: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum RamCommand {
+    /// Load a firmware image, elf image, Intel HEX file, S-record file or raw binary into RAM and
+    /// run it
+    Run {
+        /// The filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The address to load a raw binary to
+        #[structopt(long = "base", parse(try_from_str = parse_address))]
+        base: Option<u32>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum HeaderCommand {
+    /// Export the boot header of a firmware image
+    Export {
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The file to write the boot header to - the format is determined by the extension
+        /// (.toml or .json), and it's written to stdout as TOML if omitted
+        #[structopt(short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Replace the boot header of a firmware image with an exported one and recompute the
+    /// checksums
+    Import {
+        /// The boot header filename - the format is determined by the extension (.toml or .json)
+        #[structopt(required = true)]
+        header: PathBuf,
+        /// The firmware image filename
+        #[structopt(required = true)]
+        filename: PathBuf,
+        /// The file to write the rebuilt firmware image to
+        #[structopt(short = "o", long = "output", required = true)]
+        output: PathBuf,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -35,15 +351,18 @@ pub enum FlashCommand {
     },
     /// Write external flash contents
     Write {
-        /// The name of the file to read from
+        /// The name of the file to read from - Intel HEX (.hex) and S-record (.srec, .s19, .s28,
+        /// .s37) files are written to the addresses of their records, which are flash offsets
         #[structopt(required = true)]
         filename: PathBuf,
-        /// Address offset of the flash medium
-        #[structopt(required = true)]
-        address: u32,
+        /// Address offset of the flash medium, which is required for raw binaries
+        #[structopt(parse(try_from_str = parse_address))]
+        address: Option<u32>,
         /// Size of the region to write
         size: Option<u32>,
     },
+    /// Read the JEDEC ID of the flash chip and print the matching flash configuration
+    Id,
     /// Erase flash contents
     Erase {
         /// The offset in flash to start erasing from, starting from 0
@@ -60,24 +379,52 @@ pub struct Opts {
     #[structopt(subcommand)]
     pub command: Command,
 
-    /// The serial device to connect to
-    #[structopt(
-        env = "SERIAL_PORT",
-        short = "p",
-        long = "port",
-        default_value = "/dev/ttyUSB0"
-    )]
-    pub serial_port: String,
-
-    /// The serial baud rate to use when communicating with the Boot ROM
-    #[structopt(
-        env = "BAUD_RATE",
-        short = "b",
-        long = "baud-rate",
-        default_value = "500000"
-    )]
-    pub baud_rate: usize,
-
-    #[structopt(long = "programming-baud-rate", default_value = "500000")]
-    pub programming_baud_rate: usize,
+    /// The board profile in Bouffalo.toml to take the settings from, which defaults to its
+    /// default-profile
+    #[structopt(long = "profile")]
+    pub profile: Option<String>,
+
+    /// The serial device to connect to [default: /dev/ttyUSB0]
+    #[structopt(env = "SERIAL_PORT", short = "p", long = "port")]
+    pub serial_port: Option<String>,
+
+    /// The serial baud rate to use when communicating with the Boot ROM [default: 500000]
+    #[structopt(env = "BAUD_RATE", short = "b", long = "baud-rate")]
+    pub baud_rate: Option<usize>,
+
+    /// The serial baud rate to switch to once the eflash_loader is running [default: 2000000]
+    ///
+    /// Lower baud rates are tried if the link isn't stable at this rate
+    #[structopt(long = "programming-baud-rate")]
+    pub programming_baud_rate: Option<usize>,
+
+    /// The number of times to retry a failed flash transfer before giving up
+    #[structopt(long = "retries", default_value = "3")]
+    pub retries: u32,
+
+    /// Record all communication with the device to this transcript file
+    #[structopt(long = "trace-file")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Replay a recorded transcript file instead of communicating with a device
+    #[structopt(long = "replay-file", conflicts_with = "trace-file")]
+    pub replay_file: Option<PathBuf>,
+}
+
+impl Opts {
+    /// Returns the serial device to connect to
+    pub fn serial_port(&self) -> &str {
+        self.serial_port.as_deref().unwrap_or(DEFAULT_SERIAL_PORT)
+    }
+
+    /// Returns the serial baud rate to use when communicating with the Boot ROM
+    pub fn baud_rate(&self) -> usize {
+        self.baud_rate.unwrap_or(DEFAULT_BAUD_RATE)
+    }
+
+    /// Returns the serial baud rate to switch to once the eflash_loader is running
+    pub fn programming_baud_rate(&self) -> usize {
+        self.programming_baud_rate
+            .unwrap_or(DEFAULT_PROGRAMMING_BAUD_RATE)
+    }
 }
@@ -0,0 +1,354 @@
+//! Board profiles in `Bouffalo.toml`
+//!
+//! The configuration file is looked up in the current directory and its parents, and in the user
+//! configuration directory. Profiles in the project configuration take precedence over the ones
+//! with the same name in the user configuration, and relative paths in a profile are relative to
+//! the file they're in.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bouffalo_cli::bl::size_report::Budget;
+use bouffalo_cli::bl::{FlashClockType, PllClock, XtalType};
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::cli::{BoardOpts, Boot2Command, Command, DtsCommand, ElfCommand, Opts};
+
+/// The name of the configuration file
+pub const CONFIG_FILE_NAME: &str = "Bouffalo.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read {}: {}", _0.display(), _1)]
+    IoError(PathBuf, io::Error),
+    #[error("Invalid configuration in {}: {}", _0.display(), _1)]
+    TomlError(PathBuf, toml::de::Error),
+    #[error("No profile named '{}' in {}", _0, CONFIG_FILE_NAME)]
+    UnknownProfile(String),
+}
+
+/// The settings of a board
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// The serial device to connect to
+    pub port: Option<String>,
+    /// The serial baud rate to use when communicating with the Boot ROM
+    pub baud_rate: Option<usize>,
+    /// The serial baud rate to switch to once the eflash_loader is running
+    pub programming_baud_rate: Option<usize>,
+    /// The crystal on the board
+    pub xtal: Option<XtalType>,
+    /// The system clock
+    pub pll: Option<PllClock>,
+    /// The flash clock
+    pub flash_clock: Option<FlashClockType>,
+    /// The flash chip, by name or by hexadecimal JEDEC ID
+    pub flash: Option<String>,
+    /// The vendor SDK configuration file with the flash and clock configuration
+    pub conf: Option<PathBuf>,
+    /// The elf image of the firmware, for `elf2image` and `elf size`
+    pub elf: Option<PathBuf>,
+    /// The boot2 image, for `boot2 install`
+    pub boot2: Option<PathBuf>,
+    /// The device tree source, for `dts compile`
+    pub dts: Option<PathBuf>,
+    /// The maximum usage of the regions of the memory map, for `elf size`
+    #[serde(default, deserialize_with = "from_str_all")]
+    pub budgets: Vec<Budget>,
+}
+
+impl Profile {
+    /// Fills in the settings and inputs that weren't given on the command-line, or in the
+    /// environment, with the ones of the profile
+    pub fn apply(&self, opts: &mut Opts) {
+        fill(&mut opts.serial_port, &self.port);
+        fill(&mut opts.baud_rate, &self.baud_rate);
+        fill(&mut opts.programming_baud_rate, &self.programming_baud_rate);
+
+        match opts.command {
+            Command::Elf2Image(ref mut elf2image_opts) => {
+                fill(&mut elf2image_opts.filename, &self.elf);
+                self.apply_board(&mut elf2image_opts.board);
+            }
+            Command::Elf(ElfCommand::Size {
+                ref mut filename,
+                ref mut budgets,
+                ..
+            }) => {
+                fill(filename, &self.elf);
+
+                if budgets.is_empty() {
+                    budgets.clone_from(&self.budgets);
+                }
+            }
+            Command::Boot2(Boot2Command::Install {
+                ref mut filename,
+                ref mut board,
+            }) => {
+                fill(filename, &self.boot2);
+                self.apply_board(board);
+            }
+            Command::Dts(DtsCommand::Compile {
+                ref mut filename, ..
+            }) => fill(filename, &self.dts),
+            _ => {}
+        }
+    }
+
+    /// Fills in the flash and clock configuration of the board
+    ///
+    /// The flash chip of the profile is only used when no flash chip, detection or configuration
+    /// file was given, as those conflict with each other.
+    fn apply_board(&self, board: &mut BoardOpts) {
+        fill(&mut board.xtal, &self.xtal);
+        fill(&mut board.pll, &self.pll);
+        fill(&mut board.flash_clock, &self.flash_clock);
+
+        if board.flash.is_none() && !board.detect_flash && board.conf.is_none() {
+            board.flash.clone_from(&self.flash);
+            board.conf.clone_from(&self.conf);
+        }
+    }
+
+    /// Makes the relative paths in the profile relative to `dir`
+    fn resolve_paths(&mut self, dir: &Path) {
+        for path in [
+            &mut self.conf,
+            &mut self.elf,
+            &mut self.boot2,
+            &mut self.dts,
+        ]
+        .iter_mut()
+        .filter_map(|path| path.as_mut())
+        {
+            if path.is_relative() {
+                *path = dir.join(&path);
+            }
+        }
+    }
+}
+
+/// The contents of one or more configuration files
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// The profile to use when none is given
+    pub default_profile: Option<String>,
+    /// The profiles by name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// Parses the configuration file `contents`, where relative paths are relative to `dir`
+    pub fn parse(contents: &str, dir: &Path) -> Result<Config, toml::de::Error> {
+        let mut config: Config = toml::from_str(contents)?;
+
+        for profile in config.profiles.values_mut() {
+            profile.resolve_paths(dir);
+        }
+
+        Ok(config)
+    }
+
+    /// Reads the configuration file at `path`
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::IoError(path.to_path_buf(), err))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        Config::parse(&contents, dir).map_err(|err| ConfigError::TomlError(path.to_path_buf(), err))
+    }
+
+    /// Reads the project configuration in `dir` or the closest parent that has one, and the user
+    /// configuration, where either may be missing
+    pub fn load(dir: &Path) -> Result<Config, ConfigError> {
+        let project = dir
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file());
+        let user = user_config_path().filter(|path| path.is_file());
+
+        let mut config = Config::default();
+
+        for path in user.iter().chain(project.iter()) {
+            config.merge(Config::from_file(path)?);
+        }
+
+        Ok(config)
+    }
+
+    /// Merges `other` into this configuration, where the default profile and the profiles of
+    /// `other` take precedence
+    pub fn merge(&mut self, other: Config) {
+        if other.default_profile.is_some() {
+            self.default_profile = other.default_profile;
+        }
+
+        self.profiles.extend(other.profiles);
+    }
+
+    /// Returns the profile with the given `name`, or the default profile if there's no `name`
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>, ConfigError> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .map(Some)
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the path of the user configuration file, which is in `$XDG_CONFIG_HOME` or
+/// `~/.config`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(config_dir.join("bouffalo-cli").join(CONFIG_FILE_NAME))
+}
+
+/// Sets `value` to `default` if it has no value
+fn fill<T: Clone>(value: &mut Option<T>, default: &Option<T>) {
+    if value.is_none() {
+        value.clone_from(default);
+    }
+}
+
+/// Deserializes a list of values from strings, in the same format as on the command-line
+fn from_str_all<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT_CONFIG: &str = r#"
+        default-profile = "devkit"
+
+        [profiles.devkit]
+        port = "/dev/ttyUSB1"
+        baud-rate = 115200
+        programming-baud-rate = 1000000
+        xtal = "40M"
+        flash = "W25Q16"
+        elf = "build_out/app.elf"
+        budgets = ["ITCM=32K", "XIP flash=1M"]
+
+        [profiles.module]
+        port = "/dev/ttyACM0"
+        flash-clock = "48M"
+        conf = "/opt/bl602/efuse_bootheader_cfg.conf"
+    "#;
+
+    #[test]
+    fn it_should_parse_profiles() {
+        let config = Config::parse(PROJECT_CONFIG, Path::new("/project")).unwrap();
+        let devkit = config.profile(None).unwrap().unwrap();
+
+        assert_eq!(devkit.port.as_deref(), Some("/dev/ttyUSB1"));
+        assert_eq!(devkit.baud_rate, Some(115200));
+        assert_eq!(devkit.xtal, Some(XtalType::Xtal40M));
+        assert_eq!(
+            devkit.elf.as_deref(),
+            Some(Path::new("/project/build_out/app.elf"))
+        );
+        assert_eq!(devkit.budgets.len(), 2);
+
+        let module = config.profile(Some("module")).unwrap().unwrap();
+
+        assert_eq!(module.flash_clock, Some(FlashClockType::Pll48M));
+        assert_eq!(
+            module.conf.as_deref(),
+            Some(Path::new("/opt/bl602/efuse_bootheader_cfg.conf"))
+        );
+        assert!(matches!(
+            config.profile(Some("missing")),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+    }
+
+    #[test]
+    fn it_should_prefer_the_project_config() {
+        let mut config = Config::parse(
+            r#"
+                default-profile = "module"
+
+                [profiles.module]
+                port = "/dev/ttyUSB9"
+
+                [profiles.personal]
+                port = "/dev/ttyUSB2"
+            "#,
+            Path::new("/home/user/.config/bouffalo-cli"),
+        )
+        .unwrap();
+
+        config.merge(Config::parse(PROJECT_CONFIG, Path::new("/project")).unwrap());
+
+        assert_eq!(config.default_profile.as_deref(), Some("devkit"));
+        assert_eq!(
+            config
+                .profile(Some("module"))
+                .unwrap()
+                .unwrap()
+                .port
+                .as_deref(),
+            Some("/dev/ttyACM0")
+        );
+        assert!(config.profile(Some("personal")).unwrap().is_some());
+        assert!(Config::parse("[profiles.devkit]\nxtal = \"41M\"", Path::new("")).is_err());
+        assert!(Config::parse("[profiles.devkit]\nbaudrate = 1", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn it_should_only_fill_in_missing_options() {
+        use structopt::StructOpt;
+
+        let config = Config::parse(PROJECT_CONFIG, Path::new("/project")).unwrap();
+        let devkit = config.profile(None).unwrap().unwrap();
+        let mut opts = Opts::from_iter_safe(&[
+            "bouffalo-cli",
+            "--baud-rate",
+            "230400",
+            "elf2image",
+            "--detect-flash",
+        ])
+        .unwrap();
+
+        devkit.apply(&mut opts);
+
+        assert_eq!(opts.baud_rate(), 230400);
+        assert_eq!(opts.programming_baud_rate(), 1000000);
+
+        match opts.command {
+            Command::Elf2Image(ref elf2image_opts) => {
+                assert_eq!(
+                    elf2image_opts.filename.as_deref(),
+                    Some(Path::new("/project/build_out/app.elf"))
+                );
+                assert_eq!(elf2image_opts.board.xtal, Some(XtalType::Xtal40M));
+                assert_eq!(elf2image_opts.board.flash, None);
+            }
+            _ => panic!("expected elf2image"),
+        }
+    }
+}
@@ -0,0 +1,584 @@
+//! Flattened device trees (DTB) and their source format (DTS)
+//!
+//! The BL602 firmware reads the board and RF parameters from a device tree blob in flash, so the
+//! tree is kept as it's stored: nodes with their properties as raw bytes, in the order they're
+//! defined. The blob is written the same way as `dtc` writes it.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use thiserror::Error;
+
+mod dts;
+
+pub use dts::parse_value;
+
+/// The magic value at the start of a device tree blob
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// The version of the device tree blobs that are written
+const FDT_VERSION: u32 = 17;
+
+/// The oldest version that the written device tree blobs are compatible with
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+/// The length of the header of a device tree blob
+pub const FDT_HEADER_LEN: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum DeviceTreeError {
+    #[error("The file is too short to be a device tree blob ({} bytes)", _0)]
+    MissingHeader(usize),
+    #[error("Invalid magic value {:#010x}, expected {:#010x}", _0, FDT_MAGIC)]
+    InvalidMagic(u32),
+    #[error("Unsupported device tree blob version {}", _0)]
+    UnsupportedVersion(u32),
+    #[error("The {} block is outside of the blob", _0)]
+    TruncatedBlock(&'static str),
+    #[error(
+        "Unexpected token {:#x} at offset {:#x} of the structure block",
+        _0,
+        _1
+    )]
+    InvalidToken(u32, usize),
+    #[error("Invalid property name offset {:#x}", _0)]
+    InvalidNameOffset(u32),
+    #[error("Syntax error on line {}: {}", _0, _1)]
+    SyntaxError(usize, String),
+    #[error("No node at {}", _0)]
+    NodeNotFound(String),
+    #[error("No property at {}", _0)]
+    PropertyNotFound(String),
+}
+
+/// A property of a node
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Property {
+    /// The name of the property
+    pub name: String,
+    /// The value of the property
+    pub value: Vec<u8>,
+}
+
+impl Property {
+    /// Returns the value as big-endian 32-bit cells, if the length is a multiple of 4
+    pub fn cells(&self) -> Option<Vec<u32>> {
+        if self.value.len() % 4 != 0 {
+            return None;
+        }
+
+        Some(
+            self.value
+                .chunks(4)
+                .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Returns the value as NUL-terminated strings, if it's a list of printable strings
+    pub fn strings(&self) -> Option<Vec<&str>> {
+        let value = self.value.strip_suffix(&[0])?;
+        let strings: Vec<&str> = value
+            .split(|&b| b == 0)
+            .map(std::str::from_utf8)
+            .collect::<Result<_, _>>()
+            .ok()?;
+
+        let printable = |s: &&str| !s.is_empty() && s.chars().all(|c| (' '..='~').contains(&c));
+
+        if strings.iter().all(printable) {
+            Some(strings)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns `cells` as a big-endian property value
+pub fn cells_value(cells: &[u32]) -> Vec<u8> {
+    cells.iter().flat_map(|cell| cell.to_be_bytes()).collect()
+}
+
+/// A node in the device tree
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct Node {
+    /// The name of the node, including the unit address, which is empty for the root node
+    pub name: String,
+    /// The properties of the node
+    pub properties: Vec<Property>,
+    /// The child nodes
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Creates an empty node with the given `name`
+    pub fn new<S: Into<String>>(name: S) -> Node {
+        Node {
+            name: name.into(),
+            ..Node::default()
+        }
+    }
+
+    /// Returns the property with the given `name`
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    /// Returns the child node with the given `name`
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|n| n.name == name)
+    }
+
+    /// Sets the property `name` to `value`, adding it if it doesn't exist
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|p| p.name == name) {
+            Some(property) => property.value = value,
+            None => self.properties.push(Property {
+                name: name.to_string(),
+                value,
+            }),
+        }
+    }
+
+    /// Merges `other` into this node, where the properties and children of `other` take
+    /// precedence, like when a node is defined twice in a source file
+    fn merge(&mut self, other: Node) {
+        for property in other.properties {
+            self.set_property(&property.name, property.value);
+        }
+
+        for child in other.children {
+            match self.children.iter_mut().find(|n| n.name == child.name) {
+                Some(existing) => existing.merge(child),
+                None => self.children.push(child),
+            }
+        }
+    }
+}
+
+/// A device tree
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct DeviceTree {
+    /// The memory reservations as `(address, size)` pairs
+    pub reservations: Vec<(u64, u64)>,
+    /// The physical ID of the CPU that boots
+    pub boot_cpuid: u32,
+    /// The root node
+    pub root: Node,
+}
+
+impl DeviceTree {
+    /// Parses a device tree blob
+    pub fn from_bytes(data: &[u8]) -> Result<DeviceTree, DeviceTreeError> {
+        let total_size = DeviceTree::total_size(data)? as usize;
+        let word = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let version = word(0x14);
+        let last_comp_version = word(0x18);
+
+        if version < FDT_LAST_COMP_VERSION || last_comp_version > FDT_VERSION {
+            return Err(DeviceTreeError::UnsupportedVersion(version));
+        }
+
+        let data = data
+            .get(..total_size)
+            .ok_or(DeviceTreeError::TruncatedBlock("device tree"))?;
+
+        let block = |name: &'static str, offset: usize, len: Option<usize>| {
+            let end = match len {
+                Some(len) => offset.checked_add(len),
+                None => Some(data.len()),
+            };
+
+            end.and_then(|end| data.get(offset..end))
+                .ok_or(DeviceTreeError::TruncatedBlock(name))
+        };
+
+        // Version 16 has no size of the structure block, so it ends where the blob ends
+        let struct_len = if version >= 17 {
+            Some(word(0x24) as usize)
+        } else {
+            None
+        };
+
+        let structure = block("structure", word(0x08) as usize, struct_len)?;
+        let strings = block("strings", word(0x0c) as usize, Some(word(0x20) as usize))?;
+        let reservations = block("memory reservation", word(0x10) as usize, None)?;
+
+        let mut tree = DeviceTree {
+            boot_cpuid: word(0x1c),
+            ..DeviceTree::default()
+        };
+
+        // Read the memory reservations up until the terminating empty entry
+        for entry in reservations.chunks(16) {
+            if entry.len() < 16 {
+                return Err(DeviceTreeError::TruncatedBlock("memory reservation"));
+            }
+
+            let address = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+            let size = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+
+            if address == 0 && size == 0 {
+                break;
+            }
+
+            tree.reservations.push((address, size));
+        }
+
+        let mut reader = StructReader {
+            structure,
+            strings,
+            pos: 0,
+        };
+
+        // The structure block starts with the root node
+        match reader.token()? {
+            (FDT_BEGIN_NODE, _) => tree.root = reader.node()?,
+            (token, offset) => return Err(DeviceTreeError::InvalidToken(token, offset)),
+        }
+
+        match reader.token()? {
+            (FDT_END, _) => Ok(tree),
+            (token, offset) => Err(DeviceTreeError::InvalidToken(token, offset)),
+        }
+    }
+
+    /// Returns the size of the device tree blob at the start of `data` from its header
+    pub fn total_size(data: &[u8]) -> Result<u32, DeviceTreeError> {
+        if data.len() < FDT_HEADER_LEN {
+            return Err(DeviceTreeError::MissingHeader(data.len()));
+        }
+
+        let magic = u32::from_be_bytes(data[0..4].try_into().unwrap());
+
+        if magic != FDT_MAGIC {
+            return Err(DeviceTreeError::InvalidMagic(magic));
+        }
+
+        Ok(u32::from_be_bytes(data[4..8].try_into().unwrap()))
+    }
+
+    /// Returns whether `data` starts with a device tree blob
+    pub fn is_device_tree(data: &[u8]) -> bool {
+        DeviceTree::total_size(data).is_ok()
+    }
+
+    /// Compiles device tree source
+    pub fn from_source(source: &str) -> Result<DeviceTree, DeviceTreeError> {
+        dts::parse(source)
+    }
+
+    /// Returns the device tree as a blob
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure = vec![];
+        let mut strings = vec![];
+
+        write_node(&self.root, &mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let mut reservations = vec![];
+
+        for &(address, size) in self.reservations.iter().chain(&[(0, 0)]) {
+            reservations.extend_from_slice(&address.to_be_bytes());
+            reservations.extend_from_slice(&size.to_be_bytes());
+        }
+
+        let reservations_offset = FDT_HEADER_LEN;
+        let struct_offset = reservations_offset + reservations.len();
+        let strings_offset = struct_offset + structure.len();
+        let total_size = strings_offset + strings.len();
+
+        let mut buf = Vec::with_capacity(total_size);
+
+        for word in &[
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            reservations_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            buf.extend_from_slice(&word.to_be_bytes());
+        }
+
+        buf.extend_from_slice(&reservations);
+        buf.extend_from_slice(&structure);
+        buf.extend_from_slice(&strings);
+
+        buf
+    }
+
+    /// Returns the node at `path`, i.e. `/wifi/brd_rf`
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    /// Returns the node at `path` for modification
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&mut self.root, |node, name| {
+                node.children.iter_mut().find(|n| n.name == name)
+            })
+    }
+
+    /// Returns the property at `path`, i.e. `/wifi/brd_rf/xtal`
+    pub fn property(&self, path: &str) -> Option<&Property> {
+        let (node, name) = split_path(path);
+
+        self.node(node).and_then(|node| node.property(name))
+    }
+
+    /// Sets the property at `path` to `value`, adding the property if the node exists
+    pub fn set_property(&mut self, path: &str, value: Vec<u8>) -> Result<(), DeviceTreeError> {
+        let (node, name) = split_path(path);
+
+        self.node_mut(node)
+            .ok_or_else(|| DeviceTreeError::NodeNotFound(node.to_string()))?
+            .set_property(name, value);
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceTree {
+    /// Formats the device tree as source
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        dts::write(self, f)
+    }
+}
+
+/// Splits `path` into the path of the node and the name of the property
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Reads the tokens of the structure block
+struct StructReader<'a> {
+    structure: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StructReader<'a> {
+    /// Returns the next token that isn't a NOP and its offset
+    fn token(&mut self) -> Result<(u32, usize), DeviceTreeError> {
+        loop {
+            let offset = self.pos;
+            let token = self.u32()?;
+
+            if token != FDT_NOP {
+                return Ok((token, offset));
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DeviceTreeError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DeviceTreeError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.structure.get(self.pos..end))
+            .ok_or(DeviceTreeError::TruncatedBlock("structure"))?;
+
+        // Everything in the structure block is aligned to 4 bytes
+        self.pos += (len + 3) & !3;
+
+        Ok(bytes)
+    }
+
+    /// Reads the node that follows a `FDT_BEGIN_NODE` token, up to and including its
+    /// `FDT_END_NODE` token
+    fn node(&mut self) -> Result<Node, DeviceTreeError> {
+        let rest = self
+            .structure
+            .get(self.pos..)
+            .ok_or(DeviceTreeError::TruncatedBlock("structure"))?;
+        let name_len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(DeviceTreeError::TruncatedBlock("structure"))?;
+        let name = String::from_utf8_lossy(self.bytes(name_len + 1)?[..name_len].as_ref());
+        let mut node = Node::new(name);
+
+        loop {
+            match self.token()? {
+                (FDT_PROP, _) => {
+                    let len = self.u32()? as usize;
+                    let name_offset = self.u32()?;
+                    let value = self.bytes(len)?.to_vec();
+
+                    node.properties.push(Property {
+                        name: self.string(name_offset)?,
+                        value,
+                    });
+                }
+                (FDT_BEGIN_NODE, _) => {
+                    let child = self.node()?;
+                    node.children.push(child);
+                }
+                (FDT_END_NODE, _) => return Ok(node),
+                (token, offset) => return Err(DeviceTreeError::InvalidToken(token, offset)),
+            }
+        }
+    }
+
+    /// Returns the property name at `offset` of the strings block
+    fn string(&self, offset: u32) -> Result<String, DeviceTreeError> {
+        let rest = self
+            .strings
+            .get(offset as usize..)
+            .ok_or(DeviceTreeError::InvalidNameOffset(offset))?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(DeviceTreeError::InvalidNameOffset(offset))?;
+
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+/// Writes `node` to the structure block, adding the property names to the strings block
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    write_padded(structure, node.name.as_bytes(), true);
+
+    for property in &node.properties {
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&(property.value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&string_offset(strings, &property.name).to_be_bytes());
+        write_padded(structure, &property.value, false);
+    }
+
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+}
+
+/// Writes `data`, optionally NUL-terminated, padded with zeros to a multiple of 4 bytes
+fn write_padded(buf: &mut Vec<u8>, data: &[u8], nul_terminated: bool) {
+    buf.extend_from_slice(data);
+
+    if nul_terminated {
+        buf.push(0);
+    }
+
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+/// Returns the offset of `name` in the strings block, adding it if it isn't there
+///
+/// Like `dtc`, a name that is the end of another name reuses it.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut needle = name.as_bytes().to_vec();
+    needle.push(0);
+
+    let offset = strings
+        .windows(needle.len())
+        .position(|window| window == &needle[..]);
+
+    match offset {
+        Some(offset) => offset as u32,
+        None => {
+            let offset = strings.len();
+            strings.extend_from_slice(&needle);
+            offset as u32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE_FIRMWARE: &[u8] =
+        include_bytes!("../test/whole_dts40M_pt2M_boot2release_ef7015.bin");
+
+    /// The offset of the device tree in the factory partition of the reference firmware
+    const REFERENCE_DTB_OFFSET: usize = 0x1f8000;
+
+    fn reference_dtb() -> &'static [u8] {
+        let data = &REFERENCE_FIRMWARE[REFERENCE_DTB_OFFSET..];
+        let len = DeviceTree::total_size(data).unwrap() as usize;
+
+        &data[..len]
+    }
+
+    #[test]
+    fn it_should_parse_the_reference_device_tree() {
+        let tree = DeviceTree::from_bytes(reference_dtb()).unwrap();
+
+        assert_eq!(
+            tree.property("/model").unwrap().strings(),
+            Some(vec!["bl bl602 AVB board"])
+        );
+        assert_eq!(
+            tree.property("/wifi/brd_rf/xtal").unwrap().cells(),
+            Some(vec![36, 36, 0, 60, 60])
+        );
+        assert_eq!(
+            tree.property("/wifi/mac/sta_mac_addr").unwrap().value,
+            [0xc8, 0x43, 0x57, 0x82, 0x73, 0x40]
+        );
+        assert!(tree.node("/i2c/i2c@40011000/pin").is_some());
+    }
+
+    #[test]
+    fn it_should_write_the_reference_device_tree_like_dtc() {
+        let tree = DeviceTree::from_bytes(reference_dtb()).unwrap();
+
+        assert_eq!(tree.to_bytes(), reference_dtb());
+
+        // Compiling the source of the tree should give the same blob
+        let source = tree.to_string();
+
+        assert_eq!(
+            DeviceTree::from_source(&source).unwrap().to_bytes(),
+            reference_dtb()
+        );
+    }
+
+    #[test]
+    fn it_should_set_properties() {
+        let mut tree = DeviceTree::from_bytes(reference_dtb()).unwrap();
+
+        tree.set_property("/wifi/region/country_code", cells_value(&[1]))
+            .unwrap();
+        tree.set_property("/wifi/region/new", vec![]).unwrap();
+
+        assert_eq!(
+            tree.set_property("/wifi/missing/new", vec![]),
+            Err(DeviceTreeError::NodeNotFound("/wifi/missing".to_string()))
+        );
+
+        let tree = DeviceTree::from_bytes(&tree.to_bytes()).unwrap();
+        let region = tree.node("/wifi/region").unwrap();
+
+        assert_eq!(
+            region.property("country_code").unwrap().cells(),
+            Some(vec![1])
+        );
+        assert_eq!(region.property("new").unwrap().value, Vec::<u8>::new());
+    }
+}
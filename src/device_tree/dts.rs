@@ -0,0 +1,463 @@
+//! Compiling and printing device tree source
+//!
+//! This supports the subset of the source format that the vendor board files use: nodes,
+//! properties with strings, cells and byte strings, labels, memory reservations and comments.
+//! References, includes and the `/delete-*/` directives aren't supported.
+
+use std::fmt;
+
+use super::{DeviceTree, DeviceTreeError, Node, Property};
+
+/// Compiles the device tree `source`
+pub fn parse(source: &str) -> Result<DeviceTree, DeviceTreeError> {
+    let mut parser = Parser::new(source);
+    let mut tree = DeviceTree::default();
+    let mut has_root = false;
+
+    parser.keyword("/dts-v1/")?;
+    parser.expect(b';')?;
+
+    loop {
+        match parser.peek() {
+            None => break,
+            Some(b'/') if parser.is_keyword("/memreserve/") => {
+                parser.keyword("/memreserve/")?;
+
+                let address = parser.number()?;
+                let size = parser.number()?;
+
+                parser.expect(b';')?;
+                tree.reservations.push((address, size));
+            }
+            Some(b'/') if parser.is_keyword("/ ") || parser.is_keyword("/{") => {
+                parser.pos += 1;
+
+                // A node that's defined more than once is merged, the root node included
+                let root = parser.node_body(Node::new(""))?;
+                tree.root.merge(root);
+                has_root = true;
+            }
+            Some(_) => return Err(parser.error("expected a root node or /memreserve/")),
+        }
+    }
+
+    if !has_root {
+        return Err(parser.error("there's no root node"));
+    }
+
+    Ok(tree)
+}
+
+/// Compiles a property value like `<0x1 2>`, `"okay"` or `[c8 43 57]`, or a list of them
+/// separated by commas
+pub fn parse_value(source: &str) -> Result<Vec<u8>, DeviceTreeError> {
+    let mut parser = Parser::new(source);
+    let value = parser.value()?;
+
+    match parser.peek() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("unexpected text after the value")),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Parser<'a> {
+        Parser {
+            src: source.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    /// Returns a syntax error at the current line
+    fn error<S: Into<String>>(&self, message: S) -> DeviceTreeError {
+        let line = self.src[..self.pos].iter().filter(|&&b| b == b'\n').count() + 1;
+
+        DeviceTreeError::SyntaxError(line, message.into())
+    }
+
+    /// Skips whitespace and comments
+    fn skip(&mut self) {
+        loop {
+            let rest = &self.src[self.pos..];
+
+            if rest.first().is_some_and(u8::is_ascii_whitespace) {
+                self.pos += 1;
+            } else if rest.starts_with(b"//") {
+                self.pos += rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            } else if rest.starts_with(b"/*") {
+                self.pos += rest
+                    .windows(2)
+                    .position(|w| w == b"*/")
+                    .map_or(rest.len(), |end| end + 2);
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Returns the next character that isn't whitespace or part of a comment
+    fn peek(&mut self) -> Option<u8> {
+        self.skip();
+        self.src.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), DeviceTreeError> {
+        match self.peek() {
+            Some(next) if next == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error(format!("expected '{}'", c as char))),
+        }
+    }
+
+    fn is_keyword(&mut self, keyword: &str) -> bool {
+        self.skip();
+        self.src[self.pos..].starts_with(keyword.as_bytes())
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), DeviceTreeError> {
+        if !self.is_keyword(keyword) {
+            return Err(self.error(format!("expected {}", keyword)));
+        }
+
+        self.pos += keyword.len();
+
+        Ok(())
+    }
+
+    /// Returns the name of a node, property or label
+    fn name(&mut self) -> Result<String, DeviceTreeError> {
+        self.skip();
+
+        let is_name_char = |c: &u8| c.is_ascii_alphanumeric() || b",._+*#?@-".contains(c);
+        let len = self.src[self.pos..]
+            .iter()
+            .take_while(|c| is_name_char(c))
+            .count();
+
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+
+        let name = String::from_utf8_lossy(&self.src[self.pos..self.pos + len]).into_owned();
+        self.pos += len;
+
+        Ok(name)
+    }
+
+    /// Parses the properties and children of a node, from the opening brace up to and including
+    /// the semicolon after the closing brace
+    fn node_body(&mut self, mut node: Node) -> Result<Node, DeviceTreeError> {
+        self.expect(b'{')?;
+
+        loop {
+            match self.peek() {
+                Some(b'}') => break,
+                Some(b'/') | Some(b'&') => {
+                    return Err(self.error("directives and references aren't supported"))
+                }
+                Some(_) => {}
+                None => return Err(self.error("expected '}'")),
+            }
+
+            let mut name = self.name()?;
+
+            // Labels can only be referenced, which isn't supported, so they're skipped
+            if self.src.get(self.pos) == Some(&b':') {
+                self.pos += 1;
+                name = self.name()?;
+            }
+
+            match self.peek() {
+                Some(b'{') => {
+                    let child = self.node_body(Node::new(name))?;
+                    node.merge(Node {
+                        children: vec![child],
+                        ..Node::default()
+                    });
+                }
+                Some(b'=') => {
+                    self.pos += 1;
+
+                    let value = self.value()?;
+                    self.expect(b';')?;
+                    node.set_property(&name, value);
+                }
+                Some(b';') => {
+                    self.pos += 1;
+                    node.set_property(&name, vec![]);
+                }
+                _ => return Err(self.error(format!("expected '{{', '=' or ';' after {}", name))),
+            }
+        }
+
+        self.expect(b'}')?;
+        self.expect(b';')?;
+
+        Ok(node)
+    }
+
+    /// Parses a property value, which is a list of strings, cells and byte strings separated
+    /// by commas
+    fn value(&mut self) -> Result<Vec<u8>, DeviceTreeError> {
+        let mut value = vec![];
+
+        loop {
+            match self.peek() {
+                Some(b'"') => self.string(&mut value)?,
+                Some(b'<') => self.cells(&mut value)?,
+                Some(b'[') => self.bytes(&mut value)?,
+                _ => return Err(self.error("expected a string, <cells> or [bytes]")),
+            }
+
+            if self.peek() != Some(b',') {
+                return Ok(value);
+            }
+
+            self.pos += 1;
+        }
+    }
+
+    fn string(&mut self, value: &mut Vec<u8>) -> Result<(), DeviceTreeError> {
+        self.expect(b'"')?;
+
+        loop {
+            let c = match self.src.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+
+            self.pos += 1;
+
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.src.get(self.pos) {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(b'0') => 0,
+                        Some(&c @ b'\\') | Some(&c @ b'"') | Some(&c @ b'\'') => c,
+                        _ => return Err(self.error("unsupported escape sequence")),
+                    };
+
+                    self.pos += 1;
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+
+        value.push(0);
+
+        Ok(())
+    }
+
+    fn cells(&mut self, value: &mut Vec<u8>) -> Result<(), DeviceTreeError> {
+        self.expect(b'<')?;
+
+        while self.peek() != Some(b'>') {
+            let number = self.number()?;
+
+            if number > u32::MAX as u64 {
+                return Err(self.error(format!("{:#x} doesn't fit in a cell", number)));
+            }
+
+            value.extend_from_slice(&(number as u32).to_be_bytes());
+        }
+
+        self.expect(b'>')
+    }
+
+    fn bytes(&mut self, value: &mut Vec<u8>) -> Result<(), DeviceTreeError> {
+        self.expect(b'[')?;
+
+        // The bytes may or may not be separated by whitespace
+        while self.peek() != Some(b']') {
+            let digits = self
+                .src
+                .get(self.pos..self.pos + 2)
+                .and_then(|digits| std::str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+            match digits {
+                Some(byte) => value.push(byte),
+                None => return Err(self.error("expected two hexadecimal digits")),
+            }
+
+            self.pos += 2;
+        }
+
+        self.expect(b']')
+    }
+
+    /// Parses a hexadecimal, octal or decimal number
+    fn number(&mut self) -> Result<u64, DeviceTreeError> {
+        self.skip();
+
+        let len = self.src[self.pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .count();
+        let token = std::str::from_utf8(&self.src[self.pos..self.pos + len]).unwrap();
+
+        let number = if let Some(hex) = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+        {
+            u64::from_str_radix(hex, 16)
+        } else if token.len() > 1 && token.starts_with('0') {
+            u64::from_str_radix(&token[1..], 8)
+        } else {
+            token.parse()
+        };
+
+        let number = number.map_err(|_| self.error(format!("invalid number '{}'", token)))?;
+        self.pos += len;
+
+        Ok(number)
+    }
+}
+
+/// Writes `tree` as source, in the same style as `dtc`
+pub fn write(tree: &DeviceTree, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "/dts-v1/;")?;
+    writeln!(f)?;
+
+    for (address, size) in &tree.reservations {
+        writeln!(f, "/memreserve/ {:#018x} {:#018x};", address, size)?;
+    }
+
+    write_node(&tree.root, "/", 0, f)
+}
+
+fn write_node(node: &Node, name: &str, depth: usize, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let indent = "\t".repeat(depth);
+
+    writeln!(f, "{}{} {{", indent, name)?;
+
+    for property in &node.properties {
+        writeln!(f, "{}\t{};", indent, property)?;
+    }
+
+    for child in &node.children {
+        writeln!(f)?;
+        write_node(child, &child.name, depth + 1, f)?;
+    }
+
+    writeln!(f, "{}}};", indent)
+}
+
+impl fmt::Display for Property {
+    /// Formats the property like it's defined in source, without the semicolon
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        write_value(self, f)
+    }
+}
+
+/// Writes the value of `property` as strings, cells or bytes, whichever it looks like
+fn write_value(property: &Property, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if property.value.is_empty() {
+        return Ok(());
+    }
+
+    write!(f, " = ")?;
+
+    if let Some(strings) = property.strings() {
+        let strings: Vec<String> = strings
+            .iter()
+            .map(|s| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+
+        return write!(f, "{}", strings.join(", "));
+    }
+
+    if let Some(cells) = property.cells() {
+        let cells: Vec<String> = cells.iter().map(|cell| format!("{:#x}", cell)).collect();
+
+        return write!(f, "<{}>", cells.join(" "));
+    }
+
+    let bytes: Vec<String> = property
+        .value
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    write!(f, "[{}]", bytes.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_compile_vendor_style_source() {
+        let source = r#"
+            /dts-v1/;
+            // The board
+            / {
+                model = "bl bl602 AVB board";
+                compatible = "bl,bl602-sample", "bl,bl602-common";
+                #address-cells = <0x1>;
+                wifi {
+                    /* RF parameters */
+                    region {
+                        country_code = <86>;
+                    };
+                    mac {
+                        sta_mac_addr = [C8 43 57 82 73 40];
+                        ap_mac_addr = [c84357827302];
+                    };
+                    label: brd_rf {
+                        xtal = <36 36 0 60 60>;
+                        mixed = "a", <0x1>, [ff];
+                        enabled;
+                    };
+                };
+            };
+        "#;
+
+        let tree = parse(source).unwrap();
+
+        assert_eq!(
+            tree.property("/compatible").unwrap().value,
+            b"bl,bl602-sample\0bl,bl602-common\0"
+        );
+        assert_eq!(
+            tree.property("/wifi/region/country_code").unwrap().cells(),
+            Some(vec![86])
+        );
+        assert_eq!(
+            tree.property("/wifi/mac/ap_mac_addr").unwrap().value,
+            [0xc8, 0x43, 0x57, 0x82, 0x73, 0x02]
+        );
+        assert_eq!(
+            tree.property("/wifi/brd_rf/mixed").unwrap().value,
+            [b'a', 0, 0, 0, 0, 1, 0xff]
+        );
+        assert_eq!(
+            tree.property("/wifi/brd_rf/enabled").unwrap().value,
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn it_should_report_syntax_errors_with_the_line() {
+        let source = "/dts-v1/;\n/ {\n\tmodel = \"board\"\n};\n";
+
+        assert_eq!(
+            parse(source),
+            Err(DeviceTreeError::SyntaxError(4, "expected ';'".to_string()))
+        );
+        assert_eq!(parse_value("<1 0x2>").unwrap(), [0, 0, 0, 1, 0, 0, 0, 2]);
+        assert!(parse_value("<1> <2>").is_err());
+    }
+}
@@ -1,20 +1,43 @@
+use std::cell::RefCell;
 use std::convert::TryInto;
 use std::fmt;
-use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
 
 use thiserror::Error;
 
-/// This is a simple ELF64 file parser that makes it easy to extract sections
+/// The size of an ELF32 file header, which is the smallest valid ELF file
+const HEADER_SIZE: usize = 52;
+
+/// The machine type of RISC-V
+const EM_RISCV: u16 = 0xf3;
+
+/// The OS ABI of UNIX - System V
+const ELFOSABI_SYSV: u8 = 0;
+
+/// The OS ABI of GNU/Linux, which some toolchains use for bare-metal files too
+const ELFOSABI_GNU: u8 = 3;
+
+/// The section index that indicates that there is no section
+const SHN_UNDEF: u16 = 0;
+
+/// The section index that indicates that the real index is in the first section header
+const SHN_XINDEX: u16 = 0xffff;
+
+/// The largest section or segment that is zero-filled, which is the size of the largest memory
+/// of the chips this is for (the 16 MiB of XIP flash)
+const MAX_ZERO_FILL_LEN: u64 = 16 * 1024 * 1024;
+
+/// This is a simple ELF32 and ELF64 file parser that makes it easy to extract sections
 #[derive(Debug)]
 pub struct ElfParser<R> {
-    reader: BufReader<R>,
+    reader: RefCell<BufReader<R>>,
     header: Header,
     program_headers: Vec<ProgramHeader>,
     section_headers: Vec<SectionHeader>,
 }
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ProgType {
     Null = 0x0,
     Load,
@@ -71,6 +94,8 @@ pub enum SectionType {
     Num,
     // Sometimes called ARM_ATTRIBUTES, other times RISCV_ATTRIBUTES
     CompatAttribute = 0x70000003,
+    /// A section type that isn't recognized, like an OS- or processor-specific one
+    Unknown(u32),
 }
 
 impl From<u32> for SectionType {
@@ -95,7 +120,7 @@ impl From<u32> for SectionType {
             0x12 => SectionType::SymTabShNdx,
             0x13 => SectionType::Num,
             0x70000003 => SectionType::CompatAttribute,
-            _ => panic!("Unrecognized section type {:#x}", val),
+            _ => SectionType::Unknown(val),
         }
     }
 }
@@ -122,13 +147,14 @@ impl fmt::Debug for SectionType {
             SectionType::SymTabShNdx => "SYMTAB_SHNDX",
             SectionType::Num => "NUM",
             SectionType::CompatAttribute => "RISCV_ATTRIBUTE",
+            SectionType::Unknown(val) => return write!(f, "UNKNOWN({:#x})", val),
         };
 
         write!(f, "{}", s)
     }
 }
 
-/// This is an ELF32 header
+/// This is an ELF32 or ELF64 header
 #[derive(Debug)]
 pub struct Header {
     /// This byte is set to either 1 or 2 to signify 32- or 64-bit format, respectively
@@ -142,13 +168,15 @@ pub struct Header {
     /// The target OS ABI version
     pub os_abi_version: u8,
     /// The object file type
-    pub file_type: u16,
+    pub file_type: FileType,
+    /// The machine type
+    pub machine: u16,
     /// The program entry address
-    pub entry_addr: u32,
+    pub entry_addr: u64,
     /// The program header offset
-    pub ph_offset: u32,
+    pub ph_offset: u64,
     /// The section header offset
-    pub sh_offset: u32,
+    pub sh_offset: u64,
     /// The size of a program header entry
     pub ph_entry_size: u16,
     /// The number of program header entries
@@ -161,21 +189,21 @@ pub struct Header {
     pub sh_str_idx: u16,
 }
 
-/// ELF32 Program Header
-#[derive(Debug)]
+/// ELF32 or ELF64 Program Header
+#[derive(Debug, Clone)]
 pub struct ProgramHeader {
     /// The type of the program header segment
     typ: ProgType,
     /// The offset to the segment in the image file
-    offset: u32,
+    offset: u64,
     /// The virtual address to map the segment to
-    virt_addr: u32,
+    virt_addr: u64,
     /// The physical address to map the segment to, when relevant
-    phys_addr: u32,
+    phys_addr: u64,
     /// Size of the segment in the file image, in bytes
-    file_size: u32,
+    file_size: u64,
     /// Size of the segment in memory, in bytes
-    mem_size: u32,
+    mem_size: u64,
     /// Segment-dependent flags
     flags: u32,
     /// How to align the section
@@ -184,40 +212,358 @@ pub struct ProgramHeader {
     ///
     /// Otherwise should be a positive, integral power of 2, with `virt_addr` equating `offset`
     /// modulus `alignment`
-    alignment: u32,
+    alignment: u64,
 }
 
-/// ELF32 Section Header
-#[derive(Debug)]
+impl ProgramHeader {
+    /// Returns the type of the program header segment
+    pub fn typ(&self) -> ProgType {
+        self.typ
+    }
+
+    /// Returns the offset to the segment in the image file
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the virtual address to map the segment to
+    pub fn virt_addr(&self) -> u64 {
+        self.virt_addr
+    }
+
+    /// Returns the physical address to map the segment to, which is where the data of the
+    /// segment is loaded from when it differs from `virt_addr`
+    pub fn phys_addr(&self) -> u64 {
+        self.phys_addr
+    }
+
+    /// Returns the size of the segment in the file image, in bytes
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the size of the segment in memory, in bytes
+    pub fn mem_size(&self) -> u64 {
+        self.mem_size
+    }
+
+    /// Returns the segment-dependent flags
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns the alignment of the segment
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+}
+
+/// ELF32 or ELF64 Section Header
+#[derive(Debug, Clone)]
 pub struct SectionHeader {
     /// Offset to a string in the .shstrtab section with the name of this section
     pub name_offset: u32,
     /// The type of this section
     pub typ: SectionType,
     /// The attributes of this section
-    pub flags: u32,
+    pub flags: u64,
     /// Virtual address for this section, if it's to be loaded into memory
-    pub virt_addr: u32,
+    pub virt_addr: u64,
     /// Offset to the section in the file image
-    pub offset: u32,
+    pub offset: u64,
     /// The size of the section in the file image, in bytes
-    pub size: u32,
+    pub size: u64,
     /// Contains the index of an associated section, which might be used depending on the type
     pub link: u32,
     /// Contains information about the section
     pub info: u32,
     /// The required alignment of the section
-    pub addr_align: u32,
+    pub addr_align: u64,
     /// The size of each entry, in bytes, if this is a section with fixed sized data
-    pub entry_size: u32,
+    pub entry_size: u64,
     /// The name of the section
     pub name: Option<String>,
 }
 
+/// The type of a symbol
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Func,
+    Section,
+    File,
+    Common,
+    Tls,
+    Other(u8),
+}
+
+impl From<u8> for SymbolType {
+    fn from(val: u8) -> SymbolType {
+        match val {
+            0 => SymbolType::NoType,
+            1 => SymbolType::Object,
+            2 => SymbolType::Func,
+            3 => SymbolType::Section,
+            4 => SymbolType::File,
+            5 => SymbolType::Common,
+            6 => SymbolType::Tls,
+            _ => SymbolType::Other(val),
+        }
+    }
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolType::NoType => write!(f, "NOTYPE"),
+            SymbolType::Object => write!(f, "OBJECT"),
+            SymbolType::Func => write!(f, "FUNC"),
+            SymbolType::Section => write!(f, "SECTION"),
+            SymbolType::File => write!(f, "FILE"),
+            SymbolType::Common => write!(f, "COMMON"),
+            SymbolType::Tls => write!(f, "TLS"),
+            SymbolType::Other(val) => write!(f, "<unknown>: {}", val),
+        }
+    }
+}
+
+/// The binding of a symbol, which determines its visibility to the linker
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    Other(u8),
+}
+
+impl From<u8> for SymbolBinding {
+    fn from(val: u8) -> SymbolBinding {
+        match val {
+            0 => SymbolBinding::Local,
+            1 => SymbolBinding::Global,
+            2 => SymbolBinding::Weak,
+            _ => SymbolBinding::Other(val),
+        }
+    }
+}
+
+impl fmt::Display for SymbolBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolBinding::Local => write!(f, "LOCAL"),
+            SymbolBinding::Global => write!(f, "GLOBAL"),
+            SymbolBinding::Weak => write!(f, "WEAK"),
+            SymbolBinding::Other(val) => write!(f, "<unknown>: {}", val),
+        }
+    }
+}
+
+/// The section a symbol is defined in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SymbolSection {
+    /// The symbol is undefined
+    Undefined,
+    /// The symbol has an absolute value that isn't relative to a section
+    Absolute,
+    /// The symbol is a common block that hasn't been allocated yet
+    Common,
+    /// The index of the section header of the section
+    Index(u16),
+}
+
+impl From<u16> for SymbolSection {
+    fn from(val: u16) -> SymbolSection {
+        match val {
+            0 => SymbolSection::Undefined,
+            0xfff1 => SymbolSection::Absolute,
+            0xfff2 => SymbolSection::Common,
+            _ => SymbolSection::Index(val),
+        }
+    }
+}
+
+/// ELF32 or ELF64 symbol table entry
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Symbol {
+    /// The name of the symbol
+    pub name: String,
+    /// The value of the symbol, which is its address for functions and objects
+    pub value: u64,
+    /// The size of the symbol, in bytes, or 0 if it has no size
+    pub size: u64,
+    /// The type of the symbol
+    pub typ: SymbolType,
+    /// The binding of the symbol
+    pub binding: SymbolBinding,
+    /// The visibility of the symbol
+    pub other: u8,
+    /// The section the symbol is defined in
+    pub section: SymbolSection,
+}
+
+impl Symbol {
+    /// Returns whether `addr` is within this symbol
+    ///
+    /// Symbols without a size only contain their own address
+    pub fn contains(&self, addr: u64) -> bool {
+        if self.size == 0 {
+            addr == self.value
+        } else {
+            addr >= self.value && (addr - self.value) < self.size
+        }
+    }
+}
+
+/// The symbols of an ELF file
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Returns an iterator over the symbols, in the order of the symbol table
+    pub fn iter(&self) -> std::slice::Iter<'_, Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Returns the number of symbols
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Returns whether there are no symbols, i.e. if the file has been stripped
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Returns the first symbol with the given `name`
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Returns the function or object symbol that contains `addr`
+    ///
+    /// Global symbols are preferred over local ones, and symbols with a size over symbols
+    /// without one, so that assembler labels don't shadow the function they're in
+    pub fn lookup(&self, addr: u64) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .filter(|sym| {
+                matches!(sym.section, SymbolSection::Index(_))
+                    && !matches!(sym.typ, SymbolType::Section | SymbolType::File)
+                    && !sym.name.is_empty()
+                    && sym.contains(addr)
+            })
+            .max_by_key(|sym| (sym.size > 0, sym.binding != SymbolBinding::Local))
+    }
+
+    /// Returns `addr` as the name of the symbol that contains it plus the offset, i.e.
+    /// `main+0x4`
+    pub fn describe(&self, addr: u64) -> Option<String> {
+        self.lookup(addr).map(|sym| match addr - sym.value {
+            0 => sym.name.clone(),
+            offset => format!("{}+{:#x}", sym.name, offset),
+        })
+    }
+}
+
+impl<'a> IntoIterator for &'a SymbolTable {
+    type Item = &'a Symbol;
+    type IntoIter = std::slice::Iter<'a, Symbol>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.symbols.iter()
+    }
+}
+
 /// The target machine class
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Class {
     Elf32,
+    Elf64,
+}
+
+impl Class {
+    /// Returns the cores that files of this class are built for
+    pub fn targets(&self) -> &'static str {
+        match self {
+            Class::Elf32 => {
+                "RV32 cores, like the BL602, the BL702 and the M0 and LP cores of the BL808"
+            }
+            Class::Elf64 => "RV64 cores, like the D0 core of the BL808",
+        }
+    }
+
+    /// Returns the size of the file header
+    fn header_size(&self) -> usize {
+        match self {
+            Class::Elf32 => HEADER_SIZE,
+            Class::Elf64 => 64,
+        }
+    }
+
+    /// Returns the size of a program header
+    fn program_header_size(&self) -> u16 {
+        match self {
+            Class::Elf32 => 32,
+            Class::Elf64 => 56,
+        }
+    }
+
+    /// Returns the size of a section header
+    fn section_header_size(&self) -> u16 {
+        match self {
+            Class::Elf32 => 40,
+            Class::Elf64 => 64,
+        }
+    }
+
+    /// Returns the size of a symbol table entry
+    fn symbol_size(&self) -> usize {
+        match self {
+            Class::Elf32 => 16,
+            Class::Elf64 => 24,
+        }
+    }
+
+    /// Reads an address, offset or size, which is 4 bytes in ELF32 and 8 bytes in ELF64
+    fn word_at(&self, buf: &[u8], offset: usize) -> u64 {
+        match self {
+            Class::Elf32 => u32_at(buf, offset) as u64,
+            Class::Elf64 => u64_at(buf, offset),
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Class::Elf32 => write!(f, "ELF32"),
+            Class::Elf64 => write!(f, "ELF64"),
+        }
+    }
+}
+
+/// The object file type
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileType {
+    /// A relocatable object file that hasn't been linked yet
+    Relocatable,
+    /// A linked executable file
+    Executable,
+    /// A shared object, or a position independent executable
+    SharedObject,
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileType::Relocatable => write!(f, "relocatable object file"),
+            FileType::Executable => write!(f, "executable file"),
+            FileType::SharedObject => write!(f, "shared object file"),
+        }
+    }
 }
 
 /// Indicates the elf and target endianness
@@ -229,24 +575,74 @@ pub enum Endianness {
 /// Errors that indicate what went wrong during parsing
 #[derive(Debug, Error)]
 pub enum ParseError {
-    #[error("Missing ELF header")]
+    #[error(
+        "Missing or truncated ELF header, expected at least {} bytes",
+        HEADER_SIZE
+    )]
     MissingHeader,
+    #[error("Missing or truncated ELF64 header, expected at least 64 bytes")]
+    MissingElf64Header,
     #[error("Input does not contain ELF magic header")]
     InvalidMagicHeader,
-    #[error("Input ELF is 64-bit, only 32-bit is supported")]
-    ElfIs64Bit,
+    #[error(
+        "Input has an invalid ELF class {}, expected 1 (ELF32) or 2 (ELF64)",
+        _0
+    )]
+    InvalidClass(u8),
     #[error("Input has an unsupported ELF version, expected 1")]
     InvalidElfVersion,
     #[error("Input endianness is unsupported, only little endian is supported")]
     UnsupportedEndianness,
-    #[error("Input ABI is unsupported, only System V is supported")]
-    UnsupportedAbi,
-    #[error("Input has an unsupported machine type, only RISC-V is supported")]
-    UnsupportedMachineType,
-    #[error("Input is an unsupported file type, only executable files are supported")]
-    UnsupportedFileType,
-    #[error("There was an error when trying to parse the section name as utf-8")]
-    SectionNameEncodingError(#[from] std::string::FromUtf8Error),
+    #[error("Input ABI {} is unsupported, only System V and GNU are supported", _0)]
+    UnsupportedAbi(u8),
+    #[error(
+        "Input has an unsupported machine type {:#x}, only RISC-V is supported",
+        _0
+    )]
+    UnsupportedMachineType(u16),
+    #[error(
+        "Input is an unsupported file type {}, only relocatable, executable and shared object files are supported",
+        _0
+    )]
+    UnsupportedFileType(u16),
+    #[error("Section {} links to section {}, which doesn't exist", _0, _1)]
+    InvalidSectionLink(usize, u32),
+    #[error("Invalid offset {:#x} into string table", _0)]
+    InvalidStringOffset(u32),
+    #[error("Invalid program header size {}, expected at least {}", _0, _1)]
+    InvalidProgramHeaderSize(u16, u16),
+    #[error("Invalid section header size {}, expected at least {}", _0, _1)]
+    InvalidSectionHeaderSize(u16, u16),
+    #[error(
+        "Program header {} at offset {:#x} is beyond the end of the file",
+        _0,
+        _1
+    )]
+    TruncatedProgramHeader(u16, u64),
+    #[error(
+        "Section header {} at offset {:#x} is beyond the end of the file",
+        _0,
+        _1
+    )]
+    TruncatedSectionHeader(u16, u64),
+    #[error(
+        "The section name string table index {} is out of bounds ({} sections)",
+        _0,
+        _1
+    )]
+    InvalidStringTableIndex(u32, usize),
+    #[error(
+        "The data at offset {:#x} ({} bytes) is beyond the end of the file",
+        _0,
+        _1
+    )]
+    TruncatedData(u64, u64),
+    #[error(
+        "The size {:#x} of data without contents in the file is too large, expected at most {:#x}",
+        _0,
+        MAX_ZERO_FILL_LEN
+    )]
+    ZeroFillTooLarge(u64),
     #[error("I/O error: {}", _0)]
     IoError(#[from] io::Error),
 }
@@ -260,117 +656,258 @@ impl<R: Read + Seek> ElfParser<R> {
         let mut program_headers = Vec::with_capacity(header.ph_entry_num as usize);
         let mut section_headers = Vec::with_capacity(header.sh_entry_num as usize);
 
+        let class = header.class;
+
+        // Assert that the entries are large enough to hold the headers we read
+        if header.ph_entry_num > 0 && header.ph_entry_size < class.program_header_size() {
+            return Err(ParseError::InvalidProgramHeaderSize(
+                header.ph_entry_size,
+                class.program_header_size(),
+            ));
+        }
+
+        if header.sh_offset != 0 && header.sh_entry_size < class.section_header_size() {
+            return Err(ParseError::InvalidSectionHeaderSize(
+                header.sh_entry_size,
+                class.section_header_size(),
+            ));
+        }
+
         // Read the program headers
         for n in 0..header.ph_entry_num {
-            let offset = header.ph_offset as u64 + (header.ph_entry_size as u64 * n as u64);
-            let program_header = Self::parse_program_header(&mut reader, offset)?;
+            let offset = header
+                .ph_offset
+                .saturating_add(header.ph_entry_size as u64 * n as u64);
+            let program_header = Self::parse_program_header(&mut reader, class, offset)
+                .map_err(|err| truncated(err, ParseError::TruncatedProgramHeader(n, offset)))?;
 
             program_headers.push(program_header);
         }
 
-        // Read the section headers
-        for n in 0..header.sh_entry_num {
-            let offset = header.sh_offset as u64 + (header.sh_entry_size as u64 * n as u64);
-            let section_header = Self::parse_section_header(&mut reader, offset)?;
+        // Read the section headers, unless there are none (i.e. the file has been stripped of
+        // them with `objcopy --strip-section-headers`)
+        if header.sh_offset != 0 {
+            for n in 0..header.sh_entry_num {
+                let offset = header
+                    .sh_offset
+                    .saturating_add(header.sh_entry_size as u64 * n as u64);
+                let section_header = Self::parse_section_header(&mut reader, class, offset)
+                    .map_err(|err| truncated(err, ParseError::TruncatedSectionHeader(n, offset)))?;
 
-            section_headers.push(section_header);
+                section_headers.push(section_header);
+            }
         }
 
-        let mut strbuf: Vec<u8> = Vec::new();
-        let str_table_offset = section_headers[header.sh_str_idx as usize].offset as u64;
+        // The index of the section name string table is in the first section header if it
+        // doesn't fit in the file header
+        let str_idx = match header.sh_str_idx {
+            SHN_XINDEX => section_headers.first().map(|sh| sh.link).unwrap_or(0),
+            idx => idx as u32,
+        };
 
-        // Read the section names
-        for sh in section_headers.iter_mut() {
-            strbuf.clear();
-            reader.seek(SeekFrom::Start(str_table_offset + sh.name_offset as u64))?;
-            reader.read_until(0x00, &mut strbuf)?;
+        // Read the section names, unless there is no section name string table
+        if str_idx != SHN_UNDEF as u32 && !section_headers.is_empty() {
+            let strtab = section_headers.get(str_idx as usize).ok_or(
+                ParseError::InvalidStringTableIndex(str_idx, section_headers.len()),
+            )?;
+            let strbuf = read_at(&mut reader, strtab.offset, strtab.size)?;
 
-            sh.name = Some(String::from_utf8_lossy(&strbuf[..strbuf.len() - 1]).to_string());
+            for sh in section_headers.iter_mut() {
+                sh.name = Some(string_at(&strbuf, sh.name_offset)?);
+            }
         }
 
         Ok(ElfParser {
-            reader,
+            reader: RefCell::new(reader),
             header,
             program_headers,
             section_headers,
         })
     }
 
+    /// Returns the ELF file header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns the program headers
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    /// Returns the section headers
+    pub fn section_headers(&self) -> &[SectionHeader] {
+        &self.section_headers
+    }
+
+    /// Reads and returns the symbol table
+    ///
+    /// The symbol table is empty if the file has been stripped
+    pub fn symbol_table(&self) -> Result<SymbolTable, ParseError> {
+        let (index, symtab) = match self
+            .section_headers
+            .iter()
+            .enumerate()
+            .find(|(_, sh)| matches!(sh.typ, SectionType::SymTab))
+        {
+            Some(section) => section,
+            None => return Ok(SymbolTable::default()),
+        };
+
+        // The symbol names are in the string table the symbol table links to
+        let strtab = self
+            .section_headers
+            .get(symtab.link as usize)
+            .ok_or(ParseError::InvalidSectionLink(index, symtab.link))?;
+
+        let symbol_data = self.read_at(symtab.offset, symtab.size)?;
+        let string_data = self.read_at(strtab.offset, strtab.size)?;
+
+        let class = self.header.class;
+        let mut symbols = Vec::with_capacity(symbol_data.len() / class.symbol_size());
+
+        for entry in symbol_data.chunks_exact(class.symbol_size()) {
+            // The fields are in a different order in ELF64, to keep them aligned
+            let (value, size, info, other, section) = match class {
+                Class::Elf32 => (
+                    u32_at(entry, 0x04) as u64,
+                    u32_at(entry, 0x08) as u64,
+                    entry[0x0c],
+                    entry[0x0d],
+                    u16_at(entry, 0x0e),
+                ),
+                Class::Elf64 => (
+                    u64_at(entry, 0x08),
+                    u64_at(entry, 0x10),
+                    entry[0x04],
+                    entry[0x05],
+                    u16_at(entry, 0x06),
+                ),
+            };
+
+            symbols.push(Symbol {
+                name: string_at(&string_data, u32_at(entry, 0x00))?,
+                value,
+                size,
+                typ: (info & 0xf).into(),
+                binding: (info >> 4).into(),
+                other,
+                section: section.into(),
+            });
+        }
+
+        Ok(SymbolTable { symbols })
+    }
+
+    /// Reads and returns the data of the given `section`
+    ///
+    /// Sections without data in the file, like `.bss`, are returned as zeros
+    pub fn read_section_data(&self, section: &SectionHeader) -> Result<Vec<u8>, ParseError> {
+        if let SectionType::NoBits = section.typ {
+            return zero_filled(vec![], section.size);
+        }
+
+        self.read_at(section.offset, section.size)
+    }
+
+    /// Reads and returns the data of the given `segment` as it's laid out in memory
+    ///
+    /// The part of the segment that isn't in the file, like `.bss`, is filled with zeros
+    pub fn read_segment_data(&self, segment: &ProgramHeader) -> Result<Vec<u8>, ParseError> {
+        let buf = self.read_at(segment.offset, segment.file_size)?;
+
+        zero_filled(buf, segment.mem_size)
+    }
+
+    /// Reads `size` bytes at `offset` from the beginning of the input
+    fn read_at(&self, offset: u64, size: u64) -> Result<Vec<u8>, ParseError> {
+        read_at(&mut *self.reader.borrow_mut(), offset, size)
+    }
+
+    /// Consumes the parser and returns the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner().into_inner()
+    }
+
     /// Parses and returns the Program Header at the given `offset` from the beginning of the input
     fn parse_program_header(
         reader: &mut BufReader<R>,
+        class: Class,
         offset: u64,
     ) -> Result<ProgramHeader, ParseError> {
         reader.seek(SeekFrom::Start(offset))?;
 
-        let mut buffer = [0u8; 32];
+        let mut buffer = vec![0u8; class.program_header_size() as usize];
 
         reader.read_exact(&mut buffer)?;
 
-        let typ = u32::from_le_bytes(buffer[0x00..0x04].try_into().unwrap());
-        let offset = u32::from_le_bytes(buffer[0x04..0x08].try_into().unwrap());
-        let virt_addr = u32::from_le_bytes(buffer[0x08..0x0c].try_into().unwrap());
-        let phys_addr = u32::from_le_bytes(buffer[0x0c..0x10].try_into().unwrap());
-        let file_size = u32::from_le_bytes(buffer[0x10..0x14].try_into().unwrap());
-        let mem_size = u32::from_le_bytes(buffer[0x14..0x18].try_into().unwrap());
-        let flags = u32::from_le_bytes(buffer[0x18..0x1c].try_into().unwrap());
-        let alignment = u32::from_le_bytes(buffer[0x1c..0x20].try_into().unwrap());
-
-        Ok(ProgramHeader {
-            typ: typ.into(),
-            offset,
-            virt_addr,
-            phys_addr,
-            file_size,
-            mem_size,
-            flags,
-            alignment,
-        })
+        // The flags are moved up after the type in ELF64, to keep the other fields aligned
+        let program_header = match class {
+            Class::Elf32 => ProgramHeader {
+                typ: u32_at(&buffer, 0x00).into(),
+                offset: class.word_at(&buffer, 0x04),
+                virt_addr: class.word_at(&buffer, 0x08),
+                phys_addr: class.word_at(&buffer, 0x0c),
+                file_size: class.word_at(&buffer, 0x10),
+                mem_size: class.word_at(&buffer, 0x14),
+                flags: u32_at(&buffer, 0x18),
+                alignment: class.word_at(&buffer, 0x1c),
+            },
+            Class::Elf64 => ProgramHeader {
+                typ: u32_at(&buffer, 0x00).into(),
+                flags: u32_at(&buffer, 0x04),
+                offset: class.word_at(&buffer, 0x08),
+                virt_addr: class.word_at(&buffer, 0x10),
+                phys_addr: class.word_at(&buffer, 0x18),
+                file_size: class.word_at(&buffer, 0x20),
+                mem_size: class.word_at(&buffer, 0x28),
+                alignment: class.word_at(&buffer, 0x30),
+            },
+        };
+
+        Ok(program_header)
     }
 
     /// Parses and returns the section header at `offset`
     pub fn parse_section_header(
         reader: &mut BufReader<R>,
+        class: Class,
         offset: u64,
     ) -> Result<SectionHeader, ParseError> {
         reader.seek(SeekFrom::Start(offset))?;
 
-        let mut buffer = [0u8; 40];
+        let mut buffer = vec![0u8; class.section_header_size() as usize];
 
         reader.read_exact(&mut buffer)?;
 
-        let name_offset = u32::from_le_bytes(buffer[0x00..0x04].try_into().unwrap());
-        let typ = u32::from_le_bytes(buffer[0x04..0x08].try_into().unwrap());
-        let flags = u32::from_le_bytes(buffer[0x08..0x0c].try_into().unwrap());
-        let virt_addr = u32::from_le_bytes(buffer[0x0c..0x10].try_into().unwrap());
-        let offset = u32::from_le_bytes(buffer[0x10..0x14].try_into().unwrap());
-        let size = u32::from_le_bytes(buffer[0x14..0x18].try_into().unwrap());
-        let link = u32::from_le_bytes(buffer[0x18..0x1c].try_into().unwrap());
-        let info = u32::from_le_bytes(buffer[0x1c..0x20].try_into().unwrap());
-        let addr_align = u32::from_le_bytes(buffer[0x20..0x24].try_into().unwrap());
-        let entry_size = u32::from_le_bytes(buffer[0x24..0x28].try_into().unwrap());
+        // The fields are in the same order in ELF32 and ELF64, but the addresses, offsets and
+        // sizes are twice as wide in ELF64
+        let word = match class {
+            Class::Elf32 => 4,
+            Class::Elf64 => 8,
+        };
 
         Ok(SectionHeader {
-            name_offset,
-            typ: typ.into(),
-            flags,
-            virt_addr,
-            offset,
-            size,
-            link,
-            info,
-            addr_align,
-            entry_size,
+            name_offset: u32_at(&buffer, 0x00),
+            typ: u32_at(&buffer, 0x04).into(),
+            flags: class.word_at(&buffer, 0x08),
+            virt_addr: class.word_at(&buffer, 0x08 + word),
+            offset: class.word_at(&buffer, 0x08 + word * 2),
+            size: class.word_at(&buffer, 0x08 + word * 3),
+            link: u32_at(&buffer, 0x08 + word * 4),
+            info: u32_at(&buffer, 0x0c + word * 4),
+            addr_align: class.word_at(&buffer, 0x10 + word * 4),
+            entry_size: class.word_at(&buffer, 0x10 + word * 5),
             name: None,
         })
     }
 
-    /// Parses and returns an ELF32 file header at the current position of the reader
+    /// Parses and returns an ELF32 or ELF64 file header at the current position of the reader
     ///
     /// Note: It is up to the user to ensure that the reader is at the beginning of the input
     fn parse_header(reader: &mut BufReader<R>) -> Result<Header, ParseError> {
-        // Read the first 64 bytes of the input into the `header` buffer
-        let mut header = [0u8; 64];
+        // Read the smallest possible header into the `header` buffer
+        let mut header = vec![0u8; HEADER_SIZE];
 
         reader
             .read_exact(&mut header)
@@ -384,9 +921,19 @@ impl<R: Read + Seek> ElfParser<R> {
         // Read the target class, either 32-bit or 64-bit
         let class = match header[0x4] {
             1 => Class::Elf32,
-            _ => return Err(ParseError::ElfIs64Bit),
+            2 => Class::Elf64,
+            class => return Err(ParseError::InvalidClass(class)),
         };
 
+        // Read the rest of the header if it's an ELF64 header
+        if class.header_size() > HEADER_SIZE {
+            header.resize(class.header_size(), 0);
+
+            reader
+                .read_exact(&mut header[HEADER_SIZE..])
+                .map_err(|_| ParseError::MissingElf64Header)?;
+        }
+
         // Read the ELF endianness
         let endianness = match header[0x5] {
             1 => Endianness::Little,
@@ -400,55 +947,53 @@ impl<R: Read + Seek> ElfParser<R> {
             return Err(ParseError::InvalidElfVersion);
         }
 
-        // Read the OS ABI and assert that it is 0 (UNIX - System V)
+        // Read the OS ABI and assert that it is either System V or GNU, which are the same for
+        // bare-metal files
         let os_abi = header[0x7];
 
-        if os_abi != 0 {
-            return Err(ParseError::UnsupportedAbi);
+        if os_abi != ELFOSABI_SYSV && os_abi != ELFOSABI_GNU {
+            return Err(ParseError::UnsupportedAbi(os_abi));
         }
 
         // Read the OS ABI version
         let os_abi_version = header[0x8];
 
         // Read the object file type
-        let file_type = u16::from_le_bytes(header[0x10..0x12].try_into().unwrap());
-
-        // Assert that the file type is an executable file
-        if file_type != 0x02 {
-            return Err(ParseError::UnsupportedFileType);
-        }
+        let file_type = match u16_at(&header, 0x10) {
+            1 => FileType::Relocatable,
+            2 => FileType::Executable,
+            3 => FileType::SharedObject,
+            file_type => return Err(ParseError::UnsupportedFileType(file_type)),
+        };
 
-        // Read the machine type
-        let machine_type = u16::from_le_bytes(header[0x12..0x14].try_into().unwrap());
+        // Read the machine type and assert that it is RISC-V
+        let machine = u16_at(&header, 0x12);
 
-        // Assert that the machine type is RISC-V
-        if machine_type != 0xF3 {
-            return Err(ParseError::UnsupportedMachineType);
+        if machine != EM_RISCV {
+            return Err(ParseError::UnsupportedMachineType(machine));
         }
 
-        // Read the entry address
-        let entry_addr = u32::from_le_bytes(header[0x18..0x1c].try_into().unwrap());
-
-        // Read the program header offset
-        let ph_offset = u32::from_le_bytes(header[0x1c..0x20].try_into().unwrap());
-
-        // Read the section header offset
-        let sh_offset = u32::from_le_bytes(header[0x20..0x24].try_into().unwrap());
-
-        // Read the size of the program header entries
-        let ph_entry_size = u16::from_le_bytes(header[0x2a..0x2c].try_into().unwrap());
-
-        // Read the number of program header entries
-        let ph_entry_num = u16::from_le_bytes(header[0x2c..0x2e].try_into().unwrap());
-
-        // Read the size of the section header entries
-        let sh_entry_size = u16::from_le_bytes(header[0x2e..0x30].try_into().unwrap());
+        // Read the entry address and the program and section header offsets, which are the
+        // only fields that differ in size
+        let entry_addr = class.word_at(&header, 0x18);
+        let (ph_offset, sh_offset, rest) = match class {
+            Class::Elf32 => (
+                u32_at(&header, 0x1c) as u64,
+                u32_at(&header, 0x20) as u64,
+                0x24,
+            ),
+            Class::Elf64 => (u64_at(&header, 0x20), u64_at(&header, 0x28), 0x30),
+        };
 
-        // Read the number of section header entries
-        let sh_entry_num = u16::from_le_bytes(header[0x30..0x32].try_into().unwrap());
+        // Read the size and number of the program and section header entries, skipping the
+        // flags and the size of the file header
+        let ph_entry_size = u16_at(&header, rest + 0x06);
+        let ph_entry_num = u16_at(&header, rest + 0x08);
+        let sh_entry_size = u16_at(&header, rest + 0x0a);
+        let sh_entry_num = u16_at(&header, rest + 0x0c);
 
         // Read the index of the section header that contains the name of the sections
-        let sh_str_idx = u16::from_le_bytes(header[0x32..0x34].try_into().unwrap());
+        let sh_str_idx = u16_at(&header, rest + 0x0e);
 
         let header = Header {
             class,
@@ -457,6 +1002,7 @@ impl<R: Read + Seek> ElfParser<R> {
             os_abi,
             os_abi_version,
             file_type,
+            machine,
             entry_addr,
             ph_offset,
             sh_offset,
@@ -470,3 +1016,353 @@ impl<R: Read + Seek> ElfParser<R> {
         Ok(header)
     }
 }
+
+/// Reads a little endian `u16` at `offset` in `buf`
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+/// Reads a little endian `u32` at `offset` in `buf`
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little endian `u64` at `offset` in `buf`
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads `size` bytes at `offset` from the beginning of `reader`
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> Result<Vec<u8>, ParseError> {
+    // Don't trust the size enough to allocate it all up front
+    let mut buf = Vec::new();
+
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.by_ref().take(size).read_to_end(&mut buf)?;
+
+    if buf.len() != size as usize {
+        return Err(ParseError::TruncatedData(offset, size));
+    }
+
+    Ok(buf)
+}
+
+/// Returns `buf` filled with zeros up to `size`, unless `size` is too large to be trusted
+fn zero_filled(mut buf: Vec<u8>, size: u64) -> Result<Vec<u8>, ParseError> {
+    if size > buf.len() as u64 {
+        if size > MAX_ZERO_FILL_LEN {
+            return Err(ParseError::ZeroFillTooLarge(size));
+        }
+
+        buf.resize(size as usize, 0);
+    }
+
+    Ok(buf)
+}
+
+/// Returns the null-terminated string at `offset` in the string table `strtab`
+fn string_at(strtab: &[u8], offset: u32) -> Result<String, ParseError> {
+    let s = strtab
+        .get(offset as usize..)
+        .ok_or(ParseError::InvalidStringOffset(offset))?;
+    let len = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+
+    Ok(String::from_utf8_lossy(&s[..len]).into_owned())
+}
+
+/// Replaces an unexpected end of file error with the more descriptive `truncated` error
+fn truncated(err: ParseError, truncated: ParseError) -> ParseError {
+    match err {
+        ParseError::IoError(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+            truncated
+        }
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MINIMAL_ELF: &[u8] = include_bytes!("../test/elf/bl602_minimal.elf");
+    const MINIMAL_OBJECT: &[u8] = include_bytes!("../test/elf/bl602_minimal.o");
+    const RV64_ELF: &[u8] = include_bytes!("../test/elf/rv64_minimal.elf");
+
+    #[test]
+    fn it_should_read_the_symbol_table() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+        let symbols = parser.symbol_table().unwrap();
+
+        assert_eq!(symbols.len(), 12);
+
+        let main = symbols.find("main").unwrap();
+
+        assert_eq!(main.value, 0x2300_0012);
+        assert_eq!(main.size, 24);
+        assert_eq!(main.typ, SymbolType::Func);
+        assert_eq!(main.binding, SymbolBinding::Global);
+        assert_eq!(main.section, SymbolSection::Index(1));
+
+        let buffer = symbols.find("buffer").unwrap();
+
+        assert_eq!(buffer.typ, SymbolType::Object);
+        assert_eq!(buffer.size, 64);
+
+        let stack_top = symbols.find("_stack_top").unwrap();
+
+        assert_eq!(stack_top.section, SymbolSection::Absolute);
+        assert_eq!(stack_top.value, 0x4204_c000);
+    }
+
+    #[test]
+    fn it_should_look_up_symbols_by_address() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+        let symbols = parser.symbol_table().unwrap();
+
+        assert_eq!(symbols.lookup(0x2300_0000).unwrap().name, "_start");
+        assert_eq!(symbols.describe(0x2300_0016).unwrap(), "main+0x4");
+
+        // The local label at the start of `helper` shouldn't shadow it
+        assert_eq!(symbols.describe(0x2300_002a).unwrap(), "helper");
+        assert_eq!(symbols.describe(0x4202_0043).unwrap(), "buffer+0x3f");
+        assert!(symbols.lookup(0x4202_0044).is_none());
+    }
+
+    #[test]
+    fn it_should_read_section_data() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+        let sections = parser.section_headers();
+
+        let rodata = sections
+            .iter()
+            .find(|sh| sh.name.as_deref() == Some(".rodata"))
+            .unwrap();
+        let bss = sections
+            .iter()
+            .find(|sh| sh.name.as_deref() == Some(".bss"))
+            .unwrap();
+
+        assert_eq!(
+            parser.read_section_data(rodata).unwrap(),
+            b"Hello, BL602!\0"
+        );
+        assert_eq!(parser.read_section_data(bss).unwrap(), vec![0; 64]);
+    }
+
+    #[test]
+    fn it_should_zero_fill_segment_data() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+        let segments: Vec<&ProgramHeader> = parser
+            .program_headers()
+            .iter()
+            .filter(|ph| ph.typ() == ProgType::Load)
+            .collect();
+
+        assert_eq!(segments.len(), 4);
+
+        // .data is loaded from flash into RAM
+        let data = segments[2];
+
+        assert_eq!(data.virt_addr(), 0x4202_0000);
+        assert_eq!(data.phys_addr(), 0x2300_0054);
+        assert_eq!(
+            parser.read_segment_data(data).unwrap(),
+            0x1337u32.to_le_bytes()
+        );
+
+        // .bss has no data in the file
+        let bss = segments[3];
+
+        assert_eq!(bss.file_size(), 0);
+        assert_eq!(parser.read_segment_data(bss).unwrap(), vec![0; 64]);
+    }
+
+    #[test]
+    fn it_should_refuse_huge_zero_filled_data() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_ELF)).unwrap();
+
+        let mut segment = parser
+            .program_headers()
+            .iter()
+            .rfind(|ph| ph.typ() == ProgType::Load)
+            .unwrap()
+            .clone();
+        segment.mem_size = u64::MAX;
+
+        assert!(matches!(
+            parser.read_segment_data(&segment),
+            Err(ParseError::ZeroFillTooLarge(u64::MAX))
+        ));
+
+        let mut section = parser
+            .section_headers()
+            .iter()
+            .find(|sh| sh.name.as_deref() == Some(".bss"))
+            .unwrap()
+            .clone();
+        section.size = MAX_ZERO_FILL_LEN + 1;
+
+        assert!(matches!(
+            parser.read_section_data(&section),
+            Err(ParseError::ZeroFillTooLarge(_))
+        ));
+    }
+
+    /// Returns a copy of `MINIMAL_ELF` with the bytes at `offset` replaced by `bytes`
+    fn patched(offset: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut elf = MINIMAL_ELF.to_vec();
+
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        elf
+    }
+
+    #[test]
+    fn it_should_parse_a_bare_header() {
+        // A header without any program or section headers
+        let mut elf = MINIMAL_ELF[..HEADER_SIZE].to_vec();
+
+        elf[0x1c..0x34].copy_from_slice(&[0u8; 0x18]);
+
+        let parser = ElfParser::parse(Cursor::new(elf)).unwrap();
+
+        assert!(parser.program_headers().is_empty());
+        assert!(parser.section_headers().is_empty());
+        assert!(parser.symbol_table().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_handle_missing_section_names() {
+        // SHN_UNDEF means that there are no section names
+        let parser = ElfParser::parse(Cursor::new(patched(0x32, &[0, 0]))).unwrap();
+
+        assert_eq!(parser.section_headers().len(), 9);
+        assert!(parser.section_headers().iter().all(|sh| sh.name.is_none()));
+
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(patched(0x32, &[99, 0]))),
+            Err(ParseError::InvalidStringTableIndex(99, 9))
+        ));
+
+        // Unknown section types are kept rather than rejected
+        let parser = ElfParser::parse(Cursor::new(patched(
+            0x21c8 + 40 * 5 + 4,
+            &[0xf0, 0xff, 0xff, 0x6f],
+        )))
+        .unwrap();
+
+        assert_eq!(
+            format!("{:?}", parser.section_headers()[5].typ),
+            "UNKNOWN(0x6ffffff0)"
+        );
+    }
+
+    #[test]
+    fn it_should_report_truncated_files() {
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(&MINIMAL_ELF[..HEADER_SIZE - 1])),
+            Err(ParseError::MissingHeader)
+        ));
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(&MINIMAL_ELF[..0x21c8 + 40 * 3])),
+            Err(ParseError::TruncatedSectionHeader(3, 0x2240))
+        ));
+
+        // The section name string table runs past the end of the file
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(patched(
+                0x21c8 + 40 * 7 + 0x14,
+                &[0xff, 0xff, 0, 0]
+            ))),
+            Err(ParseError::TruncatedData(0x2124, 0xffff))
+        ));
+    }
+
+    #[test]
+    fn it_should_not_panic_on_the_fuzzing_corpus() {
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/elf_parser");
+
+        for entry in std::fs::read_dir(corpus).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+
+            if let Ok(parser) = ElfParser::parse(Cursor::new(data)) {
+                let _ = parser.symbol_table();
+
+                for section in parser.section_headers() {
+                    let _ = parser.read_section_data(section);
+                }
+
+                for segment in parser.program_headers() {
+                    let _ = parser.read_segment_data(segment);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_parse_elf64_files() {
+        let parser = ElfParser::parse(Cursor::new(RV64_ELF)).unwrap();
+
+        assert_eq!(parser.header().class, Class::Elf64);
+        assert_eq!(parser.header().entry_addr, 0x2201_0000);
+
+        // .bss is zero-filled at the end of the data segment
+        let data = &parser.program_headers()[2];
+
+        assert_eq!(data.virt_addr(), 0x2201_0054);
+        assert_eq!(data.file_size(), 0x4);
+        assert_eq!(data.mem_size(), 0x44);
+
+        let names: Vec<&str> = parser
+            .section_headers()
+            .iter()
+            .filter_map(|sh| sh.name.as_deref())
+            .collect();
+
+        assert_eq!(names[1..5], [".text", ".rodata", ".data", ".bss"]);
+
+        let symbols = parser.symbol_table().unwrap();
+
+        assert_eq!(symbols.len(), 12);
+        assert_eq!(symbols.describe(0x2201_0016).unwrap(), "main+0x4");
+        assert_eq!(symbols.find("buffer").unwrap().size, 64);
+    }
+
+    #[test]
+    fn it_should_parse_relocatable_files() {
+        let parser = ElfParser::parse(Cursor::new(MINIMAL_OBJECT)).unwrap();
+
+        assert_eq!(parser.header().file_type, FileType::Relocatable);
+        assert!(parser.program_headers().is_empty());
+        assert_eq!(
+            parser.section_headers()[4].name.as_deref(),
+            Some(".text.entry")
+        );
+
+        // Symbol values are offsets into their section
+        let main = parser
+            .symbol_table()
+            .unwrap()
+            .find("main")
+            .cloned()
+            .unwrap();
+
+        assert_eq!(main.value, 0);
+        assert_eq!(main.section, SymbolSection::Index(2));
+    }
+
+    #[test]
+    fn it_should_accept_the_gnu_abi() {
+        let parser = ElfParser::parse(Cursor::new(patched(0x7, &[ELFOSABI_GNU]))).unwrap();
+
+        assert_eq!(parser.header().os_abi, ELFOSABI_GNU);
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(patched(0x7, &[9]))),
+            Err(ParseError::UnsupportedAbi(9))
+        ));
+        assert!(matches!(
+            ElfParser::parse(Cursor::new(&RV64_ELF[..60])),
+            Err(ParseError::MissingElf64Header)
+        ));
+    }
+}
@@ -0,0 +1,452 @@
+//! Reading and writing Intel HEX and Motorola S-record files
+//!
+//! Both formats describe memory as a list of address records, which are merged into contiguous
+//! `Block`s of data, along with the start address if the file has one.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+
+use thiserror::Error;
+
+/// The number of data bytes per record when writing Intel HEX files
+const HEX_RECORD_LEN: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum HexError {
+    #[error("Line {}: the record doesn't start with '{}'", _0, _1)]
+    MissingStartCode(usize, char),
+    #[error("Line {}: the record contains invalid hexadecimal characters", _0)]
+    InvalidHex(usize),
+    #[error("Line {}: the record length doesn't match its contents", _0)]
+    InvalidLength(usize),
+    #[error(
+        "Line {}: invalid checksum (expected {:#04x}, got {:#04x})",
+        _0,
+        _1,
+        _2
+    )]
+    InvalidChecksum(usize, u8, u8),
+    #[error("Line {}: unsupported record type {}", _0, _1)]
+    UnsupportedRecordType(usize, String),
+    #[error("Line {}: the data goes beyond the 32-bit address space", _0)]
+    AddressOverflow(usize),
+    #[error("The file has no end of file record")]
+    MissingEndOfFile,
+    #[error("The data at {:#010x} is defined more than once", _0)]
+    Overlap(u32),
+    #[error("I/O error: {}", _0)]
+    IoError(#[from] io::Error),
+}
+
+/// A contiguous block of data at an address
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Block {
+    /// The address of the first byte
+    pub addr: u32,
+    /// The data
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    /// Returns the address right after the end of the block
+    pub fn end(&self) -> u64 {
+        self.addr as u64 + self.data.len() as u64
+    }
+}
+
+/// Merges the `blocks` that share a sector of `sector_len` bytes into one block, where the gaps
+/// between them are filled with erased flash
+///
+/// Flash is erased a sector at a time, so writing the blocks one at a time would erase the blocks
+/// that were already written to the same sector. Later blocks take precedence where they overlap.
+pub fn merge_by_sector(blocks: &[Block], sector_len: u32) -> Vec<Block> {
+    let sector_len = sector_len as u64;
+    let mut sorted: Vec<&Block> = blocks
+        .iter()
+        .filter(|block| !block.data.is_empty())
+        .collect();
+    let mut merged: Vec<Block> = vec![];
+
+    sorted.sort_by_key(|block| block.addr);
+
+    for block in sorted {
+        match merged.last_mut() {
+            Some(last) if block.addr as u64 / sector_len <= (last.end() - 1) / sector_len => {
+                let offset = (block.addr - last.addr) as usize;
+                let end = offset + block.data.len();
+
+                if last.data.len() < end {
+                    last.data.resize(end, 0xff);
+                }
+
+                last.data[offset..end].copy_from_slice(&block.data);
+            }
+            _ => merged.push(block.clone()),
+        }
+    }
+
+    merged
+}
+
+/// The contents of an Intel HEX or S-record file
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HexFile {
+    /// The blocks of data, sorted by address
+    pub blocks: Vec<Block>,
+    /// The start address, if the file has one
+    pub entry: Option<u32>,
+}
+
+impl HexFile {
+    /// Parses an Intel HEX file
+    pub fn from_intel_hex(s: &str) -> Result<HexFile, HexError> {
+        let mut records = vec![];
+        let mut entry = None;
+        let mut base = 0u32;
+        let mut eof = false;
+
+        for (line, record) in lines_of(s) {
+            let record = record
+                .strip_prefix(':')
+                .ok_or(HexError::MissingStartCode(line, ':'))?;
+            let bytes = record_bytes(line, record)?;
+
+            // The record is the length, address, type, data and checksum
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(HexError::InvalidLength(line));
+            }
+
+            verify_checksum(line, &bytes, |sum| 0u8.wrapping_sub(sum))?;
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+
+            match bytes[3] {
+                // Data
+                0x00 => {
+                    let addr = base + offset;
+
+                    if addr as u64 + data.len() as u64 > 1 << 32 {
+                        return Err(HexError::AddressOverflow(line));
+                    }
+
+                    records.push((line, addr, data.to_vec()));
+                }
+                // End of file
+                0x01 => {
+                    eof = true;
+                    break;
+                }
+                // Extended segment address, which is the segment * 16
+                0x02 if data.len() == 2 => base = (u16_of(data) as u32) << 4,
+                // Start segment address, which is CS:IP
+                0x03 if data.len() == 4 => {
+                    entry = Some(((u16_of(&data[..2]) as u32) << 4) + u16_of(&data[2..]) as u32)
+                }
+                // Extended linear address, which is the upper 16 bits of the address
+                0x04 if data.len() == 2 => base = (u16_of(data) as u32) << 16,
+                // Start linear address
+                0x05 if data.len() == 4 => {
+                    entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+                }
+                0x02..=0x05 => return Err(HexError::InvalidLength(line)),
+                typ => return Err(HexError::UnsupportedRecordType(line, typ.to_string())),
+            }
+        }
+
+        if !eof {
+            return Err(HexError::MissingEndOfFile);
+        }
+
+        Ok(HexFile {
+            blocks: merge_records(records)?,
+            entry,
+        })
+    }
+
+    /// Parses a Motorola S-record file
+    pub fn from_srec(s: &str) -> Result<HexFile, HexError> {
+        let mut records = vec![];
+        let mut entry = None;
+
+        for (line, record) in lines_of(s) {
+            let record = record
+                .strip_prefix('S')
+                .ok_or(HexError::MissingStartCode(line, 'S'))?;
+            let typ = record.chars().next().ok_or(HexError::InvalidLength(line))?;
+            let bytes = record_bytes(line, &record[typ.len_utf8()..])?;
+
+            // The record is the byte count, address, data and checksum
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(HexError::InvalidLength(line));
+            }
+
+            verify_checksum(line, &bytes, |sum| !sum)?;
+
+            // The width of the address depends on the record type
+            let addr_len = match typ {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(HexError::UnsupportedRecordType(line, format!("S{}", typ))),
+            };
+
+            if bytes.len() < addr_len + 2 {
+                return Err(HexError::InvalidLength(line));
+            }
+
+            let addr = bytes[1..=addr_len]
+                .iter()
+                .fold(0u32, |addr, &b| (addr << 8) | b as u32);
+            let data = &bytes[addr_len + 1..bytes.len() - 1];
+
+            match typ {
+                '1' | '2' | '3' => {
+                    if addr as u64 + data.len() as u64 > 1 << 32 {
+                        return Err(HexError::AddressOverflow(line));
+                    }
+
+                    records.push((line, addr, data.to_vec()));
+                }
+                '7' | '8' | '9' => entry = Some(addr),
+                // The header and record counts carry no data
+                _ => {}
+            }
+        }
+
+        Ok(HexFile {
+            blocks: merge_records(records)?,
+            entry,
+        })
+    }
+
+    /// Reads an Intel HEX or S-record file, depending on the first record
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<HexFile, HexError> {
+        let s = std::fs::read_to_string(path)?;
+
+        if s.trim_start().starts_with('S') {
+            HexFile::from_srec(&s)
+        } else {
+            HexFile::from_intel_hex(&s)
+        }
+    }
+
+    /// Writes the blocks and start address as an Intel HEX file
+    pub fn write_intel_hex<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        let mut upper = 0u16;
+        let mut s = String::new();
+
+        for block in &self.blocks {
+            let mut addr = block.addr;
+
+            // Split records at 64K boundaries, since their address is only 16 bits wide
+            for chunk in chunks_within_64k(addr, &block.data) {
+                let block_upper = (addr >> 16) as u16;
+
+                if block_upper != upper {
+                    upper = block_upper;
+                    push_hex_record(&mut s, 0, 0x04, &upper.to_be_bytes());
+                }
+
+                push_hex_record(&mut s, addr as u16, 0x00, chunk);
+                addr = addr.wrapping_add(chunk.len() as u32);
+            }
+        }
+
+        if let Some(entry) = self.entry {
+            push_hex_record(&mut s, 0, 0x05, &entry.to_be_bytes());
+        }
+
+        push_hex_record(&mut s, 0, 0x01, &[]);
+
+        writer.write_all(s.as_bytes())
+    }
+}
+
+/// Returns the non-empty lines of `s` with their line number
+fn lines_of(s: &str) -> impl Iterator<Item = (usize, &str)> {
+    s.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Decodes the hexadecimal characters of a record
+fn record_bytes(line: usize, record: &str) -> Result<Vec<u8>, HexError> {
+    if record.len() % 2 != 0 || !record.is_ascii() {
+        return Err(HexError::InvalidHex(line));
+    }
+
+    (0..record.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(&record[n..n + 2], 16).map_err(|_| HexError::InvalidHex(line)))
+        .collect()
+}
+
+/// Asserts that the last byte of `bytes` is the checksum of the others, as computed by `checksum`
+/// from their sum
+fn verify_checksum(line: usize, bytes: &[u8], checksum: fn(u8) -> u8) -> Result<(), HexError> {
+    let (actual, rest) = bytes.split_last().ok_or(HexError::InvalidLength(line))?;
+    let expected = checksum(rest.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+
+    if expected != *actual {
+        return Err(HexError::InvalidChecksum(line, expected, *actual));
+    }
+
+    Ok(())
+}
+
+fn u16_of(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+/// Sorts the `(line, address, data)` records and merges adjacent ones into blocks
+fn merge_records(mut records: Vec<(usize, u32, Vec<u8>)>) -> Result<Vec<Block>, HexError> {
+    records.sort_by_key(|(line, addr, _)| (*addr, *line));
+
+    let mut blocks: Vec<Block> = vec![];
+
+    for (_, addr, data) in records.into_iter().filter(|(_, _, data)| !data.is_empty()) {
+        match blocks.last_mut() {
+            Some(block) if (addr as u64) < block.end() => return Err(HexError::Overlap(addr)),
+            Some(block) if addr as u64 == block.end() => block.data.extend_from_slice(&data),
+            _ => blocks.push(Block { addr, data }),
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Splits `data` at `addr` into chunks of up to `HEX_RECORD_LEN` bytes that don't cross a 64K
+/// boundary
+fn chunks_within_64k(addr: u32, data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut addr = addr as u64;
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let to_boundary = 0x1_0000 - (addr & 0xffff);
+        let len = rest.len().min(HEX_RECORD_LEN).min(to_boundary as usize);
+        let (chunk, tail) = rest.split_at(len);
+
+        chunks.push(chunk);
+        addr += len as u64;
+        rest = tail;
+    }
+
+    chunks
+}
+
+fn push_hex_record(s: &mut String, addr: u16, typ: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(typ);
+    bytes.extend_from_slice(data);
+    bytes.push(0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))));
+
+    s.push(':');
+
+    for b in bytes {
+        let _ = write!(s, "{:02X}", b);
+    }
+
+    s.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_intel_hex() {
+        let hex = ":020000042300D7\n\
+                   :0400000001020304F2\n\
+                   :0400040005060708DE\n\
+                   :0400000523000000D4\n\
+                   :00000001FF\n";
+        let file = HexFile::from_intel_hex(hex).unwrap();
+
+        assert_eq!(
+            file.blocks,
+            [Block {
+                addr: 0x2300_0000,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8]
+            }]
+        );
+        assert_eq!(file.entry, Some(0x2300_0000));
+
+        assert!(matches!(
+            HexFile::from_intel_hex(":0400000001020304F2\n:0400000001020304F2\n:00000001FF\n"),
+            Err(HexError::Overlap(0))
+        ));
+        assert!(matches!(
+            HexFile::from_intel_hex(":0400000001020304F3\n:00000001FF\n"),
+            Err(HexError::InvalidChecksum(1, 0xf2, 0xf3))
+        ));
+        assert!(matches!(
+            HexFile::from_intel_hex(":0400000001020304F2\n"),
+            Err(HexError::MissingEndOfFile)
+        ));
+    }
+
+    #[test]
+    fn it_should_parse_srec() {
+        let srec = "S00600004844521B\n\
+                    S107000001020304EE\n\
+                    S3092201000405060708B5\n\
+                    S5030002FA\n\
+                    S70522010000D7\n";
+        let file = HexFile::from_srec(srec).unwrap();
+
+        assert_eq!(
+            file.blocks,
+            [
+                Block {
+                    addr: 0x0,
+                    data: vec![1, 2, 3, 4]
+                },
+                Block {
+                    addr: 0x2201_0004,
+                    data: vec![5, 6, 7, 8]
+                }
+            ]
+        );
+        assert_eq!(file.entry, Some(0x2201_0000));
+        assert!(matches!(
+            HexFile::from_srec("S107000001020304EF\n"),
+            Err(HexError::InvalidChecksum(1, 0xee, 0xef))
+        ));
+    }
+
+    #[test]
+    fn it_should_write_intel_hex() {
+        // Cross a 64K boundary to get an extended linear address record in the middle
+        let file = HexFile {
+            blocks: vec![Block {
+                addr: 0x2300_fff8,
+                data: (0..24).collect(),
+            }],
+            entry: Some(0x2300_0000),
+        };
+
+        let mut buf = vec![];
+        file.write_intel_hex(&mut buf).unwrap();
+
+        let s = String::from_utf8(buf).unwrap();
+
+        assert_eq!(
+            s.lines().collect::<Vec<_>>(),
+            [
+                ":020000042300D7",
+                ":08FFF8000001020304050607E5",
+                ":020000042301D6",
+                ":1000000008090A0B0C0D0E0F1011121314151617F8",
+                ":0400000523000000D4",
+                ":00000001FF",
+            ]
+        );
+        assert_eq!(HexFile::from_intel_hex(&s).unwrap(), file);
+    }
+}
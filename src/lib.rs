@@ -0,0 +1,20 @@
+//! Library for communicating with the BL60x BootROM and eflash_loader over UART, and for working
+//! with BL60x firmware images and the ELF files they are built from
+//!
+//! This is what the `bouffalo-cli` binary is built on.
+
+pub mod bl;
+pub mod bl60x;
+pub mod device_tree;
+pub mod elf_parser;
+pub mod error;
+pub mod hex_file;
+
+pub use bl::{Firmware, FirmwareBuilder};
+pub use bl60x::{Bl60xSerialPort, IspError};
+pub use elf_parser::ElfParser;
+pub use error::SerialError;
+
+/// An address in the memory map of the device
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
+pub struct VirtAddr(pub u32);
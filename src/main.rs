@@ -1,37 +1,78 @@
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{BufReader, Cursor, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{anyhow, Context};
-use log::{debug, error};
+use log::{debug, error, warn};
 use sha2::{Digest, Sha256};
 use structopt::StructOpt;
 
-mod bl;
-mod bl60x;
+use bouffalo_cli::bl::boot2::{self, Boot2Image};
+use bouffalo_cli::bl::image;
+use bouffalo_cli::bl::ota::OtaImage;
+use bouffalo_cli::bl::partition::{PartitionTable, PARTITION_TABLE_OFFSETS};
+use bouffalo_cli::bl::size_report::{Budget, SizeReport};
+use bouffalo_cli::bl::{
+    self, conf, diff, encryption, factory_params, flash_presets, memory_map, signature, AesKey,
+    BootHeader, Firmware, HeaderFormat, SignatureType,
+};
+use bouffalo_cli::bl60x::trace::{ReplayTransport, TracingTransport};
+use bouffalo_cli::bl60x::{self, Bl60xSerialPort, RetryPolicy};
+use bouffalo_cli::device_tree::{self, DeviceTree};
+use bouffalo_cli::elf_parser;
+use bouffalo_cli::hex_file::{Block, HexFile};
+
 mod cli;
-mod elf_parser;
-mod error;
+mod config;
+mod progress;
 
-use bl::Firmware;
-use bl60x::Bl60xSerialPort;
-pub use error::SerialError;
+use progress::ProgressBarObserver;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
-pub struct VirtAddr(u32);
+/// Opens the serial port given in `global_opts`, or the transcript to replay, and sets up
+/// tracing if requested
+fn open_port(global_opts: &cli::Opts) -> Result<Bl60xSerialPort, anyhow::Error> {
+    let mut port = if let Some(ref replay_file) = global_opts.replay_file {
+        println!("Replaying transcript {}", replay_file.display());
 
-fn get_boot_info(global_opts: &cli::Opts) -> Result<(), anyhow::Error> {
-    let serial_port = &global_opts.serial_port;
-    let baud_rate = global_opts.baud_rate;
+        let file = File::open(replay_file).with_context(|| "Could not open transcript")?;
+        let transport = ReplayTransport::from_reader(BufReader::new(file))
+            .with_context(|| "Could not parse transcript")?;
+
+        Bl60xSerialPort::with_transport(Box::new(transport))
+    } else {
+        let serial_port = global_opts.serial_port();
+        let baud_rate = global_opts.baud_rate();
+
+        println!("Using serial device {:?}", serial_port);
+
+        // Open a serial port to the blx602 device
+        match global_opts.trace_file {
+            Some(ref trace_file) => {
+                let serial = bl60x::open_serial(serial_port, baud_rate)
+                    .with_context(|| "Could not open serial port")?;
+                let file =
+                    File::create(trace_file).with_context(|| "Could not create trace file")?;
+                let transport = TracingTransport::new(serial, file)
+                    .with_context(|| "Could not write to trace file")?;
+
+                Bl60xSerialPort::with_transport(Box::new(transport))
+            }
+            None => Bl60xSerialPort::open_with_baud_rate(serial_port, baud_rate)
+                .with_context(|| "Could not open serial port")?,
+        }
+    };
 
-    println!("Using serial device {:?}", &serial_port);
+    // Show the progress of loading segments into RAM and of flash transfers
+    port.set_progress_observer(Box::new(ProgressBarObserver::new()));
 
-    // Open a serial port to the blx602 device
-    let mut port = bl60x::Bl60xSerialPort::open_with_baud_rate(serial_port, baud_rate)
-        .with_context(|| "Could not open serial port")?;
+    Ok(port)
+}
+
+fn get_boot_info(global_opts: &cli::Opts) -> Result<(), anyhow::Error> {
+    let mut port = open_port(global_opts)?;
 
     // Put the BootROM into UART mode
     port.enter_uart_mode()
@@ -68,17 +109,22 @@ fn get_boot_info(global_opts: &cli::Opts) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-///
+/// Loads the eflash_loader firmware into RAM on the device and runs it
 fn load_flasher(port: &mut Bl60xSerialPort) -> Result<(), anyhow::Error> {
+    // Parse the eflash_loader firmware
+    let fw = Firmware::from_reader(Cursor::new(&bl::EFLASH_LOADER_40M_BIN))?;
+
+    load_image(port, &fw)
+}
+
+/// Loads a firmware image into RAM on the device through the BootROM and runs it
+fn load_image(port: &mut Bl60xSerialPort, fw: &Firmware) -> Result<(), anyhow::Error> {
     // Put the BootROM into UART mode
     port.enter_uart_mode()?;
 
     // Wait for 20ms
     thread::sleep(Duration::from_millis(20));
 
-    // Parse the eflash_loader firmware
-    let fw = Firmware::from_reader(Cursor::new(&bl::EFLASH_LOADER_40M_BIN))?;
-
     // Write the boot header into our buffer
     let mut buf: Vec<u8> = Vec::with_capacity(4096);
     fw.write_to(&mut buf)?;
@@ -95,8 +141,8 @@ fn load_flasher(port: &mut Bl60xSerialPort) -> Result<(), anyhow::Error> {
     };
 
     // Load the firmware segments
-    for segment in fw.segments {
-        port.load_segment(&segment)?;
+    for segment in &fw.segments {
+        port.load_segment(segment)?;
     }
 
     port.check_image()?;
@@ -105,39 +151,926 @@ fn load_flasher(port: &mut Bl60xSerialPort) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-fn elf2image<P: AsRef<Path>>(input_path: P) -> Result<(), anyhow::Error> {
-    let file = File::open(&input_path)?;
-    let parser = elf_parser::ElfParser::parse(file).with_context(|| {
+/// Reads the blocks of data and the entry point of the elf image, Intel HEX file, S-record file
+/// or raw binary at `path`
+fn read_input_file(path: &Path, base: Option<u32>) -> Result<HexFile, anyhow::Error> {
+    image::read_input_file(path, base)
+        .with_context(|| format!("Failed to read '{}'", path.display()))
+}
+
+/// Returns the board given in the board `opts`, with the flash chip on the connected device if
+/// it's requested, or if none is given and `detect_by_default` is set
+///
+/// The flash chip is read with `jedec_id`, and isn't needed when there's a configuration file.
+fn read_board<F>(
+    opts: &cli::BoardOpts,
+    detect_by_default: bool,
+    jedec_id: F,
+) -> Result<image::Board, anyhow::Error>
+where
+    F: FnOnce() -> Result<u32, anyhow::Error>,
+{
+    let conf: Option<conf::Conf> = match opts.conf {
+        Some(ref path) => {
+            let contents = std::fs::read_to_string(path)?;
+
+            Some(
+                contents
+                    .parse()
+                    .with_context(|| format!("Failed to parse '{}'", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    let flash = if conf.is_some() {
+        None
+    } else {
+        let preset = match opts.flash {
+            Some(ref flash) => image::flash_preset(flash)?,
+            None if opts.detect_flash || detect_by_default => {
+                image::flash_preset_by_jedec_id(jedec_id()?)?
+            }
+            None => image::flash_preset(image::DEFAULT_FLASH)?,
+        };
+
+        println!("Flash: {}", preset);
+
+        Some(preset)
+    };
+
+    Ok(image::Board {
+        conf,
+        flash,
+        xtal: opts.xtal,
+        pll: opts.pll,
+        flash_clock: opts.flash_clock,
+    })
+}
+
+/// Returns the input file given on the command-line or by the profile, where `key` is the
+/// setting of the profile
+fn input_file<'a>(path: &'a Option<PathBuf>, key: &str) -> Result<&'a Path, anyhow::Error> {
+    path.as_deref().ok_or_else(|| {
+        anyhow!(
+            "No input file was given, and the profile has no {} setting",
+            key
+        )
+    })
+}
+
+fn elf2image(opts: &cli::Elf2ImageOpts, global_opts: &cli::Opts) -> Result<(), anyhow::Error> {
+    let input_path = input_file(&opts.filename, "elf")?;
+
+    println!("Converting elf image {} to firmware", input_path.display());
+
+    let input = read_input_file(input_path, opts.base)?;
+    let board = read_board(&opts.board, false, || {
+        Ok(connect_eflash_loader(global_opts)?.read_jedec_id()?)
+    })?;
+
+    // Encrypt the image if a key was given
+    let aes = match (&opts.aes_key, &opts.aes_iv) {
+        (Some(key_path), Some(iv)) => Some((
+            read_aes_key(key_path)?,
+            encryption::parse_iv(iv).with_context(|| "Invalid AES IV")?,
+        )),
+        _ => None,
+    };
+
+    let image = image::build_image(&input, &board, aes).with_context(|| {
         format!(
-            "Failed to parse header of ELF file '{}'",
-            input_path.as_ref().display()
+            "Failed to build a firmware image from '{}'",
+            input_path.display()
         )
     })?;
 
-    let fw = Firmware::builder()
-        .entry_point(0x1337)
-        .build()
-        .with_context(|| "Failed to build firmware image")?;
+    println!("{}", image.firmware.clock_config());
+
+    for block in &input.blocks {
+        // The segment is in a region since it has been validated
+        println!(
+            "Segment: {:#010x} ({} bytes, {})",
+            block.addr,
+            block.data.len(),
+            memory_map::region_of(block.addr).unwrap().name
+        );
+    }
+
+    let output = opts
+        .output
+        .clone()
+        .unwrap_or_else(|| input_path.with_extension("bin"));
+
+    // Don't overwrite a raw binary with the image built from it
+    if output == input_path {
+        return Err(anyhow!(
+            "The firmware image would overwrite '{}', so it needs an --output filename",
+            input_path.display()
+        ));
+    }
+
+    let buf = image.to_bytes()?;
+
+    if let Some(ref data) = image.xip_data {
+        println!(
+            "Image: {} bytes at flash offset {:#x}",
+            data.len(),
+            image.firmware.image_start()
+        );
+    }
+
+    std::fs::write(&output, buf)
+        .with_context(|| format!("Failed to write firmware image '{}'", output.display()))?;
 
-    println!("ELF header: {:?}", parser);
-    println!("Firmware: {:?}", fw);
+    println!(
+        "Wrote {} with entry point {:#010x}",
+        output.display(),
+        image.firmware.entry_point()
+    );
 
     Ok(())
 }
 
-fn flash_command(
-    command: &cli::FlashCommand,
+/// Reads the AES key in the key file at `path`
+fn read_aes_key(path: &Path) -> Result<AesKey, anyhow::Error> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Could not read AES key file '{}'", path.display()))?;
+
+    AesKey::from_file_contents(&contents)
+        .with_context(|| format!("Invalid AES key in '{}'", path.display()))
+}
+
+/// Prints the boot header and the segments of the firmware image at `path`, decrypting it with
+/// the key in `key_path` if it's encrypted
+fn image_info<P: AsRef<Path>>(path: P, key_path: Option<&Path>) -> Result<(), anyhow::Error> {
+    let key = match key_path {
+        Some(key_path) => Some(read_aes_key(key_path)?),
+        None => None,
+    };
+
+    let data = std::fs::read(&path)?;
+    let fw =
+        Firmware::from_reader_with_key(Cursor::new(&data), key.as_ref()).with_context(|| {
+            format!(
+                "Failed to parse firmware image '{}'",
+                path.as_ref().display()
+            )
+        })?;
+
+    let boot_config = fw.boot_config();
+
+    if let Ok(boot2) = Boot2Image::from_bytes(&data) {
+        println!("boot2: {}", boot2.version);
+    }
+
+    println!("CPU: {:?}", fw.cpu());
+    println!("Revision: {}", fw.revision());
+    println!("Entry point: {:#010x}", fw.entry_point());
+    println!("Image start: {:#010x}", fw.image_start());
+
+    if boot_config.no_segment {
+        println!("Image length: {}", fw.image_segment_info());
+    } else {
+        println!("Segments: {}", fw.image_segment_info());
+    }
+
+    println!("Hash: {}", hex(fw.hash()));
+    println!("CRC32: {:#010x}", fw.crc32());
+
+    if let Some(iv) = fw.aes_iv() {
+        println!("AES IV: {}", hex(iv));
+    }
+
+    println!("{}", fw.clock_config());
+    println!("{}", boot_config);
+
+    if let Some(ciphertext) = fw.encrypted_segments() {
+        println!(
+            "The segments are encrypted ({} bytes), give --aes-key to decrypt them",
+            ciphertext.len()
+        );
+    }
+
+    for (n, segment) in fw.segments.iter().enumerate() {
+        let valid = segment.crc32 == bl::crc32(&segment.header_bytes()[0x0..0xc]);
+
+        println!(
+            "Segment {}: {:#010x} ({} bytes, crc32 {:#010x}{})",
+            n,
+            segment.dest_addr.0,
+            segment.data.len(),
+            segment.crc32,
+            if valid { "" } else { ", invalid" }
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints the flash, clock and boot configuration of the firmware image at `path` in the format of
+/// the vendor configuration files
+fn image_conf<P: AsRef<Path>>(path: P) -> Result<(), anyhow::Error> {
+    let file = File::open(&path)?;
+    let fw = Firmware::from_reader(BufReader::new(file)).with_context(|| {
+        format!(
+            "Failed to parse firmware image '{}'",
+            path.as_ref().display()
+        )
+    })?;
+
+    let mut section = conf::Section::new("BOOTHEADER_CFG");
+
+    conf::write_flash_config(fw.flash_config(), &mut section);
+    conf::write_clock_config(fw.clock_config(), &mut section);
+    conf::write_boot_config(fw.boot_config(), &mut section);
+
+    let conf = conf::Conf {
+        sections: vec![section],
+    };
+
+    print!("{}", conf);
+
+    Ok(())
+}
+
+/// Prints the version and boot header of the boot2 image at `path`, and which firmware image boot2
+/// boots if it's a whole-flash image
+fn boot2_info(path: &Path) -> Result<(), anyhow::Error> {
+    let data = std::fs::read(path)?;
+    let boot2 = Boot2Image::from_bytes(&data)
+        .with_context(|| format!("Failed to parse boot2 image '{}'", path.display()))?;
+
+    println!("boot2: {}", boot2.version);
+    println!(
+        "Code: {:#010x} ({} bytes)",
+        boot2.firmware.image_start(),
+        boot2.code.len()
+    );
+    println!("{}", boot2.firmware.clock_config());
+
+    if !PartitionTable::is_flash_dump(&data) {
+        return Ok(());
+    }
+
+    // Follow the handoff from boot2 to the firmware image
+    let target = boot2::boot_target(&data)?;
+
+    println!(
+        "Boots: {} partition at {:#010x}{}",
+        target.entry.name,
+        target.address,
+        if target.fallback {
+            ", since there's no image at the active address"
+        } else {
+            ""
+        }
+    );
+
+    Ok(())
+}
+
+/// Reads the flash of the device up to and including the partition tables, where everything in
+/// front of them is left erased
+fn read_partition_tables(port: &mut Bl60xSerialPort) -> Result<Vec<u8>, anyhow::Error> {
+    let tables_start = PARTITION_TABLE_OFFSETS[0] as usize;
+    let mut flash = vec![0xffu8; PARTITION_TABLE_OFFSETS[1] as usize + 0x1000];
+
+    port.read_flash(PARTITION_TABLE_OFFSETS[0], &mut flash[tables_start..])?;
+
+    Ok(flash)
+}
+
+/// Writes the boot2 image at `path` to the start of flash with the flash and clock configuration
+/// of the board, without touching the partition tables that follow it
+fn boot2_install(
+    path: &Path,
+    board: &cli::BoardOpts,
     global_opts: &cli::Opts,
 ) -> Result<(), anyhow::Error> {
-    use cli::FlashCommand;
+    let data = std::fs::read(path)?;
+
+    // Parse the image before connecting, so a wrong file doesn't get anywhere near the device
+    let image = if data.starts_with(b"BFNP") {
+        Some(
+            Boot2Image::from_bytes(&data)
+                .with_context(|| format!("Failed to parse boot2 image '{}'", path.display()))?,
+        )
+    } else {
+        None
+    };
+
+    let mut port = connect_eflash_loader(global_opts)?;
+
+    // Warn if there's no partition table, since boot2 has nothing to boot without one
+    match PartitionTable::from_flash(&read_partition_tables(&mut port)?) {
+        Ok(table) => println!(
+            "Partition table: {} entries, age {}",
+            table.entries.len(),
+            table.age
+        ),
+        Err(err) => warn!("The device has no valid partition table: {}", err),
+    }
+
+    let clock_config = image
+        .as_ref()
+        .map(|image| *image.firmware.clock_config())
+        .unwrap_or_default();
+    let board = read_board(board, true, || Ok(port.read_jedec_id()?))?;
+    let flash_config = board.flash_config()?;
+    let clock_config = board.clock_config(clock_config)?;
+
+    println!("{}", clock_config);
+
+    let image = match image {
+        Some(mut image) => {
+            image.set_config(flash_config, clock_config);
+            image
+        }
+        None => Boot2Image::from_code(data, flash_config, clock_config)
+            .with_context(|| format!("Failed to build boot2 image '{}'", path.display()))?,
+    };
+
+    let buf = image.to_bytes()?;
+
+    println!("Installing boot2 {} ({} bytes)", image.version, buf.len());
+
+    port.set_timeout(Duration::from_secs(60))?;
+    port.write_flash(0, &buf)?;
+    port.set_timeout(Duration::from_secs(2))?;
+
+    Ok(())
+}
+
+/// Compiles the device tree source at `path` to a device tree blob
+fn dts_compile(path: &Path, output: Option<&Path>) -> Result<(), anyhow::Error> {
+    let source = std::fs::read_to_string(path)?;
+    let tree = DeviceTree::from_source(&source)
+        .with_context(|| format!("Failed to compile '{}'", path.display()))?;
+
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| path.with_extension("dtb"));
+    let blob = tree.to_bytes();
+
+    std::fs::write(&output, &blob)
+        .with_context(|| format!("Failed to write '{}'", output.display()))?;
+
+    println!("Wrote {} ({} bytes)", output.display(), blob.len());
+
+    Ok(())
+}
+
+/// Reads the device tree blob `data`, or the one in the factory partition if it's a whole-flash
+/// image
+fn read_device_tree(data: &[u8]) -> Result<DeviceTree, anyhow::Error> {
+    let tree = if DeviceTree::is_device_tree(data) {
+        DeviceTree::from_bytes(data)?
+    } else {
+        factory_params::read_device_tree(data)?
+    };
+
+    Ok(tree)
+}
+
+/// Prints the device tree in the device tree blob or whole-flash image at `path` as source
+fn dts_decompile(path: &Path) -> Result<(), anyhow::Error> {
+    let data = std::fs::read(path)?;
+    let tree = read_device_tree(&data)
+        .with_context(|| format!("Failed to read the device tree of '{}'", path.display()))?;
+
+    print!("{}", tree);
 
-    println!("Using serial device {:?}", &global_opts.serial_port);
+    Ok(())
+}
+
+/// Applies `patch` to `tree` and prints the properties that were changed
+fn patch_device_tree(
+    tree: &mut DeviceTree,
+    patch: &factory_params::FactoryPatch,
+) -> Result<(), anyhow::Error> {
+    for path in patch.apply(tree)? {
+        // The property exists since it was just set
+        let (node, _) = path.split_at(path.rfind('/').unwrap());
+
+        println!("{}/{}", node, tree.property(&path).unwrap());
+    }
+
+    Ok(())
+}
+
+/// Patches the factory parameters in the device tree blob or whole-flash image at `path`, or in
+/// the flash of the connected device if there's no `path`
+fn dts_patch(
+    path: Option<&Path>,
+    output: Option<&Path>,
+    patch: &factory_params::FactoryPatch,
+    global_opts: &cli::Opts,
+) -> Result<(), anyhow::Error> {
+    if patch.is_empty() {
+        return Err(anyhow!("Nothing to change, see --help for the parameters"));
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => return dts_patch_device(patch, global_opts),
+    };
+
+    let mut data = std::fs::read(path)?;
+    let mut tree = read_device_tree(&data)
+        .with_context(|| format!("Failed to read the device tree of '{}'", path.display()))?;
+
+    patch_device_tree(&mut tree, patch)?;
+
+    if DeviceTree::is_device_tree(&data) {
+        data = tree.to_bytes();
+    } else {
+        factory_params::write_device_tree(&mut data, &tree)?;
+    }
+
+    let output = output.unwrap_or(path);
+
+    std::fs::write(output, data)
+        .with_context(|| format!("Failed to write '{}'", output.display()))?;
+
+    println!("Wrote {}", output.display());
+
+    Ok(())
+}
+
+/// Patches the factory parameters in the flash of the connected device
+fn dts_patch_device(
+    patch: &factory_params::FactoryPatch,
+    global_opts: &cli::Opts,
+) -> Result<(), anyhow::Error> {
+    let mut port = connect_eflash_loader(global_opts)?;
+
+    // Find the device tree through the partition table of the device
+    let table = PartitionTable::from_flash(&read_partition_tables(&mut port)?)?;
+    let (offset, max_len) = factory_params::factory_partition(&table)?;
+
+    let mut header = [0u8; device_tree::FDT_HEADER_LEN];
+    port.read_flash(offset, &mut header)?;
+
+    let len = DeviceTree::total_size(&header)?;
+
+    if len > max_len {
+        return Err(anyhow!(
+            "The device tree at {:#010x} is {} bytes, which is larger than the partition",
+            offset,
+            len
+        ));
+    }
+
+    let mut blob = vec![0u8; len as usize];
+    port.read_flash(offset, &mut blob)?;
+
+    let mut tree = DeviceTree::from_bytes(&blob)?;
+
+    patch_device_tree(&mut tree, patch)?;
+
+    let blob = tree.to_bytes();
+    factory_params::check_len(&blob, max_len)?;
+
+    println!(
+        "Writing {} bytes of device tree to flash at {:#010x}",
+        blob.len(),
+        offset
+    );
+
+    port.set_timeout(Duration::from_secs(60))?;
+    port.write_flash(offset, &blob)?;
+    port.set_timeout(Duration::from_secs(2))?;
+
+    Ok(())
+}
+
+/// Compares the firmware images or flash dumps at `left` and `right` and prints the differences
+fn image_diff(left: &Path, right: &Path) -> Result<(), anyhow::Error> {
+    let left_data = std::fs::read(left)?;
+    let right_data = std::fs::read(right)?;
+
+    let diffs = diff::diff_images(&left_data, &right_data)?;
+
+    if diffs.is_empty() {
+        println!("No differences");
+    }
+
+    for diff in diffs {
+        println!("{}", diff);
+    }
 
+    Ok(())
+}
+
+/// Loads a firmware image, or an image built from an elf image, Intel HEX file, S-record file or
+/// raw binary, into RAM on the device and runs it
+fn ram_run(path: &Path, base: Option<u32>, global_opts: &cli::Opts) -> Result<(), anyhow::Error> {
+    let data = std::fs::read(path)?;
+
+    let fw = if data.starts_with(b"BFNP") {
+        Firmware::from_reader(Cursor::new(&data))
+            .with_context(|| format!("Failed to parse firmware image '{}'", path.display()))?
+    } else {
+        let input = read_input_file(path, base)?;
+
+        // The BootROM doesn't touch the flash when loading into RAM, but the boot header still
+        // needs a flash configuration
+        let preset = image::flash_preset(image::DEFAULT_FLASH)?;
+
+        image::build_ram_image(&input, preset.flash_config()).with_context(|| {
+            format!("Failed to build a firmware image from '{}'", path.display())
+        })?
+    };
+
+    let mut port = open_port(global_opts)?;
+
+    println!(
+        "Loading {} segment(s) with entry point {:#010x}",
+        fw.segments.len(),
+        fw.entry_point()
+    );
+
+    load_image(&mut port, &fw)?;
+
+    println!("Running {}", path.display());
+
+    Ok(())
+}
+
+/// Writes the firmware image or flash dump at `path` as an Intel HEX file, either as it's stored
+/// in flash at `address`, or as the segments of the image at their load addresses
+fn image_export_hex(
+    path: &Path,
+    output: &Path,
+    address: u32,
+    segments: bool,
+) -> Result<(), anyhow::Error> {
+    let hex_file = if segments {
+        let (_, fw) = read_firmware(path, None)?;
+
+        HexFile {
+            blocks: fw
+                .segments
+                .iter()
+                .map(|segment| Block {
+                    addr: segment.dest_addr.0,
+                    data: segment.data.clone(),
+                })
+                .collect(),
+            entry: Some(fw.entry_point()),
+        }
+    } else {
+        HexFile {
+            blocks: vec![Block {
+                addr: address,
+                data: std::fs::read(path)?,
+            }],
+            entry: None,
+        }
+    };
+
+    let mut file = File::create(output)?;
+    hex_file.write_intel_hex(&mut file)?;
+
+    println!("Wrote {}", output.display());
+
+    Ok(())
+}
+
+/// Creates an OTA update image of the firmware image at `path`
+fn image_ota(
+    path: &Path,
+    version: &str,
+    compress: bool,
+    output: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let (data, _) = read_firmware(path, None)?;
+    let ota = OtaImage::new(&data, version, compress)?;
+
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None if compress => path.with_file_name("FW_OTA.bin.xz.ota"),
+        None => path.with_file_name("FW_OTA.bin"),
+    };
+
+    let mut file = File::create(&output)?;
+    ota.write_to(&mut file)?;
+
+    println!(
+        "Wrote {} ({}, {} bytes, version {})",
+        output.display(),
+        ota.header.typ,
+        ota.header.len,
+        ota.header.software_version
+    );
+
+    Ok(())
+}
+
+/// Prints and verifies the header and the firmware image of the OTA update image at `path`
+fn image_ota_info(path: &Path) -> Result<(), anyhow::Error> {
+    let data = std::fs::read(path)?;
+    let ota = OtaImage::from_bytes(&data)
+        .with_context(|| format!("Failed to verify OTA image '{}'", path.display()))?;
+    let header = &ota.header;
+
+    println!("Type: {}", header.typ);
+    println!("Length: {} bytes", header.len);
+    println!("Hardware version: {}", header.hardware_version);
+    println!("Software version: {}", header.software_version);
+    println!("SHA-256: {} (OK)", hex(&header.sha256));
+
+    // Assert that the payload is a valid firmware image
+    let firmware = ota.firmware()?;
+    let fw = Firmware::from_reader(Cursor::new(&firmware))
+        .with_context(|| "The payload is not a valid firmware image")?;
+
+    println!(
+        "Firmware: {} bytes, {} segment(s), entry point {:#010x}",
+        firmware.len(),
+        fw.segments.len(),
+        fw.entry_point()
+    );
+
+    Ok(())
+}
+
+fn read_size_report(path: &Path) -> Result<SizeReport, anyhow::Error> {
+    let file = File::open(path)?;
+    let parser = elf_parser::ElfParser::parse(file)
+        .with_context(|| format!("Failed to parse elf image '{}'", path.display()))?;
+    let class = parser.header().class;
+
+    if class != elf_parser::Class::Elf32 {
+        warn!(
+            "'{}' is an {} file, which is for {}, but the usage is reported for the BL602 memory map",
+            path.display(),
+            class,
+            class.targets()
+        );
+    }
+
+    Ok(SizeReport::from_elf(&parser)?)
+}
+
+/// Formats the change in size from `old` to `new`, if there's anything to compare against
+fn size_change(old: Option<u64>, new: u64) -> String {
+    match old {
+        Some(old) if new >= old => format!("+{}", new - old),
+        Some(old) => format!("-{}", old - new),
+        None => String::new(),
+    }
+}
+
+fn elf_size(
+    path: &Path,
+    compare: Option<&Path>,
+    budgets: &[Budget],
+    top: usize,
+) -> Result<(), anyhow::Error> {
+    let report = read_size_report(path)?;
+    let previous = compare.map(read_size_report).transpose()?;
+
+    println!(
+        "{:<16} {:>10} {:>10} {:>6} {:>8}",
+        "Region", "Used", "Size", "Use%", "Change"
+    );
+
+    for region in &report.regions {
+        let used = region.used();
+        let (size, percent) = match region.size {
+            Some(size) => (
+                size.to_string(),
+                format!("{:.1}%", used as f64 * 100.0 / size as f64),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let old = previous.as_ref().map(|p| p.used(region.name));
+
+        println!(
+            "{:<16} {:>10} {:>10} {:>6} {:>8}",
+            region.name,
+            used,
+            size,
+            percent,
+            size_change(old, used)
+        );
+
+        for section in &region.sections {
+            let name = if section.load_image {
+                format!("{} (load)", section.name)
+            } else {
+                section.name.clone()
+            };
+
+            println!("  {:<14} {:>10} {:#010x}", name, section.size, section.addr);
+
+            if section.load_image {
+                continue;
+            }
+
+            for symbol in report.largest_symbols(&section.name, top) {
+                println!("    {:<24} {:>8}", symbol.name, symbol.size);
+            }
+        }
+    }
+
+    // Print the regions that are no longer used
+    if let Some(previous) = &previous {
+        for region in &previous.regions {
+            if report.region(region.name).is_none() {
+                println!(
+                    "{:<16} {:>10} {:>10} {:>6} {:>8}",
+                    region.name,
+                    0,
+                    "",
+                    "",
+                    size_change(Some(region.used()), 0)
+                );
+            }
+        }
+    }
+
+    // Assert that the usage is within the budgets
+    let exceeded: Vec<&Budget> = budgets.iter().filter(|b| !b.is_met(&report)).collect();
+
+    for budget in &exceeded {
+        error!(
+            "{} uses {} bytes, which exceeds the budget of {} bytes",
+            budget.region,
+            report.used(budget.region),
+            budget.limit
+        );
+    }
+
+    if !exceeded.is_empty() {
+        return Err(anyhow!("{} budget(s) exceeded", exceeded.len()));
+    }
+
+    Ok(())
+}
+
+fn header_command(command: &cli::HeaderCommand) -> Result<(), anyhow::Error> {
+    use cli::HeaderCommand;
+
+    match command {
+        HeaderCommand::Export { filename, output } => {
+            let fw = Firmware::from_reader(BufReader::new(File::open(filename)?)).with_context(
+                || format!("Failed to parse firmware image '{}'", filename.display()),
+            )?;
+            let header = BootHeader::from(&fw);
+
+            match output {
+                Some(output) => {
+                    let s = header.to_string(HeaderFormat::from_path(output))?;
+
+                    std::fs::write(output, s)?;
+                }
+                None => print!("{}", header.to_string(HeaderFormat::Toml)?),
+            }
+        }
+        HeaderCommand::Import {
+            header,
+            filename,
+            output,
+        } => {
+            let s = std::fs::read_to_string(header)?;
+            let header = BootHeader::from_str(&s, HeaderFormat::from_path(header))
+                .with_context(|| format!("Failed to parse boot header '{}'", header.display()))?;
+
+            let data = std::fs::read(filename)?;
+            let mut fw = Firmware::from_reader(Cursor::new(&data)).with_context(|| {
+                format!("Failed to parse firmware image '{}'", filename.display())
+            })?;
+
+            // The signature block and the AES IV block are kept, so the header can't add them
+            if header.boot_config.encryption != fw.boot_config().encryption {
+                return Err(anyhow!(
+                    "The type of encryption can't be changed by importing a boot header"
+                ));
+            }
+
+            if header.boot_config.signature != SignatureType::None && fw.signature().is_none() {
+                return Err(anyhow!(
+                    "The image isn't signed, so import the boot header without a signature and \
+                     sign the image with `image sign`"
+                ));
+            }
+
+            let header_len = fw.header_len();
+            fw.set_boot_header(&header);
+
+            let buf = fw.replace_header(&data, header_len)?;
+            std::fs::write(output, buf)?;
+
+            if fw.signature().is_some() && fw.verify(None).is_err() {
+                warn!("The signature no longer matches the image, sign it again with `image sign`");
+            }
+
+            println!(
+                "Wrote {} with boot header crc32 {:#010x}",
+                output.display(),
+                fw.crc32()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the firmware image at `path`, decrypting it with the key in `key_path` if given
+fn read_firmware(
+    path: &Path,
+    key_path: Option<&Path>,
+) -> Result<(Vec<u8>, Firmware), anyhow::Error> {
+    let key = match key_path {
+        Some(key_path) => Some(read_aes_key(key_path)?),
+        None => None,
+    };
+
+    let data = std::fs::read(path)?;
+    let fw = Firmware::from_reader_with_key(Cursor::new(&data), key.as_ref())
+        .with_context(|| format!("Failed to parse firmware image '{}'", path.display()))?;
+
+    Ok((data, fw))
+}
+
+/// Signs the firmware image at `path` with the private key in `key_path` and writes the signed
+/// image to `output`
+fn image_sign(
+    path: &Path,
+    key_path: &Path,
+    output: &Path,
+    aes_key_path: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let pem = std::fs::read_to_string(key_path)?;
+    let key = signature::signing_key_from_pem(&pem)
+        .with_context(|| format!("Failed to read private key '{}'", key_path.display()))?;
+
+    let (data, mut fw) = read_firmware(path, aes_key_path)?;
+    let buf = fw.sign_image(&key, &data)?;
+
+    std::fs::write(output, buf)?;
+
+    println!("Wrote signed image {}", output.display());
+    println!(
+        "Public key hash: {}",
+        hex(&signature::public_key_hash(key.verifying_key()))
+    );
+
+    Ok(())
+}
+
+/// Verifies the signature of the firmware image at `path`, and that it's signed with the public
+/// key in `pubkey_path` if given
+fn image_verify(
+    path: &Path,
+    pubkey_path: Option<&Path>,
+    aes_key_path: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let pubkey = match pubkey_path {
+        Some(pubkey_path) => {
+            let pem = std::fs::read_to_string(pubkey_path)?;
+
+            Some(signature::verifying_key_from_pem(&pem).with_context(|| {
+                format!("Failed to read public key '{}'", pubkey_path.display())
+            })?)
+        }
+        None => None,
+    };
+
+    let (_, fw) = read_firmware(path, aes_key_path)?;
+
+    fw.verify(pubkey.as_ref())
+        .with_context(|| format!("Failed to verify '{}'", path.display()))?;
+
+    // The image is signed if it could be verified
+    let image_signature = fw.signature().unwrap();
+
+    println!("Signature OK");
+    println!(
+        "Public key hash: {}",
+        hex(&image_signature.public_key_hash())
+    );
+
+    Ok(())
+}
+
+/// Returns the given `bytes` as a lowercase hex string
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Opens the serial port, loads the eflash_loader and switches to the programming baud rate
+fn connect_eflash_loader(global_opts: &cli::Opts) -> Result<Bl60xSerialPort, anyhow::Error> {
     // Open the serial port
-    let mut port = bl60x::Bl60xSerialPort::open_with_baud_rate(
-        &global_opts.serial_port,
-        global_opts.baud_rate,
-    )?;
+    let mut port = open_port(global_opts)?;
+
+    port.set_retry_policy(RetryPolicy {
+        max_retries: global_opts.retries,
+        ..RetryPolicy::default()
+    });
 
     // Load fhe eflash firmware into RAM and run it
     load_flasher(&mut port)?;
@@ -145,15 +1078,37 @@ fn flash_command(
     // Wait for 100 ms
     std::thread::sleep(Duration::from_millis(100));
 
-    // Change the baud rate
-    port.set_baud_rate(global_opts.programming_baud_rate as u32)?;
-
-    // Put the BootROM into UART mode
+    // Put the eflash_loader into UART mode
     port.enter_uart_mode()?;
 
     // Wait for 20ms
     thread::sleep(Duration::from_millis(20));
 
+    // Have the eflash_loader switch to the faster baud rate for the rest of the transfers
+    let programming_baud_rate = global_opts.programming_baud_rate() as u32;
+
+    if programming_baud_rate != global_opts.baud_rate() as u32 {
+        let baud_rate = port.negotiate_baud_rate(programming_baud_rate)?;
+
+        if baud_rate != programming_baud_rate {
+            warn!(
+                "Could not use a baud rate of {}, falling back to {}",
+                programming_baud_rate, baud_rate
+            );
+        }
+    }
+
+    Ok(port)
+}
+
+fn flash_command(
+    command: &cli::FlashCommand,
+    global_opts: &cli::Opts,
+) -> Result<(), anyhow::Error> {
+    use cli::FlashCommand;
+
+    let mut port = connect_eflash_loader(global_opts)?;
+
     match command {
         FlashCommand::Read {
             address,
@@ -168,33 +1123,16 @@ fn flash_command(
                 filename.display()
             );
 
-            const READ_SIZE: usize = 8192;
-
-            let mut buf = vec![0u8; READ_SIZE];
+            let mut buf = vec![0u8; *size as usize];
             let mut file = File::create(filename)?;
-            let mut hasher = Sha256::new();
-
-            let rem = *size as usize % READ_SIZE;
-            let div = *size as usize / READ_SIZE;
-            let div = if rem > 0 { div + 1 } else { div };
-            let mut remaining = *size as usize;
-
-            for n in 0..div {
-                let s = std::cmp::min(remaining, READ_SIZE);
-                let start = n * READ_SIZE;
-                let mut b = &mut buf[0..s];
-
-                port.read_flash_exact(start as u32, &mut b)?;
 
-                hasher.update(&b);
-                file.write_all(&b)?;
-                remaining -= s;
-            }
+            port.read_flash(*address, &mut buf)?;
+            file.write_all(&buf)?;
 
             // Have the device calculate the sha256 hash for the flash regions we requested
             let flash_hash = port.flash_sha256(*address, *size)?;
             // Calculate the final sha256 hash for the data we just read
-            let read_hash = hasher.finalize();
+            let read_hash = Sha256::digest(&buf);
 
             // Compare and ensure that the data we just read matches what's on the flash
             if flash_hash[..] != read_hash[..] {
@@ -208,35 +1146,89 @@ fn flash_command(
             address,
             size,
         } => {
-            let file = File::open(filename)
-                .with_context(|| "Could not open the file we wanted to write to flash")?;
-            let file_size = file
-                .metadata()
-                .with_context(|| {
-                    "Could not read metadata for the file we wanted to write to flash"
-                })?
-                .len();
-            let size = size.unwrap_or_else(|| file_size.try_into().unwrap());
-
-            assert!(size as u64 <= file_size);
+            // Intel HEX and S-record files have the addresses to write to in their records, and
+            // raw binaries are written to `address`
+            let blocks = if image::is_hex_file(filename) {
+                if address.is_some() || size.is_some() {
+                    return Err(anyhow!(
+                        "The address and size are taken from the records of '{}'",
+                        filename.display()
+                    ));
+                }
 
-            println!(
-                "Writing {} bytes to flash at {:#010x} from the file {}",
-                size,
-                address,
-                filename.display()
-            );
+                let hex_file = HexFile::from_path(filename)
+                    .with_context(|| format!("Failed to parse '{}'", filename.display()))?;
 
-            // Read the contents of the file into memory
-            let mut buf = vec![0u8; size as usize];
-            let mut reader = BufReader::new(file);
+                image::flash_blocks(hex_file)?
+            } else {
+                let address = address.ok_or_else(|| {
+                    anyhow!("An address is required to write a raw binary to flash")
+                })?;
+
+                let file = File::open(filename)
+                    .with_context(|| "Could not open the file we wanted to write to flash")?;
+                let file_size = file
+                    .metadata()
+                    .with_context(|| {
+                        "Could not read metadata for the file we wanted to write to flash"
+                    })?
+                    .len();
+                let size = size.unwrap_or_else(|| file_size.try_into().unwrap());
+
+                assert!(size as u64 <= file_size);
 
-            reader.read_exact(&mut buf)?;
+                // Read the contents of the file into memory
+                let mut buf = vec![0u8; size as usize];
+                let mut reader = BufReader::new(file);
+
+                reader.read_exact(&mut buf)?;
+
+                vec![Block {
+                    addr: address,
+                    data: buf,
+                }]
+            };
 
             port.set_timeout(Duration::from_secs(60))?;
-            port.write_flash(*address, &buf)?;
+
+            for block in &blocks {
+                println!(
+                    "Writing {} bytes to flash at {:#010x} from the file {}",
+                    block.data.len(),
+                    block.addr,
+                    filename.display()
+                );
+            }
+
+            port.write_flash_blocks(&blocks)?;
+
             port.set_timeout(Duration::from_secs(2))?;
         }
+        FlashCommand::Id => {
+            let jedec_id = port.read_jedec_id()?;
+
+            match flash_presets::find_by_jedec_id(jedec_id) {
+                Some(preset) => {
+                    // Print the flash configuration the way it's written in the vendor
+                    // configuration files
+                    let mut section = conf::Section::new("FLASH_CFG");
+                    conf::write_flash_config(&preset.flash_config(), &mut section);
+
+                    println!("Flash: {}", preset);
+                    print!(
+                        "{}",
+                        conf::Conf {
+                            sections: vec![section]
+                        }
+                    );
+                }
+                None => println!(
+                    "Flash: {} {:06x} (no built-in preset)",
+                    flash_presets::manufacturer_name((jedec_id >> 16) as u8),
+                    jedec_id
+                ),
+            }
+        }
         FlashCommand::Erase { offset, size } => {
             if size % 4096 > 0 {
                 return Err(anyhow!("The erase size must be a multiple of 4096, since data is erased in entire sections"));
@@ -256,25 +1248,95 @@ fn flash_command(
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    use cli::{Command, Elf2ImageOpts};
+    use cli::{Boot2Command, Command, DtsCommand, ElfCommand, ImageCommand, RamCommand};
 
     // Create a logger with a timestamp that logs everything at Info level or above
     pretty_env_logger::init_timed();
 
     // Parse the command-line arguments
-    let opts = cli::Opts::from_args();
+    let mut opts = cli::Opts::from_args();
+
+    // Fill in what wasn't given on the command-line from the board profile
+    let config = config::Config::load(&std::env::current_dir()?)?;
+
+    if let Some(profile) = config.profile(opts.profile.as_deref())? {
+        profile.apply(&mut opts);
+    }
 
     match &opts.command {
         Command::Info => get_boot_info(&opts)?,
         Command::Flash(ref cmd) => flash_command(cmd, &opts)?,
-        Command::Elf2Image(Elf2ImageOpts { filename }) => {
-            println!(
-                "Converting elf image {} to firmware",
-                filename.as_path().display()
-            );
+        Command::Image(ImageCommand::Info { filename, aes_key }) => {
+            image_info(filename, aes_key.as_deref())?
+        }
+        Command::Image(ImageCommand::Conf { filename }) => image_conf(filename)?,
+        Command::Image(ImageCommand::Header(ref cmd)) => header_command(cmd)?,
+        Command::Image(ImageCommand::Sign {
+            filename,
+            key,
+            output,
+            aes_key,
+        }) => image_sign(filename, key, output, aes_key.as_deref())?,
+        Command::Image(ImageCommand::Verify {
+            filename,
+            pubkey,
+            aes_key,
+        }) => image_verify(filename, pubkey.as_deref(), aes_key.as_deref())?,
+        Command::Image(ImageCommand::Diff { left, right }) => image_diff(left, right)?,
+        Command::Image(ImageCommand::Ota {
+            filename,
+            version,
+            compress,
+            output,
+        }) => image_ota(filename, version, *compress, output.as_deref())?,
+        Command::Image(ImageCommand::OtaInfo { filename }) => image_ota_info(filename)?,
+        Command::Image(ImageCommand::ExportHex {
+            filename,
+            output,
+            address,
+            segments,
+        }) => image_export_hex(filename, output, *address, *segments)?,
+        Command::Ram(RamCommand::Run { filename, base }) => ram_run(filename, *base, &opts)?,
+        Command::Elf(ElfCommand::Size {
+            filename,
+            compare,
+            budgets,
+            top,
+        }) => elf_size(
+            input_file(filename, "elf")?,
+            compare.as_deref(),
+            budgets,
+            *top,
+        )?,
+        Command::Boot2(Boot2Command::Info { filename }) => boot2_info(filename)?,
+        Command::Boot2(Boot2Command::Install { filename, board }) => {
+            boot2_install(input_file(filename, "boot2")?, board, &opts)?
+        }
+        Command::Dts(DtsCommand::Compile { filename, output }) => {
+            dts_compile(input_file(filename, "dts")?, output.as_deref())?
+        }
+        Command::Dts(DtsCommand::Decompile { filename }) => dts_decompile(filename)?,
+        Command::Dts(DtsCommand::Patch {
+            filename,
+            output,
+            xtal_capcode,
+            tx_power,
+            country_code,
+            properties,
+            device,
+        }) => {
+            let patch = factory_params::FactoryPatch {
+                xtal_capcode: *xtal_capcode,
+                tx_power: *tx_power,
+                country_code: *country_code,
+                properties: properties.clone(),
+            };
+
+            let filename = if *device { None } else { filename.as_deref() };
 
-            elf2image(filename)?;
+            dts_patch(filename, output.as_deref(), &patch, &opts)?
         }
+        Command::Elf2Image(ref elf2image_opts) => elf2image(elf2image_opts, &opts)?,
     }
 
     Ok(())
@@ -0,0 +1,60 @@
+use std::io::{self, IsTerminal};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use bouffalo_cli::bl60x::{Phase, ProgressObserver};
+
+/// The template used to draw progress bars
+const PROGRESS_TEMPLATE: &str =
+    "{msg:>8} [{bar:40}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta})";
+
+/// A `ProgressObserver` that draws a progress bar with throughput and ETA on stderr
+///
+/// Nothing is drawn when stderr is not a terminal.
+pub struct ProgressBarObserver {
+    enabled: bool,
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressBarObserver {
+    pub fn new() -> ProgressBarObserver {
+        ProgressBarObserver {
+            enabled: io::stderr().is_terminal(),
+            bar: None,
+        }
+    }
+}
+
+impl ProgressObserver for ProgressBarObserver {
+    fn start(&mut self, phase: Phase, total: u64) {
+        if !self.enabled {
+            return;
+        }
+
+        let style = ProgressStyle::default_bar()
+            .template(PROGRESS_TEMPLATE)
+            .expect("invalid progress bar template")
+            .progress_chars("=> ");
+
+        let bar = ProgressBar::new(total);
+        bar.set_style(style);
+        bar.set_message(phase.to_string());
+
+        // Replace any bar left behind by a failed phase
+        if let Some(old) = self.bar.replace(bar) {
+            old.abandon();
+        }
+    }
+
+    fn update(&mut self, done: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(done);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish();
+        }
+    }
+}